@@ -0,0 +1,6727 @@
+//! Path tracing core of fastcaster: scene description, materials,
+//! geometry, the camera and the renderer itself. The `fastcaster` binary is a
+//! thin frontend over this
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use image::{Rgb32FImage, RgbImage};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator, ParallelSlice};
+use serde::{Deserialize, Serialize};
+use vek::{Lerp, Quaternion, Rgb, Rgba, Vec2, Vec3};
+use winit::dpi::PhysicalSize;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Pixel {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum MaterialType {
+    Lambertian,
+    /// Reflection off GGX microfacets with the carried fuzz, in [0,1], as
+    /// the roughness. 0 is a perfect mirror. Rays reflected below the surface
+    /// would be absorbed, which darkens rough metals, so that lost energy comes
+    /// back through a diffuse-like multiple scattering lobe (Kulla-Conty)
+    /// unless `RenderSettings::single_scatter_metal` is set. With
+    /// `RenderSettings::legacy_metal` the mirror reflection is instead
+    /// perturbed by a random offset inside a sphere of the fuzz radius
+    Metal(f32),
+    /// Metal whose reflectance follows Fresnel-Schlick: the material color at
+    /// normal incidence, brightening to white at grazing angles. Carries the
+    /// same fuzz as `Metal`
+    FresnelMetal(f32),
+    /// Glass-like material. Carries the index of refraction
+    Dielectric(f32),
+    /// Light source that emits the carried radiance, tinted by the material
+    /// color. Paths end when they hit one
+    Emissive(Rgb<f32>),
+    /// Scatters uniformly in all directions, for participating media
+    Isotropic,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Material {
+    /// Color scattered light is tinted by
+    #[serde(alias = "color")]
+    pub albedo: Texture,
+    /// Light given off by the surface on top of whatever it scatters
+    #[serde(default = "black")]
+    pub emitted: Rgb<f32>,
+    pub mat_type: MaterialType,
+    /// Emit from both sides. One-sided emitters are dark when hit from
+    /// behind, see [`HitRecord::front_face`]
+    #[serde(default = "yes")]
+    pub double_sided: bool,
+    /// Fuzz of a metal taken from the red channel at the hit instead of the
+    /// constant in `mat_type`, so one surface can go from polished to matte
+    #[serde(default)]
+    pub roughness: Option<Texture>,
+    /// Index of refraction of glass as 1 plus the red channel at the hit,
+    /// replacing the constant in `mat_type`. A grayscale map covers 1 to 2
+    #[serde(default)]
+    pub ior: Option<Texture>,
+}
+
+fn black() -> Rgb<f32> {
+    Rgb::broadcast(0.0)
+}
+
+fn yes() -> bool {
+    true
+}
+
+impl Material {
+    pub fn lambertian(albedo: impl Into<Texture>) -> Self {
+        Self {
+            albedo: albedo.into(),
+            emitted: black(),
+            mat_type: MaterialType::Lambertian,
+            double_sided: true,
+            roughness: None,
+            ior: None,
+        }
+    }
+
+    /// Clear glass with index of refraction `ir`
+    pub fn dielectric(ir: f32) -> Self {
+        Self {
+            albedo: Texture::Solid(Rgb::broadcast(1.0)),
+            emitted: black(),
+            mat_type: MaterialType::Dielectric(ir),
+            double_sided: true,
+            roughness: None,
+            ior: None,
+        }
+    }
+
+    /// Light source giving off `radiance`
+    pub fn emissive(radiance: Rgb<f32>) -> Self {
+        Self {
+            albedo: Texture::Solid(Rgb::broadcast(1.0)),
+            emitted: black(),
+            mat_type: MaterialType::Emissive(radiance),
+            double_sided: true,
+            roughness: None,
+            ior: None,
+        }
+    }
+
+    /// Metal with `fuzz` clamped to [0,1]
+    pub fn metal(albedo: impl Into<Texture>, fuzz: f32) -> Self {
+        Self {
+            albedo: albedo.into(),
+            emitted: black(),
+            mat_type: MaterialType::Metal(fuzz.clamp(0.0, 1.0)),
+            double_sided: true,
+            roughness: None,
+            ior: None,
+        }
+    }
+
+    /// Whether light given off by the surface reaches a ray that made
+    /// `hit_record`
+    pub fn emits_toward(&self, hit_record: &HitRecord) -> bool {
+        self.double_sided || hit_record.front_face
+    }
+
+    /// Metal fuzz at `hit_record`, `fuzz` unless a roughness map replaces it
+    fn fuzz_at(&self, fuzz: f32, hit_record: &HitRecord) -> f32 {
+        let fuzz = self.roughness.as_ref().map_or(fuzz, |roughness| {
+            roughness
+                .value(hit_record.uv, hit_record.intersection_point)
+                .r
+        });
+        // Scene files construct materials without going through
+        // Material::metal, so clamp here as well
+        fuzz.clamp(0.0, 1.0)
+    }
+
+    /// Index of refraction at `hit_record`, `ir` unless an IOR map replaces
+    /// it
+    fn ior_at(&self, ir: f32, hit_record: &HitRecord) -> f32 {
+        self.ior.as_ref().map_or(ir, |ior| {
+            1.0 + ior.value(hit_record.uv, hit_record.intersection_point).r
+        })
+    }
+}
+
+/// Spatially varying surface color
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Texture {
+    Solid(Rgb<f32>),
+    /// 3D checkerboard of cubes with side `scale`
+    Checker {
+        even: Rgb<f32>,
+        odd: Rgb<f32>,
+        scale: f32,
+    },
+    Image(ImageTexture),
+    /// Grayscale marble veins from Perlin turbulence, `scale` sets how
+    /// tightly they are packed
+    Noise {
+        scale: f32,
+    },
+    /// Lines of color `line` over `background` along x and z every
+    /// `spacing` units, with every `major_every`th line drawn thicker
+    Grid {
+        background: Rgb<f32>,
+        line: Rgb<f32>,
+        spacing: f32,
+        major_every: u32,
+    },
+}
+
+/// Width of the thin lines of [`Texture::Grid`] as a fraction of the grid
+/// spacing. Major lines are twice as wide
+const GRID_LINE_WIDTH: f32 = 0.03;
+
+/// How far the surface point and coordinates of a hit move between
+/// neighbouring pixels. Textures average over it instead of point sampling,
+/// so detail smaller than a pixel blurs out instead of aliasing. The default
+/// is a point
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Footprint {
+    pub dpdx: Vec3<f32>,
+    pub dpdy: Vec3<f32>,
+    pub duvdx: Vec2<f32>,
+    pub duvdy: Vec2<f32>,
+}
+
+impl Texture {
+    /// Color at surface coordinates `uv` and world space point `p`
+    pub fn value(&self, uv: Vec2<f32>, p: Vec3<f32>) -> Rgb<f32> {
+        self.filtered_value(uv, p, Footprint::default())
+    }
+
+    /// Whether [`Texture::filtered_value`] looks at the footprint at all, so
+    /// callers can skip working it out
+    pub fn uses_footprint(&self) -> bool {
+        matches!(
+            self,
+            Texture::Checker { .. } | Texture::Image(_) | Texture::Grid { .. }
+        )
+    }
+
+    /// Average color over `footprint` around `uv` and `p`
+    pub fn filtered_value(&self, uv: Vec2<f32>, p: Vec3<f32>, footprint: Footprint) -> Rgb<f32> {
+        match self {
+            Texture::Solid(color) => *color,
+            Texture::Checker { even, odd, scale } => {
+                // Nudged so surfaces lying on a cell boundary, like a ground
+                // plane at y = 0, don't speckle from rounding
+                let cell = p / *scale + 1e-4;
+                // Box around the footprint, in cells. Flat on surfaces lined
+                // up with an axis, so the ground never blurs across y = 0
+                let width = (footprint.dpdx.map(f32::abs) + footprint.dpdy.map(f32::abs)) / *scale;
+                // Each axis alternates between +1 and -1 from cell to cell, and
+                // their product is +1 on even cells. Boxes are separable, so
+                // filter the axes one by one
+                let parity = filtered_square_wave(cell.x, width.x)
+                    * filtered_square_wave(cell.y, width.y)
+                    * filtered_square_wave(cell.z, width.z);
+                if parity == 1.0 {
+                    *even
+                } else if parity == -1.0 {
+                    *odd
+                } else {
+                    Lerp::lerp(*odd, *even, (1.0 + parity) / 2.0)
+                }
+            }
+            Texture::Image(image) => image.filtered_value(uv, footprint),
+            Texture::Noise { scale } => {
+                let turbulence = perlin().turbulence(p, 7);
+                Rgb::broadcast(0.5 * (1.0 + (scale * p.z + 10.0 * turbulence).sin()))
+            }
+            Texture::Grid {
+                background,
+                line,
+                spacing,
+                major_every,
+            } => {
+                let width =
+                    (footprint.dpdx.map(f32::abs) + footprint.dpdy.map(f32::abs)) / *spacing;
+                let cell = p / *spacing;
+                let minor = |x: f32, width: f32| filtered_pulse_train(x, GRID_LINE_WIDTH, width);
+                let mut coverage =
+                    1.0 - (1.0 - minor(cell.x, width.x)) * (1.0 - minor(cell.z, width.z));
+                if *major_every > 1 {
+                    let every = *major_every as f32;
+                    let major = |x: f32, width: f32| {
+                        filtered_pulse_train(
+                            x / every,
+                            2.0 * GRID_LINE_WIDTH / every,
+                            width / every,
+                        )
+                    };
+                    let major_coverage =
+                        1.0 - (1.0 - major(cell.x, width.x)) * (1.0 - major(cell.z, width.z));
+                    coverage = coverage.max(major_coverage);
+                }
+                Lerp::lerp(*background, *line, coverage)
+            }
+        }
+    }
+}
+
+/// Average of the wave that is +1 on even and -1 on odd integer cells over a
+/// box `width` wide centered on `x`
+fn filtered_square_wave(x: f32, width: f32) -> f32 {
+    // Too thin to tell apart from a point, and keeps unfiltered hits exact
+    if width < 1e-6 {
+        return if (x.floor() as i64).rem_euclid(2) == 0 {
+            1.0
+        } else {
+            -1.0
+        };
+    }
+    // The wave's integral is a triangle wave
+    let integral = |x: f32| 1.0 - (x.rem_euclid(2.0) - 1.0).abs();
+    (integral(x + width / 2.0) - integral(x - width / 2.0)) / width
+}
+
+/// Fraction of a box `width` wide centered on `x` covered by lines `line`
+/// wide centered on every integer
+fn filtered_pulse_train(x: f32, line: f32, width: f32) -> f32 {
+    let x = x + line / 2.0;
+    if width < 1e-6 {
+        return if x.rem_euclid(1.0) < line { 1.0 } else { 0.0 };
+    }
+    // How much of [0, x] the lines cover
+    let integral = |x: f32| x.floor() * line + x.rem_euclid(1.0).min(line);
+    ((integral(x + width / 2.0) - integral(x - width / 2.0)) / width).clamp(0.0, 1.0)
+}
+
+impl From<Rgb<f32>> for Texture {
+    fn from(color: Rgb<f32>) -> Self {
+        Texture::Solid(color)
+    }
+}
+
+/// PNG mapped over an object's surface coordinates. Scene files refer to it by
+/// path, relative to the working directory
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "PathBuf", into = "PathBuf")]
+pub struct ImageTexture {
+    path: PathBuf,
+    /// MIP chain in linear color, from the full image down to a single texel.
+    /// Each level is half the size of the one before
+    levels: Arc<Vec<MipLevel>>,
+}
+
+struct MipLevel {
+    width: u32,
+    height: u32,
+    texels: Vec<Rgb<f32>>,
+}
+
+impl MipLevel {
+    /// Nearest texel to `uv`, with v running from the bottom of the image up
+    fn nearest(&self, uv: Vec2<f32>) -> Rgb<f32> {
+        let uv = uv.map(|c| c.clamp(0.0, 1.0));
+        let x = (uv.x * (self.width - 1) as f32).round() as u32;
+        let y = ((1.0 - uv.y) * (self.height - 1) as f32).round() as u32;
+        self.texels[(y * self.width + x) as usize]
+    }
+
+    /// Box filtered to half the size, rounding odd sizes up
+    fn downsampled(&self) -> Self {
+        let (width, height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let mut texels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = Rgb::broadcast(0.0);
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    // Odd edges reuse their last texel
+                    let sx = (2 * x + dx).min(self.width - 1);
+                    let sy = (2 * y + dy).min(self.height - 1);
+                    sum += self.texels[(sy * self.width + sx) as usize];
+                }
+                texels.push(sum / 4.0);
+            }
+        }
+        Self {
+            width,
+            height,
+            texels,
+        }
+    }
+}
+
+impl ImageTexture {
+    pub fn load(path: PathBuf) -> image::ImageResult<Self> {
+        let image = image::open(&path)?.into_rgb8();
+        Ok(Self::from_image(path, &image))
+    }
+
+    fn from_image(path: PathBuf, image: &RgbImage) -> Self {
+        let (width, height) = image.dimensions();
+        // Images are stored display encoded, undo the default gamma of 2 to
+        // get back to linear
+        let texels = image
+            .pixels()
+            .map(|pixel| Rgb::from(pixel.0).map(|c: u8| (c as f32 / 255.0).powi(2)))
+            .collect();
+        let mut levels = vec![MipLevel {
+            width,
+            height,
+            texels,
+        }];
+        while let Some(last) = levels.last().filter(|l| l.width > 1 || l.height > 1) {
+            levels.push(last.downsampled());
+        }
+        Self {
+            path,
+            levels: Arc::new(levels),
+        }
+    }
+
+    /// Nearest texel to `uv`, with v running from the bottom of the image up
+    pub fn value(&self, uv: Vec2<f32>) -> Rgb<f32> {
+        self.levels[0].nearest(uv)
+    }
+
+    /// Nearest texels to `uv` in the two MIP levels whose texels are closest
+    /// to the size of `footprint`, blended by how close each is
+    pub fn filtered_value(&self, uv: Vec2<f32>, footprint: Footprint) -> Rgb<f32> {
+        let base = &self.levels[0];
+        let texels = Vec2::new(base.width as f32, base.height as f32);
+        let extent = (footprint.duvdx * texels)
+            .magnitude()
+            .max((footprint.duvdy * texels).magnitude());
+        // Footprints no bigger than a texel are as sharp as it gets
+        if extent <= 1.0 {
+            return base.nearest(uv);
+        }
+        let level = extent.log2().min((self.levels.len() - 1) as f32);
+        let (below, t) = (level.floor() as usize, level.fract());
+        let above = (below + 1).min(self.levels.len() - 1);
+        Lerp::lerp(
+            self.levels[below].nearest(uv),
+            self.levels[above].nearest(uv),
+            t,
+        )
+    }
+}
+
+impl TryFrom<PathBuf> for ImageTexture {
+    type Error = String;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        Self::load(path.clone())
+            .map_err(|e| format!("failed to load texture {}: {e}", path.display()))
+    }
+}
+
+impl From<ImageTexture> for PathBuf {
+    fn from(texture: ImageTexture) -> Self {
+        texture.path
+    }
+}
+
+const PERLIN_POINTS: usize = 256;
+
+/// Perlin noise over random gradient vectors on an integer lattice
+struct Perlin {
+    gradients: Vec<Vec3<f32>>,
+    permutations: [Vec<usize>; 3],
+}
+
+/// The noise all noise textures share. Always built from the same seed so
+/// renders are reproducible
+fn perlin() -> &'static Perlin {
+    static PERLIN: OnceLock<Perlin> = OnceLock::new();
+    PERLIN.get_or_init(|| Perlin::new(&mut StdRng::seed_from_u64(0)))
+}
+
+impl Perlin {
+    fn new(rng: &mut impl Rng) -> Self {
+        let gradients = (0..PERLIN_POINTS).map(|_| rng.rand_unit_vec3()).collect();
+        let mut permutation = || {
+            let mut permutation: Vec<usize> = (0..PERLIN_POINTS).collect();
+            permutation.shuffle(rng);
+            permutation
+        };
+        Self {
+            gradients,
+            permutations: [permutation(), permutation(), permutation()],
+        }
+    }
+
+    /// Noise in roughly [-1,1]
+    fn noise(&self, p: Vec3<f32>) -> f32 {
+        let cell = p.map(|c| c.floor());
+        let fraction = p - cell;
+        let cell = cell.map(|c| c as i64);
+        // Hermite smoothing hides the lattice
+        let weight = fraction.map(|f| f * f * (3.0 - 2.0 * f));
+
+        let mut sum = 0.0;
+        for i in 0..2 {
+            for j in 0..2 {
+                for k in 0..2 {
+                    let corner = Vec3::new(i, j, k);
+                    let index = |axis: usize| {
+                        let lattice = (cell[axis] + corner[axis]) as usize;
+                        self.permutations[axis][lattice & (PERLIN_POINTS - 1)]
+                    };
+                    let gradient = self.gradients[index(0) ^ index(1) ^ index(2)];
+                    let corner = corner.map(|c| c as f32);
+                    let offset = fraction - corner;
+                    let falloff = corner.map2(weight, |c, w| c * w + (1.0 - c) * (1.0 - w));
+                    sum += falloff.product() * gradient.dot(offset);
+                }
+            }
+        }
+        sum
+    }
+
+    /// Sum of `octaves` layers of noise, each at twice the frequency and
+    /// half the amplitude of the last
+    fn turbulence(&self, p: Vec3<f32>, octaves: usize) -> f32 {
+        let mut sum = 0.0;
+        let mut p = p;
+        let mut amplitude = 1.0;
+        for _ in 0..octaves {
+            sum += amplitude * self.noise(p);
+            amplitude *= 0.5;
+            p *= 2.0;
+        }
+        sum.abs()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sphere {
+    /// Center when the shutter opens
+    pub origin: vek::Vec3<f32>,
+    pub radius: f32,
+    pub material: Material,
+    /// Distance the center moves over the shutter interval
+    #[serde(default)]
+    pub velocity: Vec3<f32>,
+}
+
+impl Sphere {
+    pub fn center(&self, time: f32) -> Vec3<f32> {
+        self.origin + self.velocity * time
+    }
+}
+/// Infinite plane through `point`. `normal` should be unit length
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Plane {
+    pub point: Vec3<f32>,
+    pub normal: Vec3<f32>,
+    pub material: Material,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Triangle {
+    pub v0: Vec3<f32>,
+    pub v1: Vec3<f32>,
+    pub v2: Vec3<f32>,
+    pub material: Material,
+    /// Unit normals at `v0`, `v1` and `v2` to interpolate for smooth
+    /// shading. Flat shaded with the face normal when `None`
+    #[serde(default)]
+    pub normals: Option<[Vec3<f32>; 3]>,
+}
+
+/// Parallelogram with one corner at `origin` and sides `u` and `v`. Its
+/// front faces along `u.cross(v)`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Quad {
+    pub origin: Vec3<f32>,
+    pub u: Vec3<f32>,
+    pub v: Vec3<f32>,
+    pub material: Material,
+}
+
+/// The six sides of the axis aligned box between corners `min` and `max`,
+/// all facing outward
+pub fn quad_box(min: Vec3<f32>, max: Vec3<f32>, material: &Material) -> Vec<Quad> {
+    let size = max - min;
+    let dx = Vec3::new(size.x, 0.0, 0.0);
+    let dy = Vec3::new(0.0, size.y, 0.0);
+    let dz = Vec3::new(0.0, 0.0, size.z);
+    [
+        (Vec3::new(min.x, min.y, max.z), dx, dy),
+        (Vec3::new(max.x, min.y, max.z), -dz, dy),
+        (Vec3::new(max.x, min.y, min.z), -dx, dy),
+        (min, dz, dy),
+        (Vec3::new(min.x, max.y, max.z), dx, -dz),
+        (min, dx, dz),
+    ]
+    .into_iter()
+    .map(|(origin, u, v)| Quad {
+        origin,
+        u,
+        v,
+        material: material.clone(),
+    })
+    .collect()
+}
+
+/// Everything that can be hit. Bounded objects live in a BVH, unbounded ones
+/// like planes are tested linearly
+pub struct World {
+    bvh: Option<BvhNode>,
+    unbounded: Vec<Box<dyn Hittable + Sync>>,
+    /// Emissive spheres to sample directly. They are also part of the
+    /// objects above
+    lights: Vec<Sphere>,
+    /// Fog and smoke, which only `hit_with_media` sees
+    media: Vec<ConstantMedium>,
+    background: Background,
+}
+
+/// What rays that escape the scene see
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Background {
+    Solid(Rgb<f32>),
+    /// Blend from `bottom` looking straight down to `top` looking straight up
+    Gradient {
+        top: Rgb<f32>,
+        bottom: Rgb<f32>,
+    },
+    /// Equirectangular HDR image wrapped around the scene
+    EnvMap(EnvironmentMap),
+    /// Analytic daylight with a sun disk
+    Preetham(PreethamSky),
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Gradient {
+            top: Rgb::new(0.5, 0.7, 1.0),
+            bottom: Rgb::broadcast(1.0),
+        }
+    }
+}
+
+impl Background {
+    /// Radiance seen along the unit `direction`
+    pub fn color(&self, direction: Vec3<f32>) -> Rgb<f32> {
+        match self {
+            Background::Solid(color) => *color,
+            Background::Gradient { top, bottom } => {
+                let t = 0.5 * (direction.y + 1.0);
+                Lerp::lerp(*bottom, *top, t)
+            }
+            Background::EnvMap(map) => map.value(direction),
+            Background::Preetham(sky) => sky.sky_radiance(direction) + sky.sun_radiance(direction),
+        }
+    }
+
+    /// Whether `sample` can pick directions toward the background's light
+    pub fn is_sampled(&self) -> bool {
+        matches!(self, Background::EnvMap(_) | Background::Preetham(_))
+    }
+
+    /// Radiance along `direction` split into the part `sample` never picks
+    /// and the part it does. They add up to `color`
+    fn split_radiance(&self, direction: Vec3<f32>) -> (Rgb<f32>, Rgb<f32>) {
+        match self {
+            Background::Preetham(sky) => (sky.sky_radiance(direction), sky.sun_radiance(direction)),
+            Background::EnvMap(map) => (Rgb::broadcast(0.0), map.value(direction)),
+            _ => (self.color(direction), Rgb::broadcast(0.0)),
+        }
+    }
+
+    /// A direction toward the sampled part of the background, picked with
+    /// the density `pdf` gives it
+    fn sample(&self, rng: &mut impl Rng) -> Option<Vec3<f32>> {
+        match self {
+            Background::Preetham(sky) => Some(sample_cone(
+                sky.sun_direction,
+                1.0 - SUN_ANGULAR_RADIUS.cos(),
+                rng,
+            )),
+            Background::EnvMap(map) => map.sample(rng),
+            _ => None,
+        }
+    }
+
+    /// Density per solid angle of `sample` picking `direction`
+    fn pdf(&self, direction: Vec3<f32>) -> f32 {
+        match self {
+            Background::Preetham(sky) if sky.sun_radiance(direction) != Rgb::broadcast(0.0) => {
+                sky.sun_pdf()
+            }
+            Background::EnvMap(map) => map.pdf(direction),
+            _ => 0.0,
+        }
+    }
+
+    /// Radiance averaged over all directions
+    pub fn average(&self) -> Rgb<f32> {
+        match self {
+            Background::Solid(color) => *color,
+            Background::Gradient { top, bottom } => (top + bottom) / 2.0,
+            Background::EnvMap(map) => map.average,
+            Background::Preetham(sky) => sky.average,
+        }
+    }
+}
+
+/// Radiance HDR image in latitude-longitude layout, loaded by path like
+/// [`ImageTexture`]. The top row looks straight up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "PathBuf", into = "PathBuf")]
+pub struct EnvironmentMap {
+    path: PathBuf,
+    image: Arc<Rgb32FImage>,
+    /// Mean radiance over the sphere, for paths cut off by a bounce cap
+    average: Rgb<f32>,
+    /// Where bright texels are, for sampling them directly
+    distribution: Arc<EnvironmentDistribution>,
+}
+
+/// Piecewise constant density over the texels of an environment map, in
+/// proportion to their luminance and the solid angle they cover. Lookups
+/// are bilinear, so light bleeds half a texel past bright texels, and each
+/// texel is weighted by its brightest neighbor to keep that bleed sampled
+#[derive(Debug)]
+struct EnvironmentDistribution {
+    /// Cumulative weight of the rows from the top, starting at 0
+    rows: Vec<f32>,
+    /// Cumulative weight across each row from the left, starting at 0, one
+    /// row after another
+    columns: Vec<f32>,
+    width: usize,
+    height: usize,
+}
+
+impl EnvironmentDistribution {
+    fn new(image: &Rgb32FImage) -> Self {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let mut rows = vec![0.0; height + 1];
+        let mut columns = Vec::with_capacity(height * (width + 1));
+        for y in 0..height {
+            let sin_theta = ((y as f32 + 0.5) / height as f32 * std::f32::consts::PI).sin();
+            let mut total = 0.0;
+            columns.push(0.0);
+            for x in 0..width {
+                let mut brightest = 0.0f32;
+                for neighbor_y in y.saturating_sub(1)..(y + 2).min(height) {
+                    for dx in [width - 1, 0, 1] {
+                        let neighbor_x = (x + dx) % width;
+                        let texel =
+                            Rgb::from(image.get_pixel(neighbor_x as u32, neighbor_y as u32).0);
+                        brightest = brightest.max(luminance(texel));
+                    }
+                }
+                total += brightest * sin_theta;
+                columns.push(total);
+            }
+            rows[y + 1] = rows[y] + total;
+        }
+        Self {
+            rows,
+            columns,
+            width,
+            height,
+        }
+    }
+
+    fn row(&self, y: usize) -> &[f32] {
+        &self.columns[y * (self.width + 1)..(y + 1) * (self.width + 1)]
+    }
+
+    /// Pick a point in texel coordinates, x across and y down
+    fn sample(&self, rng: &mut impl Rng) -> Option<(f32, f32)> {
+        // Picks an interval from a cumulative list and where in it `u` fell
+        let pick = |cumulative: &[f32], u: f32| {
+            let total = *cumulative.last().unwrap();
+            let target = u * total;
+            let i = (cumulative.partition_point(|&c| c <= target) - 1).min(cumulative.len() - 2);
+            let (low, high) = (cumulative[i], cumulative[i + 1]);
+            i as f32 + ((target - low) / (high - low)).clamp(0.0, 1.0)
+        };
+        if *self.rows.last().unwrap() <= 0.0 {
+            return None;
+        }
+        let y = pick(&self.rows, rng.gen());
+        let x = pick(self.row(y as usize), rng.gen());
+        Some((x, y))
+    }
+
+    /// Density over the unit square of texel coordinates of picking the
+    /// texel at (`x`, `y`)
+    fn texel_pdf(&self, x: usize, y: usize) -> f32 {
+        let total = *self.rows.last().unwrap();
+        if total <= 0.0 {
+            return 0.0;
+        }
+        let row = self.row(y);
+        (row[x + 1] - row[x]) / total * (self.width * self.height) as f32
+    }
+}
+
+impl EnvironmentMap {
+    pub fn load(path: PathBuf) -> image::ImageResult<Self> {
+        let image = image::open(&path)?.into_rgb32f();
+        Ok(Self::from_image(path, image))
+    }
+
+    /// Wraps an image already in memory. `path` is only what scene files
+    /// save for it
+    pub fn from_image(path: PathBuf, image: Rgb32FImage) -> Self {
+        // Rows near the poles cover less solid angle, weight them by sin(theta)
+        let (width, height) = image.dimensions();
+        let mut sum = Rgb::broadcast(0.0);
+        let mut weight = 0.0;
+        for y in 0..height {
+            let sin_theta = ((y as f32 + 0.5) / height as f32 * std::f32::consts::PI).sin();
+            for x in 0..width {
+                sum += Rgb::from(image.get_pixel(x, y).0) * sin_theta;
+                weight += sin_theta;
+            }
+        }
+        Self {
+            path,
+            distribution: Arc::new(EnvironmentDistribution::new(&image)),
+            image: Arc::new(image),
+            average: sum / weight,
+        }
+    }
+
+    /// Texel coordinates `direction` looks up, x across and y down
+    fn texel_coordinates(&self, direction: Vec3<f32>) -> (f32, f32) {
+        let direction = direction.normalized();
+        let theta = (-direction.y).clamp(-1.0, 1.0).acos();
+        let phi = (-direction.z).atan2(direction.x) + std::f32::consts::PI;
+        let (width, height) = self.image.dimensions();
+        (
+            phi / std::f32::consts::TAU * width as f32,
+            (1.0 - theta / std::f32::consts::PI) * height as f32,
+        )
+    }
+
+    /// A direction picked in proportion to how much light comes from it
+    pub fn sample(&self, rng: &mut impl Rng) -> Option<Vec3<f32>> {
+        let (x, y) = self.distribution.sample(rng)?;
+        let (width, height) = self.image.dimensions();
+        let phi = x / width as f32 * std::f32::consts::TAU - std::f32::consts::PI;
+        let theta = (1.0 - y / height as f32) * std::f32::consts::PI;
+        let sin_theta = theta.sin();
+        Some(Vec3::new(
+            sin_theta * phi.cos(),
+            -theta.cos(),
+            -sin_theta * phi.sin(),
+        ))
+    }
+
+    /// Density per solid angle of `sample` picking `direction`
+    pub fn pdf(&self, direction: Vec3<f32>) -> f32 {
+        let direction = direction.normalized();
+        let sin_theta = (1.0 - direction.y * direction.y).max(0.0).sqrt();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let (x, y) = self.texel_coordinates(direction);
+        let (width, height) = self.image.dimensions();
+        let x = (x as usize).min(width as usize - 1);
+        let y = (y as usize).min(height as usize - 1);
+        // The unit square of texel coordinates maps onto 2 pi^2 sin(theta)
+        // steradians
+        self.distribution.texel_pdf(x, y)
+            / (2.0 * std::f32::consts::PI * std::f32::consts::PI * sin_theta)
+    }
+
+    /// Bilinearly filtered radiance seen along `direction`. Uses the same
+    /// longitude and latitude as sphere uvs, wrapping at the seam
+    pub fn value(&self, direction: Vec3<f32>) -> Rgb<f32> {
+        let (width, height) = self.image.dimensions();
+        let (x, y) = self.texel_coordinates(direction);
+        let (x, y) = (x - 0.5, y - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let texel = |x: f32, y: f32| {
+            let x = (x as i64).rem_euclid(width as i64) as u32;
+            let y = (y as i64).clamp(0, height as i64 - 1) as u32;
+            Rgb::from(self.image.get_pixel(x, y).0)
+        };
+        let top = Lerp::lerp(texel(x0, y0), texel(x0 + 1.0, y0), tx);
+        let bottom = Lerp::lerp(texel(x0, y0 + 1.0), texel(x0 + 1.0, y0 + 1.0), tx);
+        Lerp::lerp(top, bottom, ty)
+    }
+}
+
+impl TryFrom<PathBuf> for EnvironmentMap {
+    type Error = String;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        Self::load(path.clone())
+            .map_err(|e| format!("failed to load environment map {}: {e}", path.display()))
+    }
+}
+
+impl From<EnvironmentMap> for PathBuf {
+    fn from(map: EnvironmentMap) -> Self {
+        map.path
+    }
+}
+
+/// Where the sun sits and how hazy the air is for [`PreethamSky`]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SkyParams {
+    /// Degrees above the horizon, in (0, 90]
+    pub sun_elevation: f32,
+    /// Degrees clockwise seen from above, starting from -Z
+    pub sun_azimuth: f32,
+    /// Haziness, from 2 for a clear day to 10 for a hazy one
+    pub turbidity: f32,
+}
+
+impl Default for SkyParams {
+    fn default() -> Self {
+        Self {
+            sun_elevation: 45.0,
+            sun_azimuth: 0.0,
+            turbidity: 3.0,
+        }
+    }
+}
+
+/// Angular radius of the sun disk in radians
+const SUN_ANGULAR_RADIUS: f32 = 0.00465;
+
+/// Brings the model's luminance in kcd/m^2 down to where the default
+/// exposure shows a midday scene well
+const SKY_SCALE: f32 = 0.05;
+
+/// Luminance of the sun outside the atmosphere, in the model's units
+const SUN_LUMINANCE: f32 = 1.0e5;
+
+/// The Preetham, Shirley and Smits daylight model. Sky color comes from
+/// fitted Perez distributions of luminance and chromaticity, and the sun is
+/// a small disk dimmed by the air it shines through
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "SkyParams", into = "SkyParams")]
+pub struct PreethamSky {
+    params: SkyParams,
+    /// Unit vector toward the sun
+    sun_direction: Vec3<f32>,
+    /// Perez coefficients A to E for luminance and the x and y chromaticity
+    perez: [[f32; 5]; 3],
+    /// Each of the three at the zenith, over the Perez distribution there
+    zenith_over_perez: [f32; 3],
+    sun: Rgb<f32>,
+    /// Mean radiance over the sphere, sun included
+    average: Rgb<f32>,
+}
+
+/// Perez sky distribution for a view `cos_theta` from the zenith and
+/// `gamma` radians from the sun
+fn perez_distribution(coefficients: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let [a, b, c, d, e] = *coefficients;
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+impl PreethamSky {
+    pub fn new(params: SkyParams) -> Self {
+        let t = params.turbidity;
+        let (elevation, azimuth) = (
+            params.sun_elevation.to_radians(),
+            params.sun_azimuth.to_radians(),
+        );
+        let sun_direction = Vec3::new(
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            -elevation.cos() * azimuth.cos(),
+        );
+        let theta_s = std::f32::consts::FRAC_PI_2 - elevation;
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+        let chi = (4.0 / 9.0 - t / 120.0) * (std::f32::consts::PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let cubic = |[a, b, c, d]: [f32; 4]| ((a * theta_s + b) * theta_s + c) * theta_s + d;
+        let chromaticity = |t2: [f32; 4], t1: [f32; 4], t0: [f32; 4]| {
+            t * t * cubic(t2) + t * cubic(t1) + cubic(t0)
+        };
+        let zenith = [
+            zenith_luminance,
+            chromaticity(
+                [0.00166, -0.00375, 0.00209, 0.0],
+                [-0.02903, 0.06377, -0.03202, 0.00394],
+                [0.11693, -0.21196, 0.06052, 0.25886],
+            ),
+            chromaticity(
+                [0.00275, -0.00610, 0.00317, 0.0],
+                [-0.04214, 0.08970, -0.04153, 0.00516],
+                [0.15346, -0.26756, 0.06670, 0.26688],
+            ),
+        ];
+        let zenith_over_perez =
+            std::array::from_fn(|i| zenith[i] / perez_distribution(&perez[i], 1.0, theta_s));
+
+        // Rayleigh and aerosol optical depth straight up at the red, green
+        // and blue wavelengths, times the air mass toward the sun
+        let air_mass = 1.0 / (elevation.sin() + 0.15 * (3.885 + params.sun_elevation).powf(-1.253));
+        let aerosol = 0.04608 * t - 0.04586;
+        let depth = |wavelength_um: f32| {
+            0.0086 * wavelength_um.powf(-4.08) + aerosol * wavelength_um.powf(-1.3)
+        };
+        let sun = Rgb::new(
+            (-air_mass * depth(0.68)).exp(),
+            (-air_mass * depth(0.55)).exp(),
+            (-air_mass * depth(0.44)).exp(),
+        ) * SUN_LUMINANCE
+            * SKY_SCALE;
+
+        let mut sky = Self {
+            params,
+            sun_direction,
+            perez,
+            zenith_over_perez,
+            sun,
+            average: Rgb::broadcast(0.0),
+        };
+        // Rows are even steps in cos(theta), so equal solid angle
+        let (rows, columns) = (32, 64);
+        let mut sum = Rgb::broadcast(0.0);
+        for row in 0..rows {
+            let cos_theta = 1.0 - 2.0 * (row as f32 + 0.5) / rows as f32;
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            for column in 0..columns {
+                let phi = std::f32::consts::TAU * (column as f32 + 0.5) / columns as f32;
+                let direction = Vec3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin());
+                sum += sky.sky_radiance(direction);
+            }
+        }
+        sky.average = sum / (rows * columns) as f32
+            + sky.sun * sky.sun_solid_angle() / (2.0 * std::f32::consts::TAU);
+        sky
+    }
+
+    pub fn params(&self) -> SkyParams {
+        self.params
+    }
+
+    /// Radiance of the sky without the sun along `direction`. Below the
+    /// horizon it stays at its horizon color
+    pub fn sky_radiance(&self, direction: Vec3<f32>) -> Rgb<f32> {
+        let direction = direction.normalized();
+        let cos_theta = direction.y.max(0.001);
+        let gamma = direction.dot(self.sun_direction).clamp(-1.0, 1.0).acos();
+        let [luminance, x, y] = std::array::from_fn(|i| {
+            self.zenith_over_perez[i] * perez_distribution(&self.perez[i], cos_theta, gamma)
+        });
+        let luminance = luminance * SKY_SCALE;
+        let (big_x, big_z) = (x / y * luminance, (1.0 - x - y) / y * luminance);
+        Rgb::new(
+            3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+            -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+            0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+        )
+        .map(|channel| channel.max(0.0))
+    }
+
+    /// Radiance of the sun disk along `direction`, black outside it
+    pub fn sun_radiance(&self, direction: Vec3<f32>) -> Rgb<f32> {
+        if direction.normalized().dot(self.sun_direction) >= SUN_ANGULAR_RADIUS.cos() {
+            self.sun
+        } else {
+            Rgb::broadcast(0.0)
+        }
+    }
+
+    fn sun_solid_angle(&self) -> f32 {
+        std::f32::consts::TAU * (1.0 - SUN_ANGULAR_RADIUS.cos())
+    }
+
+    /// Density per solid angle of sampling the sun by picking a direction
+    /// uniformly inside its disk
+    fn sun_pdf(&self) -> f32 {
+        1.0 / self.sun_solid_angle()
+    }
+}
+
+impl From<SkyParams> for PreethamSky {
+    fn from(params: SkyParams) -> Self {
+        Self::new(params)
+    }
+}
+
+impl From<PreethamSky> for SkyParams {
+    fn from(sky: PreethamSky) -> Self {
+        sky.params
+    }
+}
+
+impl Pixel {
+    /// Turn pixel into an RGB u32. R high, top padding
+    pub fn to_u32(self) -> u32 {
+        let r_channel = (self.red as u32) << 16;
+        let g_channel = (self.green as u32) << 8;
+        let b_channel = self.blue as u32;
+        r_channel | g_channel | b_channel
+    }
+
+    /// Inverse of `to_u32`
+    pub fn from_u32(bits: u32) -> Self {
+        Self {
+            red: (bits >> 16) as u8,
+            green: (bits >> 8) as u8,
+            blue: bits as u8,
+        }
+    }
+
+    /// Create a pixel from an RGB vec in the [0,1] range. Colors with a NaN
+    /// or infinite channel come out as [`NON_FINITE_PIXEL`]
+    pub fn from_vek_color(v: Rgb<f32>) -> Self {
+        if !is_finite_color(v) {
+            return NON_FINITE_PIXEL;
+        }
+        Self {
+            red: (v.r.clamp(0.0, 1.0) * 255.99) as u8,
+            green: (v.g.clamp(0.0, 1.0) * 255.99) as u8,
+            blue: (v.b.clamp(0.0, 1.0) * 255.99) as u8,
+        }
+    }
+}
+
+/// What pixels with a NaN or infinite channel are shown as. Loud magenta in
+/// debug builds so bad math in new materials or geometry is easy to spot,
+/// black in release builds
+pub const NON_FINITE_PIXEL: Pixel = if cfg!(debug_assertions) {
+    Pixel {
+        red: 255,
+        green: 0,
+        blue: 255,
+    }
+} else {
+    Pixel {
+        red: 0,
+        green: 0,
+        blue: 0,
+    }
+};
+
+pub fn is_finite_color(color: Rgb<f32>) -> bool {
+    color.iter().all(|c| c.is_finite())
+}
+
+pub fn visualize_normal(normal: Vec3<f32>) -> Rgb<f32> {
+    (normal / 2.0 + 0.5).into()
+}
+
+/// Ray cast at `time`, in the [0,1] range the longest possible exposure
+/// covers. Moving spheres travel their velocity over that range
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: Vec3<f32>,
+    pub direction: Vec3<f32>,
+    pub time: f32,
+}
+
+impl Ray {
+    pub fn new(origin: Vec3<f32>, direction: Vec3<f32>, time: f32) -> Self {
+        Self {
+            origin,
+            direction,
+            time,
+        }
+    }
+}
+
+/// Rays one pixel over from a camera ray in x and y, for working out how
+/// much of a surface the pixel covers
+#[derive(Debug, Clone, Copy)]
+pub struct RayDifferential {
+    pub dx: Ray,
+    pub dy: Ray,
+}
+
+impl RayDifferential {
+    /// [`Footprint`] of the pixel around `hit_record`, which hit `material`.
+    /// The surface is treated as flat around the hit, so the position
+    /// offsets are where the neighbouring rays cross its tangent plane.
+    /// Surface coordinates need the neighbouring rays traced, and only count
+    /// where they hit the same material
+    pub fn footprint(
+        &self,
+        hit_record: &HitRecord,
+        material: &Material,
+        world: &World,
+    ) -> Footprint {
+        let point = hit_record.intersection_point;
+        let normal = hit_record.surface_normal;
+        let on_tangent_plane = |neighbour: Ray| {
+            let denominator = neighbour.direction.dot(normal);
+            // Grazing neighbours run off to infinity, nothing to filter there
+            // that a point sample would get wrong by much less
+            if denominator.abs() < 1e-6 {
+                return Vec3::zero();
+            }
+            let t = (point - neighbour.origin).dot(normal) / denominator;
+            neighbour.origin + t * neighbour.direction - point
+        };
+        let uv_offset = |neighbour: Ray| {
+            if !matches!(material.albedo, Texture::Image(_)) {
+                return Vec2::zero();
+            }
+            match world.hit(neighbour, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY) {
+                Some((hit, hit_material)) if std::ptr::eq(hit_material, material) => {
+                    // Surface coordinates that wrap around, like a sphere's u,
+                    // jump by one at the seam
+                    (hit.uv - hit_record.uv).map(|d| d - d.round())
+                }
+                _ => Vec2::zero(),
+            }
+        };
+        Footprint {
+            dpdx: on_tangent_plane(self.dx),
+            dpdy: on_tangent_plane(self.dy),
+            duvdx: uv_offset(self.dx),
+            duvdy: uv_offset(self.dy),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct HitRecord {
+    pub intersection_point: Vec3<f32>,
+    pub surface_normal: Vec3<f32>,
+    pub distance: f32,
+    /// Surface coordinates of the hit, for textures
+    pub uv: Vec2<f32>,
+    /// Whether the ray arrived from the side the surface faces: outside
+    /// spheres (inside hollow ones), and the side the normal of a plane or
+    /// the winding of a triangle points to
+    pub front_face: bool,
+}
+
+pub const SHADOW_ACNE_FUDGE_CONSTANT: f32 = 0.001;
+
+/// Samples every pixel takes before adaptive sampling may stop it
+const ADAPTIVE_MIN_SAMPLES: usize = 16;
+
+pub trait RandVec: Rng {
+    fn rand_vec3(&mut self) -> Vec3<f32> {
+        Vec3::new(
+            self.gen_range(-1.0..1.0),
+            self.gen_range(-1.0..1.0),
+            self.gen_range(-1.0..1.0),
+        )
+    }
+
+    fn rand_vec3_in_unit_sphere(&mut self) -> Vec3<f32> {
+        let mut v;
+        loop {
+            v = self.rand_vec3();
+            if v.magnitude_squared() < 1.0 {
+                break;
+            }
+        }
+        v
+    }
+
+    /// Point in the unit disk on the XY plane
+    fn rand_in_unit_disk(&mut self) -> Vec3<f32> {
+        let mut v;
+        loop {
+            v = Vec3::new(self.gen_range(-1.0..1.0), self.gen_range(-1.0..1.0), 0.0);
+            if v.magnitude_squared() < 1.0 {
+                break;
+            }
+        }
+        v
+    }
+
+    /// Point in the regular polygon with `sides` corners inscribed in the unit
+    /// circle on the XY plane, with a corner pointing along +Y. Picks one of
+    /// the triangles fanning out from the center, then a point inside it
+    fn rand_in_regular_polygon(&mut self, sides: u32) -> Vec3<f32> {
+        let wedge = self.gen_range(0..sides);
+        let corner = |i: u32| {
+            let angle = std::f32::consts::TAU * i as f32 / sides as f32;
+            Vec3::new(-angle.sin(), angle.cos(), 0.0)
+        };
+        let (mut a, mut b): (f32, f32) = (self.gen(), self.gen());
+        // Fold the far half of the parallelogram back onto the triangle
+        if a + b > 1.0 {
+            (a, b) = (1.0 - a, 1.0 - b);
+        }
+        a * corner(wedge) + b * corner(wedge + 1)
+    }
+
+    /// Uniform point on the unit sphere. Picks z and the azimuth directly
+    /// instead of rejection sampling the cube
+    fn rand_unit_vec3(&mut self) -> Vec3<f32> {
+        let z: f32 = self.gen_range(-1.0..1.0);
+        let phi = self.gen_range(0.0..std::f32::consts::TAU);
+        let r = (1.0 - z * z).sqrt();
+        Vec3::new(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    /// Direction in the hemisphere around the unit vector `normal`, with
+    /// probability proportional to the cosine of its angle to `normal`
+    fn rand_cosine_direction(&mut self, normal: Vec3<f32>) -> Vec3<f32> {
+        let r1: f32 = self.gen();
+        let r2: f32 = self.gen();
+        let phi = std::f32::consts::TAU * r1;
+        let r = r2.sqrt();
+        let local = Vec3::new(r * phi.cos(), r * phi.sin(), (1.0 - r2).sqrt());
+
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        bitangent * local.x + tangent * local.y + normal * local.z
+    }
+
+    /// Normal of a GGX microfacet with roughness `alpha` on a surface with
+    /// the unit `normal`, picked among the microfacets visible from the unit
+    /// direction `view` in proportion to how much of it they cover (Heitz's
+    /// visible normal sampling)
+    fn rand_ggx_visible_normal(
+        &mut self,
+        normal: Vec3<f32>,
+        view: Vec3<f32>,
+        alpha: f32,
+    ) -> Vec3<f32> {
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        let view = Vec3::new(view.dot(bitangent), view.dot(tangent), view.dot(normal));
+        // Stretch the view into the configuration where the microfacets form
+        // a hemisphere, sample the disk of it the view sees, and unstretch
+        let stretched = Vec3::new(alpha * view.x, alpha * view.y, view.z).normalized();
+        let length_squared = stretched.x * stretched.x + stretched.y * stretched.y;
+        let t1 = if length_squared > 0.0 {
+            Vec3::new(-stretched.y, stretched.x, 0.0) / length_squared.sqrt()
+        } else {
+            Vec3::unit_x()
+        };
+        let t2 = stretched.cross(t1);
+        let r = self.gen::<f32>().sqrt();
+        let phi = std::f32::consts::TAU * self.gen::<f32>();
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + stretched.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let hemisphere = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * stretched;
+        let local = Vec3::new(
+            alpha * hemisphere.x,
+            alpha * hemisphere.y,
+            hemisphere.z.max(0.0),
+        )
+        .normalized();
+
+        bitangent * local.x + tangent * local.y + normal * local.z
+    }
+
+    #[allow(dead_code)]
+    fn rand_in_hemisphere(&mut self, normal: Vec3<f32>) -> Vec3<f32> {
+        let in_unit_sphere = self.rand_vec3_in_unit_sphere();
+        if in_unit_sphere.dot(normal) > 0.0 {
+            in_unit_sphere
+        } else {
+            -in_unit_sphere
+        }
+    }
+}
+
+impl<T: Rng> RandVec for T {}
+
+/// Two unit vectors that together with the unit vector `normal` form an
+/// orthonormal basis
+fn orthonormal_basis(normal: Vec3<f32>) -> (Vec3<f32>, Vec3<f32>) {
+    let helper = if normal.x.abs() > 0.9 {
+        Vec3::unit_y()
+    } else {
+        Vec3::unit_x()
+    };
+    let tangent = normal.cross(helper).normalized();
+    (tangent, normal.cross(tangent))
+}
+
+pub trait Hittable {
+    /// Find the closest intersection of `ray` with a distance in
+    /// (`t_min`, `t_max`)
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(HitRecord, &Material)>;
+
+    /// Box enclosing the object, or `None` if it is unbounded
+    fn bounding_box(&self) -> Option<Aabb>;
+}
+
+/// Nearest intersection of `ray` with `sphere` strictly inside (`t_min`, `t_max`).
+/// Grazing rays with a zero discriminant count as misses
+pub fn hit_sphere(ray: Ray, sphere: &Sphere, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    let center = sphere.center(ray.time);
+    let oc = ray.origin - center;
+    let a = ray.direction.dot(ray.direction);
+    let b = 2.0 * oc.dot(ray.direction);
+    let c = oc.dot(oc) - sphere.radius * sphere.radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant > 0.0 {
+        let neg_distance = (-b - discriminant.sqrt()) / (2.0 * a);
+        let pos_distance = (-b + discriminant.sqrt()) / (2.0 * a);
+        let distance = if neg_distance > t_min && neg_distance < t_max {
+            neg_distance
+        } else if pos_distance > t_min && pos_distance < t_max {
+            pos_distance
+        } else {
+            return None;
+        };
+        let intersection_point = ray.origin + ray.direction * distance;
+        // Dividing by the signed radius flips the normal inward for negative
+        // radius spheres, which is what lets them act as hollow shells
+        let surface_normal = (intersection_point - center) / sphere.radius;
+        // Latitude and longitude of the hit, from the outward direction so
+        // textures on hollow shells aren't mirrored
+        let outward = (intersection_point - center) / sphere.radius.abs();
+        let theta = (-outward.y).clamp(-1.0, 1.0).acos();
+        let phi = (-outward.z).atan2(outward.x) + std::f32::consts::PI;
+        Some(HitRecord {
+            intersection_point,
+            surface_normal,
+            distance,
+            uv: Vec2::new(phi / std::f32::consts::TAU, theta / std::f32::consts::PI),
+            front_face: ray.direction.dot(surface_normal) < 0.0,
+        })
+    } else {
+        None
+    }
+}
+
+impl Hittable for Sphere {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(HitRecord, &Material)> {
+        hit_sphere(ray, self, t_min, t_max).map(|hit_record| (hit_record, &self.material))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Cover the whole swept volume for moving spheres
+        let extent = Vec3::broadcast(self.radius.abs());
+        let box_at = |time| Aabb {
+            min: self.center(time) - extent,
+            max: self.center(time) + extent,
+        };
+        Some(box_at(0.0).union(box_at(1.0)))
+    }
+}
+
+impl Hittable for Plane {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(HitRecord, &Material)> {
+        let denominator = ray.direction.dot(self.normal);
+        if denominator.abs() < 1e-6 {
+            return None;
+        }
+        let distance = (self.point - ray.origin).dot(self.normal) / denominator;
+        if distance <= t_min || distance >= t_max {
+            return None;
+        }
+        // Always face the incoming ray so the plane is lit from either side
+        let surface_normal = if denominator < 0.0 {
+            self.normal
+        } else {
+            -self.normal
+        };
+        let intersection_point = ray.origin + ray.direction * distance;
+        // Coordinates along two arbitrary directions in the plane
+        let (tangent, bitangent) = orthonormal_basis(self.normal);
+        let offset = intersection_point - self.point;
+        Some((
+            HitRecord {
+                intersection_point,
+                surface_normal,
+                distance,
+                uv: Vec2::new(offset.dot(tangent), offset.dot(bitangent)),
+                front_face: denominator < 0.0,
+            },
+            &self.material,
+        ))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+}
+
+/// Möller–Trumbore intersection of `ray` with the triangle `vertices`,
+/// shaded with interpolated `normals` if there are any
+fn hit_triangle(
+    ray: Ray,
+    [v0, v1, v2]: [Vec3<f32>; 3],
+    normals: Option<[Vec3<f32>; 3]>,
+    t_min: f32,
+    t_max: f32,
+) -> Option<HitRecord> {
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let p = ray.direction.cross(edge2);
+    let determinant = edge1.dot(p);
+    if determinant.abs() < 1e-8 {
+        return None;
+    }
+    let inverse_determinant = 1.0 / determinant;
+    let s = ray.origin - v0;
+    let u = s.dot(p) * inverse_determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(edge1);
+    let v = ray.direction.dot(q) * inverse_determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let distance = edge2.dot(q) * inverse_determinant;
+    if distance <= t_min || distance >= t_max {
+        return None;
+    }
+    let normal = edge1.cross(edge2).normalized();
+    let front_face = normal.dot(ray.direction) < 0.0;
+    let normal = match normals {
+        Some([n0, n1, n2]) => ((1.0 - u - v) * n0 + u * n1 + v * n2).normalized(),
+        None => normal,
+    };
+    let surface_normal = if front_face { normal } else { -normal };
+    Some(HitRecord {
+        intersection_point: ray.origin + ray.direction * distance,
+        surface_normal,
+        distance,
+        // Barycentric coordinates of the hit
+        uv: Vec2::new(u, v),
+        front_face,
+    })
+}
+
+/// Box around the triangle `vertices`
+fn triangle_box([v0, v1, v2]: [Vec3<f32>; 3]) -> Aabb {
+    // Pad so axis aligned triangles don't get a zero thickness box, which
+    // the slab test would never report as hit
+    let padding = Vec3::broadcast(1e-4);
+    Aabb {
+        min: Vec3::partial_min(v0, Vec3::partial_min(v1, v2)) - padding,
+        max: Vec3::partial_max(v0, Vec3::partial_max(v1, v2)) + padding,
+    }
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(HitRecord, &Material)> {
+        hit_triangle(ray, [self.v0, self.v1, self.v2], self.normals, t_min, t_max)
+            .map(|hit_record| (hit_record, &self.material))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(triangle_box([self.v0, self.v1, self.v2]))
+    }
+}
+
+/// Triangle of a [`MeshObject`], naming its material by index into the
+/// mesh's table instead of holding a copy of it
+struct MeshTriangle {
+    vertices: [Vec3<f32>; 3],
+    normals: Option<[Vec3<f32>; 3]>,
+    material: u16,
+}
+
+/// Hierarchy over the triangles of a [`MeshObject`], referring to them by
+/// index. Split the same way as [`BvhNode`]
+enum MeshBvh {
+    Leaf(u32),
+    Branch {
+        bbox: Aabb,
+        left: Box<MeshBvh>,
+        right: Box<MeshBvh>,
+    },
+}
+
+impl MeshBvh {
+    /// Panics if `indices` is empty
+    fn new(triangles: &[MeshTriangle], indices: &mut [u32]) -> Self {
+        if let [index] = indices {
+            return MeshBvh::Leaf(*index);
+        }
+        let centroid = |index: u32| triangle_box(triangles[index as usize].vertices).centroid();
+        let mut centroid_min = Vec3::broadcast(f32::INFINITY);
+        let mut centroid_max = Vec3::broadcast(f32::NEG_INFINITY);
+        for &index in indices.iter() {
+            centroid_min = Vec3::partial_min(centroid_min, centroid(index));
+            centroid_max = Vec3::partial_max(centroid_max, centroid(index));
+        }
+        let extent = centroid_max - centroid_min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        indices.sort_by(|&a, &b| centroid(a)[axis].total_cmp(&centroid(b)[axis]));
+
+        let (left, right) = indices.split_at_mut(indices.len() / 2);
+        let left = MeshBvh::new(triangles, left);
+        let right = MeshBvh::new(triangles, right);
+        let bbox = left
+            .bounding_box(triangles)
+            .union(right.bounding_box(triangles));
+        MeshBvh::Branch {
+            bbox,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn bounding_box(&self, triangles: &[MeshTriangle]) -> Aabb {
+        match self {
+            MeshBvh::Leaf(index) => triangle_box(triangles[*index as usize].vertices),
+            MeshBvh::Branch { bbox, .. } => *bbox,
+        }
+    }
+
+    /// Closest hit and the material index of the triangle it landed on
+    fn hit(
+        &self,
+        triangles: &[MeshTriangle],
+        ray: Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(HitRecord, u16)> {
+        match self {
+            MeshBvh::Leaf(index) => {
+                let triangle = &triangles[*index as usize];
+                hit_triangle(ray, triangle.vertices, triangle.normals, t_min, t_max)
+                    .map(|hit_record| (hit_record, triangle.material))
+            }
+            MeshBvh::Branch { bbox, left, right } => {
+                if !bbox.hit(ray, t_min, t_max) {
+                    return None;
+                }
+                let left_hit = left.hit(triangles, ray, t_min, t_max);
+                let t_max = left_hit.map_or(t_max, |(hit_record, _)| hit_record.distance);
+                right.hit(triangles, ray, t_min, t_max).or(left_hit)
+            }
+        }
+    }
+}
+
+/// Triangles sharing a small table of materials, which each triangle picks
+/// from with a `u16` index. Large meshes would otherwise spend most of their
+/// memory on copies of the same few materials
+pub struct MeshObject {
+    triangles: Vec<MeshTriangle>,
+    materials: Vec<Material>,
+    bvh: MeshBvh,
+}
+
+impl MeshObject {
+    /// Bytes taken by the triangles and their material table, and what the
+    /// same triangles would take as [`Triangle`]s with a material each
+    pub fn memory(&self) -> (usize, usize) {
+        let indexed = self.triangles.len() * std::mem::size_of::<MeshTriangle>()
+            + self.materials.len() * std::mem::size_of::<Material>();
+        let copied = self.triangles.len() * std::mem::size_of::<Triangle>();
+        (indexed, copied)
+    }
+}
+
+impl Hittable for MeshObject {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(HitRecord, &Material)> {
+        self.bvh
+            .hit(&self.triangles, ray, t_min, t_max)
+            .map(|(hit_record, material)| (hit_record, &self.materials[material as usize]))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bvh.bounding_box(&self.triangles))
+    }
+}
+
+impl Hittable for Quad {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(HitRecord, &Material)> {
+        let n = self.u.cross(self.v);
+        let normal = n.normalized();
+        let denominator = ray.direction.dot(normal);
+        if denominator.abs() < 1e-8 {
+            return None;
+        }
+        let distance = (self.origin - ray.origin).dot(normal) / denominator;
+        if distance <= t_min || distance >= t_max {
+            return None;
+        }
+        let intersection_point = ray.origin + ray.direction * distance;
+        // Coordinates of the hit along `u` and `v`, each in [0,1] inside
+        let w = n / n.dot(n);
+        let planar = intersection_point - self.origin;
+        let alpha = w.dot(planar.cross(self.v));
+        let beta = w.dot(self.u.cross(planar));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+        let front_face = denominator < 0.0;
+        let surface_normal = if front_face { normal } else { -normal };
+        Some((
+            HitRecord {
+                intersection_point,
+                surface_normal,
+                distance,
+                uv: Vec2::new(alpha, beta),
+                front_face,
+            },
+            &self.material,
+        ))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Padded for the same reason as triangles
+        let padding = Vec3::broadcast(1e-4);
+        let corners = [
+            self.origin + self.u,
+            self.origin + self.v,
+            self.origin + self.u + self.v,
+        ];
+        let (min, max) =
+            corners
+                .into_iter()
+                .fold((self.origin, self.origin), |(min, max), corner| {
+                    (
+                        Vec3::partial_min(min, corner),
+                        Vec3::partial_max(max, corner),
+                    )
+                });
+        Some(Aabb {
+            min: min - padding,
+            max: max + padding,
+        })
+    }
+}
+
+/// Closest hit among `objects` by linear scan
+fn closest_hit(
+    objects: &[Box<dyn Hittable + Sync>],
+    ray: Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<(HitRecord, &Material)> {
+    let mut closest = None;
+    let mut closest_distance = t_max;
+    for object in objects {
+        if let Some((hit_record, material)) = object.hit(ray, t_min, closest_distance) {
+            closest_distance = hit_record.distance;
+            closest = Some((hit_record, material));
+        }
+    }
+    closest
+}
+
+impl World {
+    pub fn new(
+        objects: Vec<Box<dyn Hittable + Sync>>,
+        lights: Vec<Sphere>,
+        background: Background,
+    ) -> Self {
+        let (bounded, unbounded): (Vec<_>, Vec<_>) = objects
+            .into_iter()
+            .partition(|object| object.bounding_box().is_some());
+        Self {
+            bvh: (!bounded.is_empty()).then(|| BvhNode::new(bounded)),
+            unbounded,
+            lights,
+            media: Vec::new(),
+            background,
+        }
+    }
+
+    /// Fill the world with `media` too
+    pub fn with_media(mut self, media: Vec<ConstantMedium>) -> Self {
+        self.media = media;
+        self
+    }
+
+    /// Like `hit`, but rays passing through fog or smoke may also scatter
+    /// inside it, more likely the thicker it is along the way
+    pub fn hit_with_media(
+        &self,
+        ray: Ray,
+        t_min: f32,
+        t_max: f32,
+        rng: &mut impl Rng,
+    ) -> Option<(HitRecord, &Material)> {
+        let mut closest = self.hit(ray, t_min, t_max);
+        for medium in &self.media {
+            let t_max = closest.map_or(t_max, |(hit_record, _)| hit_record.distance);
+            if let Some(hit) = medium.hit(ray, t_min, t_max, rng) {
+                closest = Some(hit);
+            }
+        }
+        closest
+    }
+
+    /// The light `hit_record` landed on, if `ray` hit one of `lights`
+    pub fn light_hit_by(&self, ray: Ray, hit_record: &HitRecord) -> Option<&Sphere> {
+        self.lights.iter().find(|light| {
+            hit_sphere(ray, light, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY).is_some_and(
+                |light_hit| {
+                    (light_hit.distance - hit_record.distance).abs()
+                        <= 1e-4 * hit_record.distance.max(1.0)
+                },
+            )
+        })
+    }
+
+    /// Box around every bounded object. Unlike [`Hittable::bounding_box`]
+    /// this skips unbounded objects like planes instead of giving up, so it
+    /// is `None` only when there is nothing bounded at all
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.as_ref().and_then(|bvh| bvh.bounding_box())
+    }
+
+    /// Mistakes in the scene that render as noise instead of failing:
+    /// bounded objects whose boxes nearly coincide, which usually means one
+    /// was placed twice and the copies fight over the closest hit, and
+    /// objects with no size, like spheres of radius 0. Unbounded objects
+    /// aren't checked
+    pub fn validate(&self) -> Vec<String> {
+        let mut boxes = Vec::new();
+        if let Some(bvh) = &self.bvh {
+            bvh.leaf_boxes(&mut boxes);
+        }
+        let at = |bbox: &Aabb| {
+            let c = bbox.centroid();
+            format!("({}, {}, {})", c.x, c.y, c.z)
+        };
+        let mut warnings = Vec::new();
+        for bbox in &boxes {
+            let extent = bbox.max - bbox.min;
+            if !extent.iter().all(|e| e.is_finite()) || extent == Vec3::zero() {
+                warnings.push(format!("object at {} has no size", at(bbox)));
+            }
+        }
+        // Sorted along x so only boxes starting close together are compared
+        boxes.sort_by(|a, b| a.min.x.total_cmp(&b.min.x));
+        for (i, a) in boxes.iter().enumerate() {
+            let tolerance = 1e-4 * (a.max - a.min).reduce_partial_max().max(1.0);
+            let close = |p: Vec3<f32>, q: Vec3<f32>| {
+                (p - q).map(f32::abs).reduce_partial_max() <= tolerance
+            };
+            for b in boxes[i + 1..]
+                .iter()
+                .take_while(|b| b.min.x - a.min.x <= tolerance)
+            {
+                if close(a.min, b.min) && close(a.max, b.max) {
+                    warnings.push(format!(
+                        "two objects at {} almost exactly overlap, expect noise where they fight over the closest hit",
+                        at(a)
+                    ));
+                }
+            }
+        }
+        warnings
+    }
+}
+
+impl World {
+    /// Number of BVH nodes visited finding what `ray` hits
+    pub fn bvh_visits(&self, ray: Ray) -> u32 {
+        let mut visits = 0;
+        if let Some(bvh) = &self.bvh {
+            bvh.hit_counting(ray, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY, &mut visits);
+        }
+        visits
+    }
+}
+
+impl Hittable for World {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(HitRecord, &Material)> {
+        let bvh_hit = self.bvh.as_ref().and_then(|bvh| bvh.hit(ray, t_min, t_max));
+        let t_max = bvh_hit.map_or(t_max, |(hit_record, _)| hit_record.distance);
+        closest_hit(&self.unbounded, ray, t_min, t_max).or(bvh_hit)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if self.unbounded.is_empty() {
+            self.bvh.as_ref().and_then(|bvh| bvh.bounding_box())
+        } else {
+            None
+        }
+    }
+}
+
+/// Axis aligned bounding box
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Vec3<f32>,
+    pub max: Vec3<f32>,
+}
+
+impl Aabb {
+    /// Slab test. Only answers whether the ray enters the box within
+    /// (`t_min`, `t_max`)
+    pub fn hit(&self, ray: Ray, mut t_min: f32, mut t_max: f32) -> bool {
+        for axis in 0..3 {
+            let inverse_direction = 1.0 / ray.direction[axis];
+            let mut t0 = (self.min[axis] - ray.origin[axis]) * inverse_direction;
+            let mut t1 = (self.max[axis] - ray.origin[axis]) * inverse_direction;
+            if inverse_direction < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = t0.max(t_min);
+            t_max = t1.min(t_max);
+            if t_max <= t_min {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: Vec3::partial_min(self.min, other.min),
+            max: Vec3::partial_max(self.max, other.max),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3<f32> {
+        (self.min + self.max) / 2.0
+    }
+}
+
+/// Bounding volume hierarchy over bounded objects, split along the longest
+/// axis of the node's centroids
+pub enum BvhNode {
+    Leaf(Box<dyn Hittable + Sync>),
+    Branch {
+        bbox: Aabb,
+        left: Box<BvhNode>,
+        right: Box<BvhNode>,
+    },
+}
+
+impl BvhNode {
+    /// Panics if `objects` is empty or contains an unbounded object
+    pub fn new(mut objects: Vec<Box<dyn Hittable + Sync>>) -> Self {
+        let centroid = |object: &(dyn Hittable + Sync)| {
+            object
+                .bounding_box()
+                .expect("BVH objects must be bounded")
+                .centroid()
+        };
+        if objects.len() == 1 {
+            return BvhNode::Leaf(objects.pop().unwrap());
+        }
+
+        let mut centroid_min = Vec3::broadcast(f32::INFINITY);
+        let mut centroid_max = Vec3::broadcast(f32::NEG_INFINITY);
+        for object in &objects {
+            let c = centroid(object.as_ref());
+            centroid_min = Vec3::partial_min(centroid_min, c);
+            centroid_max = Vec3::partial_max(centroid_max, c);
+        }
+        let extent = centroid_max - centroid_min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        objects.sort_by(|a, b| centroid(a.as_ref())[axis].total_cmp(&centroid(b.as_ref())[axis]));
+
+        let right = objects.split_off(objects.len() / 2);
+        let left = BvhNode::new(objects);
+        let right = BvhNode::new(right);
+        let bbox = left
+            .bounding_box()
+            .unwrap()
+            .union(right.bounding_box().unwrap());
+        BvhNode::Branch {
+            bbox,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+}
+
+impl BvhNode {
+    /// Adds the box of every object under the node to `boxes`
+    fn leaf_boxes(&self, boxes: &mut Vec<Aabb>) {
+        match self {
+            BvhNode::Leaf(object) => boxes.extend(object.bounding_box()),
+            BvhNode::Branch { left, right, .. } => {
+                left.leaf_boxes(boxes);
+                right.leaf_boxes(boxes);
+            }
+        }
+    }
+}
+
+impl BvhNode {
+    /// `hit` that also adds the number of nodes it visited to `visits`
+    pub fn hit_counting(
+        &self,
+        ray: Ray,
+        t_min: f32,
+        t_max: f32,
+        visits: &mut u32,
+    ) -> Option<(HitRecord, &Material)> {
+        *visits += 1;
+        match self {
+            BvhNode::Leaf(object) => object.hit(ray, t_min, t_max),
+            BvhNode::Branch { bbox, left, right } => {
+                if !bbox.hit(ray, t_min, t_max) {
+                    return None;
+                }
+                let left_hit = left.hit_counting(ray, t_min, t_max, visits);
+                let t_max = left_hit.map_or(t_max, |(hit_record, _)| hit_record.distance);
+                right.hit_counting(ray, t_min, t_max, visits).or(left_hit)
+            }
+        }
+    }
+}
+
+impl Hittable for BvhNode {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(HitRecord, &Material)> {
+        self.hit_counting(ray, t_min, t_max, &mut 0)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        match self {
+            BvhNode::Leaf(object) => object.bounding_box(),
+            BvhNode::Branch { bbox, .. } => Some(*bbox),
+        }
+    }
+}
+
+/// `object` moved by `offset`
+pub struct Translate {
+    pub object: Box<dyn Hittable + Sync>,
+    pub offset: Vec3<f32>,
+}
+
+impl Hittable for Translate {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(HitRecord, &Material)> {
+        let moved = Ray::new(ray.origin - self.offset, ray.direction, ray.time);
+        self.object
+            .hit(moved, t_min, t_max)
+            .map(|(hit_record, material)| {
+                let hit_record = HitRecord {
+                    intersection_point: hit_record.intersection_point + self.offset,
+                    ..hit_record
+                };
+                (hit_record, material)
+            })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.object.bounding_box().map(|bbox| Aabb {
+            min: bbox.min + self.offset,
+            max: bbox.max + self.offset,
+        })
+    }
+}
+
+/// `object` rotated about the Y axis through the origin
+pub struct RotateY {
+    object: Box<dyn Hittable + Sync>,
+    sin_theta: f32,
+    cos_theta: f32,
+    bbox: Option<Aabb>,
+}
+
+impl RotateY {
+    /// Rotate counterclockwise by `degrees` looking down from +Y
+    pub fn new(object: Box<dyn Hittable + Sync>, degrees: f32) -> Self {
+        let (sin_theta, cos_theta) = degrees.to_radians().sin_cos();
+        let mut rotated = Self {
+            object,
+            sin_theta,
+            cos_theta,
+            bbox: None,
+        };
+        // Box around the rotated corners of the object's box
+        rotated.bbox = rotated.object.bounding_box().map(|bbox| {
+            let corners = (0..8).map(|corner| {
+                let pick = |bit: usize, axis: usize| {
+                    if corner & bit == 0 {
+                        bbox.min[axis]
+                    } else {
+                        bbox.max[axis]
+                    }
+                };
+                rotated.to_world(Vec3::new(pick(1, 0), pick(2, 1), pick(4, 2)))
+            });
+            corners
+                .map(|corner| Aabb {
+                    min: corner,
+                    max: corner,
+                })
+                .reduce(Aabb::union)
+                .expect("a box has corners")
+        });
+        rotated
+    }
+
+    pub fn to_object(&self, v: Vec3<f32>) -> Vec3<f32> {
+        Vec3::new(
+            self.cos_theta * v.x - self.sin_theta * v.z,
+            v.y,
+            self.sin_theta * v.x + self.cos_theta * v.z,
+        )
+    }
+
+    pub fn to_world(&self, v: Vec3<f32>) -> Vec3<f32> {
+        Vec3::new(
+            self.cos_theta * v.x + self.sin_theta * v.z,
+            v.y,
+            -self.sin_theta * v.x + self.cos_theta * v.z,
+        )
+    }
+}
+
+impl Hittable for RotateY {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(HitRecord, &Material)> {
+        let rotated = Ray::new(
+            self.to_object(ray.origin),
+            self.to_object(ray.direction),
+            ray.time,
+        );
+        self.object
+            .hit(rotated, t_min, t_max)
+            .map(|(hit_record, material)| {
+                let hit_record = HitRecord {
+                    intersection_point: self.to_world(hit_record.intersection_point),
+                    surface_normal: self.to_world(hit_record.surface_normal),
+                    ..hit_record
+                };
+                (hit_record, material)
+            })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bbox
+    }
+}
+
+/// Fog or smoke of uniform `density` filling `boundary`, which must be
+/// closed and convex. Rays scatter with an isotropic `material` after an
+/// exponentially distributed distance inside it
+pub struct ConstantMedium {
+    pub boundary: Box<dyn Hittable + Sync>,
+    pub density: f32,
+    pub material: Material,
+}
+
+impl ConstantMedium {
+    /// Where `ray` scatters inside the medium between `t_min` and `t_max`,
+    /// drawn from `rng`, if it doesn't make it through. Unlike with a
+    /// `Hittable`, whether the same ray hits is random
+    pub fn hit(
+        &self,
+        ray: Ray,
+        t_min: f32,
+        t_max: f32,
+        rng: &mut impl Rng,
+    ) -> Option<(HitRecord, &Material)> {
+        // Where the ray enters and leaves the boundary, wherever it started
+        let (entry, _) = self.boundary.hit(ray, f32::NEG_INFINITY, f32::INFINITY)?;
+        let (exit, _) = self
+            .boundary
+            .hit(ray, entry.distance + 1e-4, f32::INFINITY)?;
+        let start = entry.distance.max(t_min).max(0.0);
+        let end = exit.distance.min(t_max);
+        if start >= end {
+            return None;
+        }
+
+        let ray_length = ray.direction.magnitude();
+        let distance_inside = (end - start) * ray_length;
+        // 1 - gen() is in (0,1], so the log stays finite
+        let scatter_distance = -(1.0 - rng.gen::<f32>()).ln() / self.density;
+        if scatter_distance > distance_inside {
+            return None;
+        }
+        let distance = start + scatter_distance / ray_length;
+        Some((
+            HitRecord {
+                intersection_point: ray.origin + ray.direction * distance,
+                // Isotropic scattering doesn't look at the normal
+                surface_normal: Vec3::unit_x(),
+                distance,
+                uv: Vec2::zero(),
+                front_face: true,
+            },
+            &self.material,
+        ))
+    }
+}
+
+/// Knobs for how an image is rendered. Scene files may carry these, in which
+/// case fields they leave out keep their defaults
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RenderSettings {
+    /// Samples per pixel
+    pub samples: usize,
+    /// Combined cap on the number of bounces a path may take
+    pub max_depth: usize,
+    /// Cap on bounces off diffuse surfaces. Falls back to `max_depth`
+    pub max_diffuse_bounces: Option<usize>,
+    /// Cap on bounces off specular (metal and glass) surfaces. Falls back to
+    /// `max_depth`
+    pub max_specular_bounces: Option<usize>,
+    /// Base seed for the per-pixel rngs. A fresh random seed is used when
+    /// this is `None`
+    pub seed: Option<u64>,
+    pub tone_map: ToneMap,
+    pub filter: Filter,
+    pub sampler: Sampler,
+    /// Radius in pixels of the tent each sample is splatted over. 0.5 keeps
+    /// every sample inside its own pixel
+    pub filter_radius: f32,
+    /// Multiplier on linear color before tone mapping. Only affects what is
+    /// displayed, linear buffers and EXR output are left as rendered
+    pub exposure: f32,
+    /// Display gamma. 1 writes linear values
+    pub gamma: f32,
+    /// Transfer function applied after tone mapping. `gamma` is only used
+    /// by `ColorSpace::Gamma`
+    pub color_space: ColorSpace,
+    /// Number of bounces before paths become eligible for Russian roulette
+    pub rr_min_bounces: usize,
+    /// Side length in pixels of the square tiles the image is split into
+    pub tile_size: usize,
+    /// Width of the 95% confidence interval below which a pixel stops taking
+    /// samples. `None` always takes `samples` samples
+    pub tolerance: Option<f32>,
+    /// Cap on samples per pixel when `tolerance` is set
+    pub max_samples: usize,
+    /// Sample lights directly at diffuse bounces (next event estimation)
+    pub nee: bool,
+    /// Scatter off metals the old way, by jittering the mirror direction,
+    /// instead of sampling GGX microfacets
+    pub legacy_metal: bool,
+    /// Leave out the multiple scattering compensation on GGX metals, so light
+    /// that would bounce between microfacets more than once is lost
+    pub single_scatter_metal: bool,
+    /// Leave the background out where the camera sees it directly, so it only
+    /// lights the scene and shows in reflections. Those pixels come out
+    /// black for compositing over a separate plate
+    pub env_lighting_only: bool,
+    /// With an even sample count, take samples in pairs with the second
+    /// mirrored around the pixel center from the first
+    pub antithetic: bool,
+    /// Blend pixels on the edge of an object against the background by how
+    /// many of the pixel's corners the object covers, so silhouettes come out
+    /// smooth even at 1 sample per pixel. Only primary silhouettes against
+    /// the background, not edges in front of other objects or reflections
+    pub analytic_aa: bool,
+    /// Show a debug view instead of the rendered image
+    pub debug: Option<DebugView>,
+    /// Scale down samples brighter than this luminance before averaging.
+    /// Biased, but gets rid of fireflies
+    pub clamp: Option<f32>,
+    /// Render at this multiple of the width and height and average back
+    /// down. 1 renders at the requested size
+    pub ssaa: u32,
+    /// Only render the pixels inside this rectangle and leave the rest
+    /// black. The camera still covers the whole image, so the region matches
+    /// the same pixels of a full render
+    pub crop: Option<Crop>,
+    /// Only render these tiles of the `tile_size` grid, numbered row by row,
+    /// and leave the rest black. Lets several processes with the same seed
+    /// each render part of an image that merges back seamlessly
+    pub tile_range: Option<TileRange>,
+    /// Spatial standard deviation in pixels of the bilateral filter run over
+    /// finished images. `None` leaves them as rendered
+    pub denoise: Option<f32>,
+    /// Only gather the part of the light this picks out
+    pub lighting: Lighting,
+    /// Scale `exposure` so the median luminance of each finished image
+    /// comes out as middle gray. See [`RenderSettings::exposed_for`]
+    pub auto_exposure: bool,
+    /// How far rays reflected and transmitted by glass start off its
+    /// surface, along the normal and relative to how far the hit is from the
+    /// origin. Transmitted rays can step much less far without acne, which
+    /// keeps them from skipping the inner wall of a thin shell
+    pub reflection_offset: f32,
+    pub transmission_offset: f32,
+    /// Let paths cut off by a bounce cap pick up the average background
+    /// radiance instead of no light at all. An approximation, but it keeps
+    /// hall-of-mirrors scenes from darkening
+    pub truncation_estimate: bool,
+}
+
+impl RenderSettings {
+    /// These settings with `exposure` adjusted for `colors` if
+    /// `auto_exposure` is set, and the adjustment in stops. Images with
+    /// nothing lit are left alone
+    pub fn exposed_for(&self, colors: &[Rgb<f32>]) -> (Self, Option<f32>) {
+        let median = match self.auto_exposure {
+            true => median_luminance(&luminance_histogram(colors)),
+            false => None,
+        };
+        match median {
+            Some(median) => {
+                let ev = (MIDDLE_GRAY / median).log2();
+                let settings = Self {
+                    exposure: self.exposure * ev.exp2(),
+                    ..*self
+                };
+                (settings, Some(ev))
+            }
+            None => (*self, None),
+        }
+    }
+
+    /// Whether the pixel at (`x`, `y`) of an image `width` pixels wide should
+    /// be rendered at all
+    pub fn renders_pixel(&self, x: usize, y: usize, width: usize) -> bool {
+        let tile = y / self.tile_size * width.div_ceil(self.tile_size) + x / self.tile_size;
+        self.crop.is_none_or(|crop| crop.contains(x, y))
+            && self.tile_range.is_none_or(|range| range.contains(tile))
+    }
+
+    /// Whether any pixel within `pad` pixels of (`x`, `y`) is rendered, so
+    /// samples taken at (`x`, `y`) could spread onto it
+    fn reaches_rendered_pixel(
+        &self,
+        x: usize,
+        y: usize,
+        size: PhysicalSize<u32>,
+        pad: usize,
+    ) -> bool {
+        let (width, height) = (size.width as usize, size.height as usize);
+        (y.saturating_sub(pad)..=(y + pad).min(height - 1)).any(|ny| {
+            (x.saturating_sub(pad)..=(x + pad).min(width - 1))
+                .any(|nx| self.renders_pixel(nx, ny, width))
+        })
+    }
+}
+
+/// Tiles from `start` up to but not including `end`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TileRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl TileRange {
+    pub fn contains(&self, tile: usize) -> bool {
+        (self.start..self.end).contains(&tile)
+    }
+}
+
+/// Pixel rectangle from (`x0`, `y0`) up to but not including (`x1`, `y1`)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Crop {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Crop {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x0..self.x1).contains(&x) && (self.y0..self.y1).contains(&y)
+    }
+
+    /// The same region of an image `factor` times larger
+    pub fn scaled(self, factor: usize) -> Self {
+        Self {
+            x0: self.x0 * factor,
+            y0: self.y0 * factor,
+            x1: self.x1 * factor,
+            y1: self.y1 * factor,
+        }
+    }
+}
+
+/// Light [`ray_cast`] gathers, split by how many times it bounced on the way
+/// to the camera. The direct and indirect parts of a render add up to the
+/// whole of it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Lighting {
+    #[default]
+    All,
+    /// Lights and background seen directly or after a single bounce
+    Direct,
+    /// Everything that bounced at least twice
+    Indirect,
+}
+
+impl Lighting {
+    /// Whether light that bounced `bounces` times is gathered
+    fn includes(self, bounces: usize) -> bool {
+        match self {
+            Lighting::All => true,
+            Lighting::Direct => bounces <= 1,
+            Lighting::Indirect => bounces > 1,
+        }
+    }
+}
+
+impl FromStr for Lighting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Lighting::All),
+            "direct" => Ok(Lighting::Direct),
+            "indirect" => Ok(Lighting::Indirect),
+            _ => Err("expected one of all, direct, indirect".to_string()),
+        }
+    }
+}
+
+/// Operator compressing linear HDR color into [0,1] before gamma
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum ToneMap {
+    /// Clip anything above 1
+    #[default]
+    Clamp,
+    /// `c / (1 + c)` per channel
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve
+    AcesFilmic,
+}
+
+impl ToneMap {
+    pub fn apply(self, color: Rgb<f32>) -> Rgb<f32> {
+        match self {
+            // Pixel::from_vek_color clamps anyway
+            ToneMap::Clamp => color,
+            ToneMap::Reinhard => color.map(|c| c / (1.0 + c)),
+            ToneMap::AcesFilmic => {
+                color.map(|c| (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14))
+            }
+        }
+    }
+}
+
+impl FromStr for ToneMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(ToneMap::Clamp),
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "aces" => Ok(ToneMap::AcesFilmic),
+            _ => Err("expected one of clamp, reinhard, aces".to_string()),
+        }
+    }
+}
+
+/// Encoding of tone mapped color for display
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum ColorSpace {
+    /// Plain power curve with `RenderSettings::gamma`
+    #[default]
+    Gamma,
+    /// The piecewise sRGB transfer function. Ignores the gamma setting
+    Srgb,
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gamma" => Ok(ColorSpace::Gamma),
+            "srgb" => Ok(ColorSpace::Srgb),
+            _ => Err("expected one of gamma, srgb".to_string()),
+        }
+    }
+}
+
+/// Debug views that show one property of the hits instead of the rendered
+/// image
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DebugView {
+    /// Surface normal mapped from [-1,1] to [0,1] per axis
+    Normals,
+    /// Grayscale, brighter the closer the hit
+    Depth,
+    /// Surface color with no lighting
+    Albedo,
+    /// Average number of bounces the pixel's paths took, from blue for few
+    /// to red for many. Unlike the others this path traces every sample
+    Bounces,
+    /// Fraction of the pixel's samples whose primary ray hit something,
+    /// for a matte with antialiased edges. Takes every sample like
+    /// `Bounces`, but doesn't trace past the first hit
+    Coverage,
+    /// How many BVH nodes the ray through the pixel center visited, from
+    /// blue for few to red for many. Shows off badly split hierarchies
+    Bvh,
+    /// World space position of the first hit in the color channels, for
+    /// relighting outside the renderer. Misses are [`NO_HIT_POSITION`]. Only
+    /// meaningful written out as EXR
+    Position,
+}
+
+/// What [`DebugView::Position`] gives pixels that see the background. Far
+/// enough out to never be a real hit
+pub const NO_HIT_POSITION: f32 = f32::MAX;
+
+impl DebugView {
+    /// Whether the view only needs one ray through the pixel center
+    pub fn single_ray(self) -> bool {
+        !matches!(self, DebugView::Bounces | DebugView::Coverage)
+    }
+
+    /// Color of a single ray view for `ray`
+    pub fn cast(self, ray: Ray, world: &World) -> Rgb<f32> {
+        if let DebugView::Bvh = self {
+            return heat(world.bvh_visits(ray) as f32, BVH_HEAT_HALF);
+        }
+        let Some((hit_record, material)) =
+            world.hit(ray, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY)
+        else {
+            return match self {
+                DebugView::Depth | DebugView::Bounces | DebugView::Coverage => Rgb::broadcast(0.0),
+                DebugView::Normals | DebugView::Albedo => world.background.color(ray.direction),
+                DebugView::Position => Rgb::broadcast(NO_HIT_POSITION),
+                DebugView::Bvh => unreachable!(),
+            };
+        };
+        match self {
+            DebugView::Normals => visualize_normal(hit_record.surface_normal),
+            DebugView::Depth => Rgb::broadcast(1.0 / (1.0 + hit_record.distance)),
+            DebugView::Albedo => material
+                .albedo
+                .value(hit_record.uv, hit_record.intersection_point),
+            DebugView::Bounces | DebugView::Coverage => {
+                unreachable!("bounce counts and coverage are averaged in draw_pixel")
+            }
+            DebugView::Position => Rgb::from(hit_record.intersection_point),
+            DebugView::Bvh => unreachable!(),
+        }
+    }
+}
+
+/// Bounce count `DebugView::Bounces` shows half way up the palette, a
+/// typical diffuse path
+const BOUNCE_HEAT_HALF: f32 = 3.0;
+
+/// Node visits `DebugView::Bvh` shows half way up the palette
+const BVH_HEAT_HALF: f32 = 32.0;
+
+/// Blue through green to red as `count` goes from none to many, passing
+/// green at `half`
+fn heat(count: f32, half: f32) -> Rgb<f32> {
+    let t = count / (count + half);
+    let (blue, green, red) = (
+        Rgb::new(0.0, 0.0, 1.0),
+        Rgb::new(0.0, 1.0, 0.0),
+        Rgb::new(1.0, 0.0, 0.0),
+    );
+    if t < 0.5 {
+        Lerp::lerp(blue, green, 2.0 * t)
+    } else {
+        Lerp::lerp(green, red, 2.0 * t - 1.0)
+    }
+}
+
+impl FromStr for DebugView {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normals" => Ok(DebugView::Normals),
+            "depth" => Ok(DebugView::Depth),
+            "albedo" => Ok(DebugView::Albedo),
+            "bounces" => Ok(DebugView::Bounces),
+            "coverage" => Ok(DebugView::Coverage),
+            "bvh" => Ok(DebugView::Bvh),
+            "position" => Ok(DebugView::Position),
+            _ => Err(
+                "expected one of normals, depth, albedo, bounces, coverage, bvh, position"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// Where within the pixel samples land
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Sampler {
+    /// Independent uniform random positions, stratified over a grid when
+    /// the sample count is a perfect square
+    #[default]
+    Random,
+    /// A Halton sequence shifted per pixel by a tiled blue noise texture.
+    /// Neighbouring pixels get dissimilar offsets, so the error at low
+    /// sample counts is high frequency noise that reads as less grainy
+    BlueNoise,
+}
+
+impl FromStr for Sampler {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Sampler::Random),
+            "bluenoise" => Ok(Sampler::BlueNoise),
+            _ => Err("expected one of random, bluenoise".to_string()),
+        }
+    }
+}
+
+/// Side length of the square blue noise texture, which tiles the image
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Two independent blue noise values in [0,1) per texel, row by row. Always
+/// built from the same seeds so renders are reproducible
+fn blue_noise() -> &'static [Vec2<f32>] {
+    static BLUE_NOISE: OnceLock<Vec<Vec2<f32>>> = OnceLock::new();
+    BLUE_NOISE.get_or_init(|| {
+        let x = void_and_cluster(&mut StdRng::seed_from_u64(0));
+        let y = void_and_cluster(&mut StdRng::seed_from_u64(1));
+        x.into_iter().zip(y).map(Vec2::from).collect()
+    })
+}
+
+/// Blue noise texture by Ulichney's void and cluster method: every texel
+/// gets a rank by which it would be switched on in a pattern kept as evenly
+/// spread as possible, under a Gaussian measure of crowding on the torus
+fn void_and_cluster(rng: &mut impl Rng) -> Vec<f32> {
+    const N: usize = BLUE_NOISE_SIZE;
+    const SIGMA: f32 = 1.5;
+    let wrapped = |d: usize| d.min(N - d) as f32;
+    let kernel: Vec<f32> = (0..N * N)
+        .map(|i| {
+            let (dx, dy) = (wrapped(i % N), wrapped(i / N));
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect();
+    // Crowding at every texel from the texels currently on
+    let mut energy = vec![0.0; N * N];
+    let mut on = vec![false; N * N];
+    let toggle = |energy: &mut [f32], on: &mut [bool], texel: usize| {
+        on[texel] = !on[texel];
+        let sign = if on[texel] { 1.0 } else { -1.0 };
+        let (x, y) = (texel % N, texel / N);
+        for (i, e) in energy.iter_mut().enumerate() {
+            let (dx, dy) = ((i % N + N - x) % N, (i / N + N - y) % N);
+            *e += sign * kernel[dy * N + dx];
+        }
+    };
+    // Tightest cluster among the texels that are on, or biggest void among
+    // those that are off
+    let extreme = |energy: &[f32], on: &[bool], state: bool| -> usize {
+        let candidates = (0..N * N).filter(|&i| on[i] == state);
+        if state {
+            candidates.max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+        } else {
+            candidates.min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+        }
+        .expect("there is always a texel in each state")
+    };
+
+    // Start from a random tenth of the texels and move the most crowded one
+    // into the emptiest spot until that stops changing anything
+    let initial_count = N * N / 10;
+    let mut texels: Vec<usize> = (0..N * N).collect();
+    texels.shuffle(rng);
+    for &texel in &texels[..initial_count] {
+        toggle(&mut energy, &mut on, texel);
+    }
+    loop {
+        let cluster = extreme(&energy, &on, true);
+        toggle(&mut energy, &mut on, cluster);
+        let void = extreme(&energy, &on, false);
+        toggle(&mut energy, &mut on, void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0; N * N];
+    // Ranks below the initial pattern come from taking it apart cluster by
+    // cluster, the rest from filling voids until every texel is on
+    let (initial_energy, initial_on) = (energy.clone(), on.clone());
+    for r in (0..initial_count).rev() {
+        let cluster = extreme(&energy, &on, true);
+        toggle(&mut energy, &mut on, cluster);
+        rank[cluster] = r;
+    }
+    let (mut energy, mut on) = (initial_energy, initial_on);
+    for r in initial_count..N * N {
+        let void = extreme(&energy, &on, false);
+        toggle(&mut energy, &mut on, void);
+        rank[void] = r;
+    }
+    rank.into_iter()
+        .map(|r| (r as f32 + 0.5) / (N * N) as f32)
+        .collect()
+}
+
+/// The `index`th element of the van der Corput sequence in `base`, one axis
+/// of the Halton sequence
+fn radical_inverse(mut index: usize, base: usize) -> f32 {
+    let mut result = 0.0;
+    let mut scale = 1.0 / base as f32;
+    while index > 0 {
+        result += (index % base) as f32 * scale;
+        index /= base;
+        scale /= base as f32;
+    }
+    result
+}
+
+/// Pixel reconstruction filter, applied by how sample positions are spread
+/// around the pixel
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Filter {
+    /// Samples spread evenly over the pixel
+    #[default]
+    Box,
+    /// Samples concentrated at the pixel center, falling off linearly to
+    /// one pixel away
+    Tent,
+}
+
+impl Filter {
+    /// Map a uniform offset in [0,1) within the pixel to one distributed
+    /// according to the filter
+    pub fn remap(self, jitter: f32) -> f32 {
+        match self {
+            Filter::Box => jitter,
+            Filter::Tent => {
+                let r = 2.0 * jitter;
+                let offset = if r < 1.0 {
+                    r.sqrt() - 1.0
+                } else {
+                    1.0 - (2.0 - r).sqrt()
+                };
+                0.5 + offset
+            }
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(Filter::Box),
+            "tent" => Ok(Filter::Tent),
+            _ => Err("expected one of box, tent".to_string()),
+        }
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            samples: 4,
+            max_depth: 100,
+            max_diffuse_bounces: None,
+            max_specular_bounces: None,
+            seed: None,
+            tone_map: ToneMap::default(),
+            filter: Filter::default(),
+            sampler: Sampler::default(),
+            filter_radius: 0.5,
+            exposure: 1.0,
+            gamma: 2.0,
+            color_space: ColorSpace::default(),
+            rr_min_bounces: 4,
+            tile_size: 32,
+            tolerance: None,
+            max_samples: 1024,
+            nee: true,
+            legacy_metal: false,
+            single_scatter_metal: false,
+            env_lighting_only: false,
+            antithetic: false,
+            analytic_aa: false,
+            debug: None,
+            clamp: None,
+            ssaa: 1,
+            crop: None,
+            tile_range: None,
+            denoise: None,
+            lighting: Lighting::default(),
+            auto_exposure: false,
+            reflection_offset: 1e-3,
+            transmission_offset: 1e-5,
+            truncation_estimate: false,
+        }
+    }
+}
+
+pub fn reflected(v: Vec3<f32>, n: Vec3<f32>) -> Vec3<f32> {
+    v - 2.0 * v.dot(n) * n
+}
+
+/// Fraction of GGX microfacets with roughness `alpha` left unshadowed when
+/// seen from an angle with cosine `cos_theta` to the macro surface normal
+pub fn smith_ggx_g1(cos_theta: f32, alpha: f32) -> f32 {
+    let alpha_squared = alpha * alpha;
+    2.0 * cos_theta
+        / (cos_theta + (alpha_squared + (1.0 - alpha_squared) * cos_theta * cos_theta).sqrt())
+}
+
+/// Side length of the tabulated GGX albedo, over cosines and roughnesses
+const GGX_ALBEDO_SIZE: usize = 32;
+
+/// Fraction of the light arriving from an angle with cosine `cos_theta` that
+/// GGX microfacets with roughness `alpha`, both in [0,1], reflect after a
+/// single bounce, and that fraction averaged over the hemisphere by cosine.
+/// Tabulated once by sampling from a fixed seed, then bilinearly interpolated
+fn ggx_albedo(cos_theta: f32, alpha: f32) -> (f32, f32) {
+    const N: usize = GGX_ALBEDO_SIZE;
+    const SAMPLES: usize = 1024;
+    // Albedo by roughness row and cosine column, then averages by roughness
+    static TABLE: OnceLock<(Vec<f32>, Vec<f32>)> = OnceLock::new();
+    let (albedo, average) = TABLE.get_or_init(|| {
+        let mut rng = StdRng::seed_from_u64(0);
+        let normal = Vec3::unit_z();
+        let albedo: Vec<f32> = (0..N * N)
+            .map(|i| {
+                let alpha = (i / N) as f32 / (N - 1) as f32;
+                let cos_theta = (i % N) as f32 / (N - 1) as f32;
+                let view = Vec3::new((1.0 - cos_theta * cos_theta).sqrt(), 0.0, cos_theta);
+                let total: f32 = (0..SAMPLES)
+                    .map(|_| {
+                        let microfacet = rng.rand_ggx_visible_normal(normal, view, alpha);
+                        let cos_out = reflected(-view, microfacet).z;
+                        if cos_out > 0.0 {
+                            smith_ggx_g1(cos_out, alpha)
+                        } else {
+                            0.0
+                        }
+                    })
+                    .sum();
+                total / SAMPLES as f32
+            })
+            .collect();
+        // Trapezoid rule over the cosine, weighted by it
+        let average = albedo
+            .chunks(N)
+            .map(|row| {
+                let step = 1.0 / (N - 1) as f32;
+                let total: f32 = row
+                    .windows(2)
+                    .enumerate()
+                    .map(|(i, pair)| {
+                        let (c0, c1) = (i as f32 * step, (i + 1) as f32 * step);
+                        0.5 * (pair[0] * c0 + pair[1] * c1) * step
+                    })
+                    .sum();
+                (2.0 * total).min(1.0)
+            })
+            .collect();
+        (albedo, average)
+    });
+    let lookup = |x: f32| {
+        let x = x.clamp(0.0, 1.0) * (N - 1) as f32;
+        let i = (x as usize).min(N - 2);
+        (i, x - i as f32)
+    };
+    let (row, row_t) = lookup(alpha);
+    let (column, column_t) = lookup(cos_theta);
+    let at = |row: usize| {
+        Lerp::lerp(
+            albedo[row * N + column],
+            albedo[row * N + column + 1],
+            column_t,
+        )
+    };
+    (
+        Lerp::lerp(at(row), at(row + 1), row_t),
+        Lerp::lerp(average[row], average[row + 1], row_t),
+    )
+}
+
+/// Refract unit vector `v` through a surface with unit normal `n` facing
+/// against it. `eta_ratio` is the incident IOR over the transmitted IOR
+pub fn refracted(v: Vec3<f32>, n: Vec3<f32>, eta_ratio: f32) -> Vec3<f32> {
+    let cos_theta = (-v).dot(n).min(1.0);
+    let perpendicular = eta_ratio * (v + cos_theta * n);
+    let parallel = -(1.0 - perpendicular.magnitude_squared()).abs().sqrt() * n;
+    perpendicular + parallel
+}
+
+/// Schlick's approximation of the Fresnel reflectance
+pub fn schlick_reflectance(cos_theta: f32, eta_ratio: f32) -> f32 {
+    let r0 = ((1.0 - eta_ratio) / (1.0 + eta_ratio)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5)
+}
+
+/// Schlick's approximation for a metal with reflectance `f0` at normal
+/// incidence, lit from `cos_theta`
+pub fn fresnel_schlick(f0: Rgb<f32>, cos_theta: f32) -> Rgb<f32> {
+    f0 + (Rgb::broadcast(1.0) - f0) * (1.0 - cos_theta).powi(5)
+}
+
+/// Axis from `point` toward the center of `light` and one minus the cosine of
+/// the half angle of the cone the light covers, or `None` if `point` is inside
+/// the light or too far away for the cone to be representable
+fn light_cone(light: &Sphere, point: Vec3<f32>, time: f32) -> Option<(Vec3<f32>, f32)> {
+    let to_center = light.center(time) - point;
+    let distance_squared = to_center.magnitude_squared();
+    let sin_squared = light.radius * light.radius / distance_squared;
+    if sin_squared >= 1.0 {
+        return None;
+    }
+    // 1 - sqrt(1 - x) rearranged so it doesn't cancel to 0 for tiny cones
+    let one_minus_cos = sin_squared / (1.0 + (1.0 - sin_squared).sqrt());
+    (one_minus_cos > 0.0).then(|| (to_center / distance_squared.sqrt(), one_minus_cos))
+}
+
+/// Density per solid angle of picking a direction from `point` among
+/// `world.lights` by choosing a light uniformly and then a direction
+/// uniformly in its cone
+fn light_pdf(world: &World, light: &Sphere, point: Vec3<f32>, time: f32) -> f32 {
+    light_cone(light, point, time).map_or(0.0, |(_, one_minus_cos)| {
+        1.0 / (std::f32::consts::TAU * one_minus_cos) / world.lights.len() as f32
+    })
+}
+
+/// Veach's power heuristic MIS weight for a sample drawn with density `pdf`
+/// given the other strategy has density `other_pdf`
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (pdf, other_pdf) = (pdf * pdf, other_pdf * other_pdf);
+    if pdf + other_pdf > 0.0 {
+        pdf / (pdf + other_pdf)
+    } else {
+        0.0
+    }
+}
+
+/// MIS weighted light reaching a Lambertian surface directly from one sampled
+/// light, not counting the surface's albedo. `world.lights` must not be empty
+fn direct_light(world: &World, hit_record: &HitRecord, time: f32, rng: &mut impl Rng) -> Rgb<f32> {
+    let none = Rgb::broadcast(0.0);
+    let light = &world.lights[rng.gen_range(0..world.lights.len())];
+    let point = hit_record.intersection_point;
+    let normal = hit_record.surface_normal;
+    let Some((axis, one_minus_cos)) = light_cone(light, point, time) else {
+        return none;
+    };
+
+    // Uniform direction within the cone
+    let cos_theta = 1.0 - rng.gen::<f32>() * one_minus_cos;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = std::f32::consts::TAU * rng.gen::<f32>();
+    let (tangent, bitangent) = orthonormal_basis(axis);
+    let direction =
+        (tangent * sin_theta * phi.cos() + bitangent * sin_theta * phi.sin() + axis * cos_theta)
+            .normalized();
+    let cos_surface = direction.dot(normal);
+    if cos_surface <= 0.0 {
+        return none;
+    }
+
+    // Shadow ray. Only counts if the first thing it hits is the chosen light
+    let shadow_ray = Ray::new(point, direction, time);
+    let Some((shadow_hit, material)) =
+        world.hit_with_media(shadow_ray, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY, rng)
+    else {
+        return none;
+    };
+    let MaterialType::Emissive(emitted) = material.mat_type else {
+        return none;
+    };
+    if !material.emits_toward(&shadow_hit) {
+        return none;
+    }
+    if !world
+        .light_hit_by(shadow_ray, &shadow_hit)
+        .is_some_and(|hit_light| std::ptr::eq(hit_light, light))
+    {
+        return none;
+    }
+
+    let radiance = material
+        .albedo
+        .value(shadow_hit.uv, shadow_hit.intersection_point)
+        * emitted;
+    let light_pdf = light_pdf(world, light, point, time);
+    let bsdf_pdf = cos_surface / std::f32::consts::PI;
+    // Lambertian BRDF is albedo / pi, the albedo is already in the throughput
+    radiance * bsdf_pdf * power_heuristic(light_pdf, bsdf_pdf) / light_pdf
+}
+
+/// Uniformly distributed unit vector in the cone around the unit `axis`
+/// whose half angle has cosine `1 - one_minus_cos`
+fn sample_cone(axis: Vec3<f32>, one_minus_cos: f32, rng: &mut impl Rng) -> Vec3<f32> {
+    let cos_theta = 1.0 - rng.gen::<f32>() * one_minus_cos;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = std::f32::consts::TAU * rng.gen::<f32>();
+    let (tangent, bitangent) = orthonormal_basis(axis);
+    (tangent * sin_theta * phi.cos() + bitangent * sin_theta * phi.sin() + axis * cos_theta)
+        .normalized()
+}
+
+/// MIS weighted background light, like the sun or the bright parts of an
+/// environment map, reaching a Lambertian surface. Doesn't count the
+/// surface's albedo. Like [`direct_light`] but the shadow ray has to escape
+fn direct_background_light(
+    world: &World,
+    hit_record: &HitRecord,
+    time: f32,
+    rng: &mut impl Rng,
+) -> Rgb<f32> {
+    let none = Rgb::broadcast(0.0);
+    let Some(direction) = world.background.sample(rng) else {
+        return none;
+    };
+    let cos_surface = direction.dot(hit_record.surface_normal);
+    if cos_surface <= 0.0 {
+        return none;
+    }
+    let shadow_ray = Ray::new(hit_record.intersection_point, direction, time);
+    if world
+        .hit_with_media(shadow_ray, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY, rng)
+        .is_some()
+    {
+        return none;
+    }
+    let background_pdf = world.background.pdf(direction);
+    if background_pdf <= 0.0 {
+        return none;
+    }
+    let bsdf_pdf = cos_surface / std::f32::consts::PI;
+    world.background.split_radiance(direction).1
+        * bsdf_pdf
+        * power_heuristic(background_pdf, bsdf_pdf)
+        / background_pdf
+}
+
+/// Radiance along `ray` and how many bounces the path took. `differential`
+/// is for camera rays, so textures at the first hit filter over the pixel
+pub fn ray_cast(
+    mut ray: Ray,
+    differential: Option<RayDifferential>,
+    world: &World,
+    settings: &RenderSettings,
+    rng: &mut impl rand::Rng,
+) -> (Rgb<f32>, usize) {
+    let max_diffuse_bounces = settings.max_diffuse_bounces.unwrap_or(settings.max_depth);
+    let max_specular_bounces = settings.max_specular_bounces.unwrap_or(settings.max_depth);
+    let nee = settings.nee && !world.lights.is_empty();
+    let background_nee = settings.nee && world.background.is_sampled();
+    // Throughput of the path so far, and light gathered along it
+    let mut color = Rgb::broadcast(1.0);
+    let mut radiance = Rgb::broadcast(0.0);
+    // Where and with what density the last diffuse bounce picked the current
+    // ray, for weighting against direct light sampling
+    let mut last_diffuse: Option<(Vec3<f32>, f32)> = None;
+    let mut diffuse_bounces = 0;
+    let mut specular_bounces = 0;
+    // Light that bounced the given number of times, or nothing if the
+    // settings leave it out
+    let gathered = |light: Rgb<f32>, bounces: usize| {
+        if settings.lighting.includes(bounces) {
+            light
+        } else {
+            Rgb::broadcast(0.0)
+        }
+    };
+    // Rays leaving glass are already moved off the surface, the rest skip
+    // hits too close to where they start instead
+    let mut t_min = SHADOW_ACNE_FUDGE_CONSTANT;
+    for depth in 0..settings.max_depth {
+        let previous_diffuse = last_diffuse.take();
+        let hit = world.hit_with_media(ray, t_min, f32::INFINITY, rng);
+        t_min = SHADOW_ACNE_FUDGE_CONSTANT;
+        if let Some((hit_record, hit_material)) = hit {
+            let emits = hit_material.emits_toward(&hit_record);
+            if emits {
+                radiance += gathered(color * hit_material.emitted, depth);
+            }
+            let footprint = match differential {
+                Some(differential) if depth == 0 && hit_material.albedo.uses_footprint() => {
+                    differential.footprint(&hit_record, hit_material, world)
+                }
+                _ => Footprint::default(),
+            };
+            let albedo = hit_material.albedo.filtered_value(
+                hit_record.uv,
+                hit_record.intersection_point,
+                footprint,
+            );
+            color *= match hit_material.mat_type {
+                MaterialType::FresnelMetal(_) => {
+                    let cos_theta = (-ray.direction.normalized())
+                        .dot(hit_record.surface_normal)
+                        .clamp(0.0, 1.0);
+                    fresnel_schlick(albedo, cos_theta)
+                }
+                _ => albedo,
+            };
+            match hit_material.mat_type {
+                MaterialType::Emissive(_) if !emits => {
+                    return (radiance, diffuse_bounces + specular_bounces)
+                }
+                MaterialType::Emissive(emitted) => {
+                    // Direct light sampling could have found this light too
+                    let weight = match (previous_diffuse, world.light_hit_by(ray, &hit_record)) {
+                        (Some((point, bsdf_pdf)), Some(light)) => {
+                            power_heuristic(bsdf_pdf, light_pdf(world, light, point, ray.time))
+                        }
+                        _ => 1.0,
+                    };
+                    return (
+                        radiance + gathered(color * emitted * weight, depth),
+                        diffuse_bounces + specular_bounces,
+                    );
+                }
+                MaterialType::Lambertian => {
+                    if diffuse_bounces == max_diffuse_bounces {
+                        break;
+                    }
+                    diffuse_bounces += 1;
+                    if nee {
+                        let light = direct_light(world, &hit_record, ray.time, rng);
+                        radiance += gathered(color * light, depth + 1);
+                    }
+                    if background_nee {
+                        let light = direct_background_light(world, &hit_record, ray.time, rng);
+                        radiance += gathered(color * light, depth + 1);
+                    }
+                    let direction = rng.rand_cosine_direction(hit_record.surface_normal);
+                    if nee || background_nee {
+                        let bsdf_pdf = direction.dot(hit_record.surface_normal).max(0.0)
+                            / std::f32::consts::PI;
+                        last_diffuse = Some((hit_record.intersection_point, bsdf_pdf));
+                    }
+                    ray = Ray::new(hit_record.intersection_point, direction, ray.time);
+                }
+                MaterialType::Isotropic => {
+                    if diffuse_bounces == max_diffuse_bounces {
+                        break;
+                    }
+                    diffuse_bounces += 1;
+                    ray = Ray::new(
+                        hit_record.intersection_point,
+                        rng.rand_unit_vec3(),
+                        ray.time,
+                    );
+                }
+                MaterialType::Metal(fuzz) | MaterialType::FresnelMetal(fuzz) => {
+                    if specular_bounces == max_specular_bounces {
+                        break;
+                    }
+                    specular_bounces += 1;
+                    let fuzz = hit_material.fuzz_at(fuzz, &hit_record);
+                    let normal = hit_record.surface_normal;
+                    if settings.legacy_metal {
+                        let reflected = reflected(ray.direction, normal)
+                            + fuzz * rng.rand_vec3_in_unit_sphere();
+                        if reflected.dot(normal) > 0.0 {
+                            ray = Ray::new(
+                                hit_record.intersection_point,
+                                reflected.normalized(),
+                                ray.time,
+                            )
+                        } else {
+                            return (radiance, diffuse_bounces + specular_bounces);
+                        }
+                    } else {
+                        // Squaring makes roughness look closer to linear, and
+                        // keeps low fuzz close to how the old perturbation looked
+                        let alpha = fuzz * fuzz;
+                        let incoming = ray.direction.normalized();
+                        if incoming.dot(normal) >= 0.0 {
+                            return (radiance, diffuse_bounces + specular_bounces);
+                        }
+                        // How much a single bounce off the microfacets
+                        // reflects, unless that's all the settings ask for
+                        let (single, average) = if settings.single_scatter_metal {
+                            (1.0, 1.0)
+                        } else {
+                            ggx_albedo(-incoming.dot(normal), alpha)
+                        };
+                        // Light lost to single scattering leaves through the
+                        // multiple scattering lobe instead, so the throughput
+                        // of either lobe averages 1 before Fresnel
+                        if !settings.single_scatter_metal && rng.gen::<f32>() >= single {
+                            let direction = rng.rand_cosine_direction(normal);
+                            let (outgoing, _) = ggx_albedo(direction.dot(normal), alpha);
+                            // Light bouncing between microfacets picks up
+                            // Fresnel every time, approximated with the
+                            // albedo as the average reflectance
+                            let tint = albedo.map(|f| average / (1.0 - f * (1.0 - average)));
+                            color *= tint * (1.0 - outgoing) / (1.0 - average).max(1e-6);
+                            ray = Ray::new(hit_record.intersection_point, direction, ray.time);
+                        } else {
+                            let microfacet = rng.rand_ggx_visible_normal(normal, -incoming, alpha);
+                            let direction = reflected(incoming, microfacet);
+                            let cos_out = direction.dot(normal);
+                            if cos_out <= 0.0 {
+                                return (radiance, diffuse_bounces + specular_bounces);
+                            }
+                            // Sampling visible normals cancels everything in
+                            // the BRDF over the pdf except the shadowing of the
+                            // outgoing direction. Fresnel is in the albedo above
+                            color *= smith_ggx_g1(cos_out, alpha) / single;
+                            ray = Ray::new(hit_record.intersection_point, direction, ray.time);
+                        }
+                    }
+                }
+                MaterialType::Dielectric(ir) => {
+                    if specular_bounces == max_specular_bounces {
+                        break;
+                    }
+                    specular_bounces += 1;
+                    let ir = hit_material.ior_at(ir, &hit_record);
+                    let front_face = ray.direction.dot(hit_record.surface_normal) < 0.0;
+                    let (normal, eta_ratio) = if front_face {
+                        (hit_record.surface_normal, 1.0 / ir)
+                    } else {
+                        (-hit_record.surface_normal, ir)
+                    };
+                    let cos_theta = (-ray.direction).dot(normal).min(1.0);
+                    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                    let (direction, offset) = if eta_ratio * sin_theta > 1.0
+                        || schlick_reflectance(cos_theta, eta_ratio) > rng.gen::<f32>()
+                    {
+                        (
+                            reflected(ray.direction, normal),
+                            normal * settings.reflection_offset,
+                        )
+                    } else {
+                        (
+                            refracted(ray.direction, normal, eta_ratio),
+                            -normal * settings.transmission_offset,
+                        )
+                    };
+                    let point = hit_record.intersection_point;
+                    let scale = 1.0 + point.map(f32::abs).reduce_partial_max();
+                    ray = Ray::new(point + offset * scale, direction.normalized(), ray.time);
+                    t_min = 0.0;
+                }
+            }
+
+            // Russian roulette. Dim paths are likely to be killed, and the
+            // survivors are boosted by the same factor to keep the estimate
+            // unbiased
+            if depth + 1 >= settings.rr_min_bounces {
+                let survival = color.reduce_partial_max().min(1.0);
+                if rng.gen::<f32>() >= survival {
+                    return (radiance, diffuse_bounces + specular_bounces);
+                }
+                color /= survival;
+            }
+        } else {
+            if depth == 0 && settings.env_lighting_only {
+                return (radiance, 0);
+            }
+            let background = match previous_diffuse {
+                // Sampling the background could have found part of it too
+                Some((_, bsdf_pdf)) if background_nee => {
+                    let (unsampled, sampled) = world.background.split_radiance(ray.direction);
+                    let background_pdf = world.background.pdf(ray.direction);
+                    unsampled + sampled * power_heuristic(bsdf_pdf, background_pdf)
+                }
+                _ => world.background.color(ray.direction),
+            };
+            return (
+                radiance + gathered(color * background, depth),
+                diffuse_bounces + specular_bounces,
+            );
+        }
+    }
+    // The estimate stands for light bouncing at least once more at the last
+    // vertex
+    let bounces = diffuse_bounces + specular_bounces;
+    if settings.truncation_estimate {
+        radiance += gathered(color * world.background.average(), bounces + 1);
+    }
+    (radiance, bounces)
+}
+
+/// Thin lens camera. `get_ray` maps `s` left to right and `t` top to bottom
+/// across the viewport, both in [0,1]
+pub struct Camera {
+    origin: Vec3<f32>,
+    upper_left_corner: Vec3<f32>,
+    horizontal: Vec3<f32>,
+    vertical: Vec3<f32>,
+    u: Vec3<f32>,
+    v: Vec3<f32>,
+    /// Direction orthographic rays travel in
+    forward: Vec3<f32>,
+    projection: Projection,
+    /// Viewport width over height, for mapping fisheye images
+    aspect_ratio: f32,
+    lens_radius: f32,
+    /// Corners of the polygonal lens opening, or 0 for a round one
+    aperture_blades: u32,
+    /// Spread rays over the shutter interval instead of casting them all at
+    /// the moment it opens
+    motion_blur: bool,
+    /// Times within [0,1] the shutter opens and closes at
+    shutter_open: f32,
+    shutter_close: f32,
+}
+
+/// How the camera maps the viewport to rays
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Projection {
+    /// Rays fan out from the eye
+    #[default]
+    Perspective,
+    /// Parallel rays from a viewport `scale` units tall centered on the eye.
+    /// There is no foreshortening, and `vfov` and the lens are ignored
+    Orthographic { scale: f32 },
+    /// Equidistant fisheye. Angle from the view direction grows in step
+    /// with distance from the image center, reaching half of `fov` degrees
+    /// at the top and bottom edges. `vfov` and the lens are ignored
+    Fisheye { fov: f32 },
+    /// Longitude across and latitude down the whole image, covering every
+    /// direction with the view direction in the middle. Left and right
+    /// edges meet behind the camera. `vfov` and the lens are ignored
+    Equirectangular,
+}
+
+impl FromStr for Projection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "perspective" => Ok(Projection::Perspective),
+            // Frames the same area at distance 1 as the default 90 degree
+            // perspective view
+            "ortho" => Ok(Projection::Orthographic { scale: 2.0 }),
+            "fisheye" => Ok(Projection::Fisheye { fov: 180.0 }),
+            "equirect" => Ok(Projection::Equirectangular),
+            _ => Err("expected one of perspective, ortho, fisheye, equirect".to_string()),
+        }
+    }
+}
+
+/// Unit vector pointing right for a camera looking along `-w` with `vup`
+/// roughly up. When `vup` is parallel to the view, as for a camera looking
+/// straight down, the axis least aligned with the view stands in for it
+fn camera_right(w: Vec3<f32>, vup: Vec3<f32>) -> Vec3<f32> {
+    let right = vup.cross(w);
+    if right.magnitude_squared() > 1e-12 * vup.magnitude_squared() {
+        return right.normalized();
+    }
+    let alternate = if w.z.abs() < 0.9 {
+        Vec3::unit_z()
+    } else {
+        Vec3::unit_y()
+    };
+    alternate.cross(w).normalized()
+}
+
+impl Camera {
+    /// `vfov` is the vertical field of view in degrees. An `aperture` of 0
+    /// gives a pinhole camera where everything is in focus
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        lookfrom: Vec3<f32>,
+        lookat: Vec3<f32>,
+        vup: Vec3<f32>,
+        vfov: f32,
+        aspect_ratio: f32,
+        aperture: f32,
+        focus_dist: f32,
+        projection: Projection,
+    ) -> Self {
+        let w = (lookfrom - lookat).normalized();
+        let u = camera_right(w, vup);
+        let v = w.cross(u);
+        let origin = lookfrom;
+
+        let (horizontal, vertical, upper_left_corner) = match projection {
+            // Panoramic rays are worked out from `u`, `v` and `forward`, the
+            // viewport only matters to code that doesn't know about them
+            Projection::Perspective | Projection::Fisheye { .. } | Projection::Equirectangular => {
+                let theta = vfov.to_radians();
+                let viewport_height = 2.0 * (theta / 2.0).tan();
+                let viewport_width = aspect_ratio * viewport_height;
+                let horizontal = focus_dist * viewport_width * u;
+                let vertical = -focus_dist * viewport_height * v;
+                let upper_left_corner = origin - horizontal / 2.0 - vertical / 2.0 - focus_dist * w;
+                (horizontal, vertical, upper_left_corner)
+            }
+            // The viewport sits on the eye and rays leave it straight ahead
+            Projection::Orthographic { scale } => {
+                let horizontal = scale * aspect_ratio * u;
+                let vertical = -scale * v;
+                let upper_left_corner = origin - horizontal / 2.0 - vertical / 2.0;
+                (horizontal, vertical, upper_left_corner)
+            }
+        };
+
+        Self {
+            origin,
+            upper_left_corner,
+            horizontal,
+            vertical,
+            u,
+            v,
+            forward: -w,
+            projection,
+            aspect_ratio,
+            lens_radius: aperture / 2.0,
+            aperture_blades: 0,
+            motion_blur: false,
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+        }
+    }
+
+    pub fn with_motion_blur(self, motion_blur: bool) -> Self {
+        Self {
+            motion_blur,
+            ..self
+        }
+    }
+
+    /// Shape the lens opening as a regular polygon with `blades` corners, so
+    /// out of focus highlights come out polygonal. Fewer than 3 keeps it
+    /// round
+    pub fn with_aperture_blades(self, blades: u32) -> Self {
+        Self {
+            aperture_blades: blades,
+            ..self
+        }
+    }
+
+    /// Only expose between `open` and `close`, both in [0,1]. Shorter
+    /// intervals give shorter motion blur
+    pub fn with_shutter(self, open: f32, close: f32) -> Self {
+        Self {
+            shutter_open: open,
+            shutter_close: close,
+            ..self
+        }
+    }
+
+    /// Time to cast a ray at, spread over the shutter interval if anything
+    /// moves
+    fn ray_time(&self, rng: &mut impl Rng) -> f32 {
+        if self.motion_blur {
+            self.shutter_open + (self.shutter_close - self.shutter_open) * rng.gen::<f32>()
+        } else {
+            self.shutter_open
+        }
+    }
+
+    /// Direction through (`s`, `t`) for the panoramic projections
+    fn panoramic_direction(&self, s: f32, t: f32) -> Vec3<f32> {
+        match self.projection {
+            Projection::Fisheye { fov } => {
+                let (x, y) = ((2.0 * s - 1.0) * self.aspect_ratio, 1.0 - 2.0 * t);
+                let r = x.hypot(y);
+                let theta = (r * fov.to_radians() / 2.0).min(std::f32::consts::PI);
+                let phi = y.atan2(x);
+                (theta.sin() * (phi.cos() * self.u + phi.sin() * self.v)
+                    + theta.cos() * self.forward)
+                    .normalized()
+            }
+            Projection::Equirectangular => {
+                let longitude = (s - 0.5) * std::f32::consts::TAU;
+                let latitude = (0.5 - t) * std::f32::consts::PI;
+                (latitude.cos() * (longitude.sin() * self.u + longitude.cos() * self.forward)
+                    + latitude.sin() * self.v)
+                    .normalized()
+            }
+            _ => unreachable!("only panoramic projections"),
+        }
+    }
+
+    pub fn get_ray(&self, s: f32, t: f32, rng: &mut impl Rng) -> Ray {
+        self.get_ray_with_differential(s, t, 0.0, 0.0, rng).0
+    }
+
+    /// Like [`Camera::get_ray`], along with the rays `ds` and `dt` further
+    /// across the viewport, from the same point on the lens at the same time
+    pub fn get_ray_with_differential(
+        &self,
+        s: f32,
+        t: f32,
+        ds: f32,
+        dt: f32,
+        rng: &mut impl Rng,
+    ) -> (Ray, RayDifferential) {
+        if let Projection::Orthographic { .. } = self.projection {
+            let origin = self.upper_left_corner + s * self.horizontal + t * self.vertical;
+            let ray = Ray::new(origin, self.forward, self.ray_time(rng));
+            let differential = RayDifferential {
+                dx: Ray::new(origin + ds * self.horizontal, ray.direction, ray.time),
+                dy: Ray::new(origin + dt * self.vertical, ray.direction, ray.time),
+            };
+            return (ray, differential);
+        }
+        if let Projection::Fisheye { .. } | Projection::Equirectangular = self.projection {
+            let time = self.ray_time(rng);
+            let ray = Ray::new(self.origin, self.panoramic_direction(s, t), time);
+            let differential = RayDifferential {
+                dx: Ray::new(self.origin, self.panoramic_direction(s + ds, t), time),
+                dy: Ray::new(self.origin, self.panoramic_direction(s, t + dt), time),
+            };
+            return (ray, differential);
+        }
+        // Leave the rng alone for pinhole cameras so they sample exactly as
+        // they did before the lens existed
+        let offset = if self.lens_radius > 0.0 {
+            let rd = self.lens_radius
+                * match self.aperture_blades {
+                    0..=2 => rng.rand_in_unit_disk(),
+                    blades => rng.rand_in_regular_polygon(blades),
+                };
+            self.u * rd.x + self.v * rd.y
+        } else {
+            Vec3::zero()
+        };
+        let origin = self.origin + offset;
+        let through = |s: f32, t: f32| {
+            (self.upper_left_corner + s * self.horizontal + t * self.vertical - origin).normalized()
+        };
+        let direction = through(s, t);
+        // Only a zero or non-finite viewport could break this, which the
+        // pixel loop keeps away from
+        debug_assert!(
+            direction.is_normalized(),
+            "camera ray {direction:?} isn't unit"
+        );
+        // Like the lens, only touch the rng when something actually moves
+        let time = self.ray_time(rng);
+        let differential = RayDifferential {
+            dx: Ray::new(origin, through(s + ds, t), time),
+            dy: Ray::new(origin, through(s, t + dt), time),
+        };
+        (Ray::new(origin, direction, time), differential)
+    }
+}
+
+/// SplitMix64 finalizer. Scrambles nearby inputs into unrelated outputs
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Seed for one pixel's rng. Hashing keeps neighbouring pixels (and base
+/// seeds that differ by one) from getting correlated streams
+fn pixel_seed(seed: u64, pixel_index: u64) -> u64 {
+    splitmix64(seed ^ splitmix64(pixel_index))
+}
+
+/// `n` such that `n * n == value`, if there is one
+fn perfect_square_root(value: usize) -> Option<usize> {
+    let root = (value as f64).sqrt().round() as usize;
+    (root * root == value).then_some(root)
+}
+
+/// Rectangle of finished linear pixels, stored row by row
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub width: usize,
+    pub pixels: Vec<Rgb<f32>>,
+}
+
+/// Render one pass over the image, returning the linear color of every pixel
+/// and the total number of samples taken. The output is a pure function of
+/// the inputs and `seed`. Returns `None` if `cancel` is set before the pass
+/// finishes
+pub fn draw_linear(
+    draw_size: PhysicalSize<u32>,
+    world: &World,
+    camera: &Camera,
+    settings: &RenderSettings,
+    seed: u64,
+    cancel: &AtomicBool,
+) -> Option<(Vec<Rgb<f32>>, u64)> {
+    draw_linear_with_progress(draw_size, world, camera, settings, seed, cancel, &|_| {})
+}
+
+/// `draw_linear` that hands every tile to `on_tile` as soon as it is
+/// finished, from whichever thread rendered it. Supersampled and splatted
+/// renders only finish all at once, so they report no tiles
+pub fn draw_linear_with_progress(
+    draw_size: PhysicalSize<u32>,
+    world: &World,
+    camera: &Camera,
+    settings: &RenderSettings,
+    seed: u64,
+    cancel: &AtomicBool,
+    on_tile: &(dyn Fn(&Tile) + Sync),
+) -> Option<(Vec<Rgb<f32>>, u64)> {
+    if settings.ssaa > 1 {
+        return draw_supersampled(draw_size, world, camera, settings, seed, cancel);
+    }
+    if settings.filter_radius > 0.5 && settings.debug.is_none() {
+        return draw_splatted(draw_size, world, camera, settings, seed, cancel);
+    }
+    let (width, height) = (draw_size.width as usize, draw_size.height as usize);
+    let tile_size = settings.tile_size;
+    let tiles_x = width.div_ceil(tile_size);
+    let tiles_y = height.div_ceil(tile_size);
+
+    // Each tile is rendered row by row into its own contiguous buffer and
+    // copied into place afterwards
+    let tiles: Vec<_> = (0..tiles_x * tiles_y)
+        .into_par_iter()
+        .map(|tile| {
+            let x0 = tile % tiles_x * tile_size;
+            let y0 = tile / tiles_x * tile_size;
+            let x1 = (x0 + tile_size).min(width);
+            let y1 = (y0 + tile_size).min(height);
+            let mut pixels = Vec::with_capacity((x1 - x0) * (y1 - y0));
+            let mut samples_taken = 0;
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    if !settings.renders_pixel(x, y, width) {
+                        pixels.push(Rgb::broadcast(0.0));
+                        continue;
+                    }
+                    let (color, samples) = draw_pixel(
+                        x,
+                        y,
+                        draw_size,
+                        world,
+                        camera,
+                        settings,
+                        seed,
+                        cancel,
+                        |_, _, _| {},
+                    );
+                    pixels.push(color);
+                    samples_taken += samples as u64;
+                }
+            }
+            let tile = Tile {
+                x0,
+                y0,
+                width: x1 - x0,
+                pixels,
+            };
+            on_tile(&tile);
+            (tile, samples_taken)
+        })
+        .collect();
+
+    if cancel.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let mut buffer = vec![Rgb::broadcast(0.0); width * height];
+    let mut total_samples = 0;
+    for (tile, samples_taken) in tiles {
+        for (row, row_pixels) in tile.pixels.chunks(tile.width).enumerate() {
+            let start = (tile.y0 + row) * width + tile.x0;
+            buffer[start..start + tile.width].copy_from_slice(row_pixels);
+        }
+        total_samples += samples_taken;
+    }
+    Some((buffer, total_samples))
+}
+
+/// `draw_linear` at `settings.ssaa` times the width and height, box filtered
+/// back down to `draw_size`
+fn draw_supersampled(
+    draw_size: PhysicalSize<u32>,
+    world: &World,
+    camera: &Camera,
+    settings: &RenderSettings,
+    seed: u64,
+    cancel: &AtomicBool,
+) -> Option<(Vec<Rgb<f32>>, u64)> {
+    let factor = settings.ssaa as usize;
+    let large_size = PhysicalSize::new(
+        draw_size.width * settings.ssaa,
+        draw_size.height * settings.ssaa,
+    );
+    // Scaling the tiles along with the image keeps tile ranges covering the
+    // same pixels
+    let large_settings = RenderSettings {
+        ssaa: 1,
+        crop: settings.crop.map(|crop| crop.scaled(factor)),
+        tile_size: settings.tile_size * factor,
+        ..*settings
+    };
+    let (large, total_samples) =
+        draw_linear(large_size, world, camera, &large_settings, seed, cancel)?;
+
+    let (width, height) = (draw_size.width as usize, draw_size.height as usize);
+    let large_width = width * factor;
+    let mut buffer = vec![Rgb::broadcast(0.0); width * height];
+    for (i, pixel) in buffer.iter_mut().enumerate() {
+        let (x, y) = (i % width * factor, i / width * factor);
+        for dy in 0..factor {
+            let row = (y + dy) * large_width + x;
+            for color in &large[row..row + factor] {
+                *pixel += *color;
+            }
+        }
+        *pixel /= (factor * factor) as f32;
+    }
+    Some((buffer, total_samples))
+}
+
+/// `draw_linear` with every sample spread over the pixels within
+/// `settings.filter_radius` by a separable tent, then normalized by the summed
+/// weights. Tiles splat into their own padded buffers which are added
+/// together afterwards, so the result still doesn't depend on tile order
+fn draw_splatted(
+    draw_size: PhysicalSize<u32>,
+    world: &World,
+    camera: &Camera,
+    settings: &RenderSettings,
+    seed: u64,
+    cancel: &AtomicBool,
+) -> Option<(Vec<Rgb<f32>>, u64)> {
+    let (width, height) = (draw_size.width as usize, draw_size.height as usize);
+    let radius = settings.filter_radius;
+    let pad = (radius - 0.5).ceil() as usize;
+    let tile_size = settings.tile_size;
+    let tiles_x = width.div_ceil(tile_size);
+    let tiles_y = height.div_ceil(tile_size);
+
+    let tiles: Vec<_> = (0..tiles_x * tiles_y)
+        .into_par_iter()
+        .map(|tile| {
+            let x0 = tile % tiles_x * tile_size;
+            let y0 = tile / tiles_x * tile_size;
+            let x1 = (x0 + tile_size).min(width);
+            let y1 = (y0 + tile_size).min(height);
+            // The region this tile's samples can reach, clipped to the image
+            let (px0, py0) = (x0.saturating_sub(pad), y0.saturating_sub(pad));
+            let (px1, py1) = ((x1 + pad).min(width), (y1 + pad).min(height));
+            let padded_width = px1 - px0;
+            let mut sums = vec![Rgb::broadcast(0.0); padded_width * (py1 - py0)];
+            let mut weights = vec![0.0; sums.len()];
+            let mut splat = |sx: f32, sy: f32, color: Rgb<f32>| {
+                let tent = |d: f32| (1.0 - d.abs() / radius).max(0.0);
+                let from_y = ((sy - radius - 0.5).floor().max(0.0) as usize).max(py0);
+                let to_y = ((sy + radius - 0.5).ceil().max(0.0) as usize).min(py1 - 1);
+                let from_x = ((sx - radius - 0.5).floor().max(0.0) as usize).max(px0);
+                let to_x = ((sx + radius - 0.5).ceil().max(0.0) as usize).min(px1 - 1);
+                for py in from_y..=to_y {
+                    let weight_y = tent(sy - (py as f32 + 0.5));
+                    for px in from_x..=to_x {
+                        let weight = weight_y * tent(sx - (px as f32 + 0.5));
+                        let i = (py - py0) * padded_width + px - px0;
+                        sums[i] += color * weight;
+                        weights[i] += weight;
+                    }
+                }
+            };
+            let mut samples_taken = 0;
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    // Pixels just outside the rendered region still spread
+                    // samples into it, so sample them too to match a full
+                    // render
+                    if !settings.reaches_rendered_pixel(x, y, draw_size, pad) {
+                        continue;
+                    }
+                    let (_, samples) = draw_pixel(
+                        x, y, draw_size, world, camera, settings, seed, cancel, &mut splat,
+                    );
+                    samples_taken += samples as u64;
+                }
+            }
+            (px0, py0, padded_width, sums, weights, samples_taken)
+        })
+        .collect();
+
+    if cancel.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let mut sums = vec![Rgb::broadcast(0.0); width * height];
+    let mut weights = vec![0.0; width * height];
+    let mut total_samples = 0;
+    for (px0, py0, padded_width, tile_sums, tile_weights, samples_taken) in tiles {
+        for (i, (sum, weight)) in tile_sums.into_iter().zip(tile_weights).enumerate() {
+            let index = (py0 + i / padded_width) * width + px0 + i % padded_width;
+            sums[index] += sum;
+            weights[index] += weight;
+        }
+        total_samples += samples_taken;
+    }
+    // Samples near the edge of a crop spill out of it, keep those pixels black
+    let buffer = sums
+        .into_iter()
+        .zip(weights)
+        .enumerate()
+        .map(|(i, (sum, weight))| {
+            if weight > 0.0 && settings.renders_pixel(i % width, i / width, width) {
+                sum / weight
+            } else {
+                Rgb::broadcast(0.0)
+            }
+        })
+        .collect();
+    Some((buffer, total_samples))
+}
+
+/// Linear average of the samples for the pixel at (`x`, `y`) and how many
+/// were taken. Each sample is also handed to `splat` with its position in
+/// pixels. The rng is seeded from the pixel's index so the result doesn't
+/// depend on the order pixels are rendered in
+#[allow(clippy::too_many_arguments)]
+fn draw_pixel(
+    x: usize,
+    y: usize,
+    draw_size: PhysicalSize<u32>,
+    world: &World,
+    camera: &Camera,
+    settings: &RenderSettings,
+    seed: u64,
+    cancel: &AtomicBool,
+    mut splat: impl FnMut(f32, f32, Rgb<f32>),
+) -> (Rgb<f32>, usize) {
+    let (width, height) = (draw_size.width as usize, draw_size.height as usize);
+    // What pixel coordinates are divided by to get the viewport position,
+    // kept away from zero so single pixel rows and columns stay finite
+    let (u_span, v_span) = ((width.max(2) - 1) as f32, (height.max(2) - 1) as f32);
+    let mut rng = StdRng::seed_from_u64(pixel_seed(seed, (y * width + x) as u64));
+    if let Some(view) = settings.debug.filter(|view| view.single_ray()) {
+        let v = (y as f32 + 0.5) / v_span;
+        let u = (x as f32 + 0.5) / u_span;
+        return (view.cast(camera.get_ray(u, v, &mut rng), world), 1);
+    }
+    let (sample_count, strata) = match settings.tolerance {
+        // How many samples an adaptive pixel takes isn't known up front, so
+        // there is no grid to stratify over
+        Some(_) => (settings.max_samples, None),
+        None => (settings.samples, perfect_square_root(settings.samples)),
+    };
+    // Welford's running mean and sum of squared deviations per channel
+    let mut mean = Rgb::broadcast(0.0);
+    let mut m2 = Rgb::broadcast(0.0);
+    let mut taken = 0;
+    let antithetic = settings.antithetic && sample_count % 2 == 0;
+    let mut previous_jitter = (0.0, 0.0);
+    // Shifting the texture by the seed gives every pass a different offset
+    // while keeping neighbouring pixels blue noise apart
+    let blue_noise_offset = match settings.sampler {
+        Sampler::Random => None,
+        Sampler::BlueNoise => {
+            let shift = splitmix64(seed) as usize;
+            let tx = (x + shift) % BLUE_NOISE_SIZE;
+            let ty = (y + (shift >> 32)) % BLUE_NOISE_SIZE;
+            Some(blue_noise()[ty * BLUE_NOISE_SIZE + tx])
+        }
+    };
+    // Fraction of the pixel's corners that hit something, and a ray through
+    // one that did, when only some do
+    let coverage = matches!(settings.debug, Some(DebugView::Coverage));
+    // Coverage already is the fraction of samples that hit
+    let edge = if settings.analytic_aa && !coverage {
+        // A copy so the samples stay the same as without the corners
+        let mut corner_rng = rng.clone();
+        let corners = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(dx, dy)| {
+            let u = (x + dx) as f32 / u_span;
+            let v = (y + dy) as f32 / v_span;
+            let ray = camera.get_ray(u, v, &mut corner_rng);
+            world
+                .hit(ray, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY)
+                .map(|_| ray)
+        });
+        let covered = corners.iter().flatten().count();
+        corners
+            .into_iter()
+            .flatten()
+            .next()
+            .filter(|_| covered < corners.len())
+            .map(|ray| (covered as f32 / corners.len() as f32, ray))
+    } else {
+        None
+    };
+    for sample in 0..sample_count {
+        if cancel.load(Ordering::Relaxed) {
+            break;
+        }
+        // Jitter within one cell of an n x n grid over the pixel when the
+        // sample count allows it, otherwise anywhere in the pixel
+        let (jitter_x, jitter_y) = match (blue_noise_offset, strata) {
+            _ if antithetic && sample % 2 == 1 => {
+                (1.0 - previous_jitter.0, 1.0 - previous_jitter.1)
+            }
+            (Some(offset), _) => {
+                let jitter_x = (radical_inverse(sample, 2) + offset.x).fract();
+                let jitter_y = (radical_inverse(sample, 3) + offset.y).fract();
+                (jitter_x, jitter_y)
+            }
+            (None, Some(n)) => {
+                let (cell_x, cell_y) = (sample % n, sample / n);
+                let jitter_y = (cell_y as f32 + rng.gen::<f32>()) / n as f32;
+                let jitter_x = (cell_x as f32 + rng.gen::<f32>()) / n as f32;
+                (jitter_x, jitter_y)
+            }
+            (None, None) => {
+                let jitter_y = rng.gen::<f32>();
+                let jitter_x = rng.gen::<f32>();
+                (jitter_x, jitter_y)
+            }
+        };
+        previous_jitter = (jitter_x, jitter_y);
+        let (jitter_x, jitter_y) = (
+            settings.filter.remap(jitter_x),
+            settings.filter.remap(jitter_y),
+        );
+        let v = (y as f32 + jitter_y) / v_span;
+        let u = (x as f32 + jitter_x) / u_span;
+        let (ray, differential) =
+            camera.get_ray_with_differential(u, v, 1.0 / u_span, 1.0 / v_span, &mut rng);
+
+        let (mut color, bounces) = if coverage {
+            let hit =
+                world.hit_with_media(ray, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY, &mut rng);
+            (Rgb::broadcast(if hit.is_some() { 1.0 } else { 0.0 }), 0)
+        } else {
+            ray_cast(ray, Some(differential), world, settings, &mut rng)
+        };
+        if let Some((coverage, covered_ray)) = edge {
+            // Whichever of the object and background this sample missed is
+            // filled in from a corner ray or the background
+            let (surface, background) =
+                match world.hit(ray, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY) {
+                    Some(_) if settings.env_lighting_only => (color, Rgb::broadcast(0.0)),
+                    Some(_) => (color, world.background.color(ray.direction)),
+                    None => (
+                        ray_cast(covered_ray, None, world, settings, &mut rng).0,
+                        color,
+                    ),
+                };
+            color = Lerp::lerp(background, surface, coverage);
+        }
+        if let Some(max_luminance) = settings.clamp {
+            let luminance = luminance(color);
+            if luminance > max_luminance {
+                color *= max_luminance / luminance;
+            }
+        }
+        // Average the bounce count instead, it is mapped to color at the end
+        if let Some(DebugView::Bounces) = settings.debug {
+            color = Rgb::broadcast(bounces as f32);
+        }
+        splat(x as f32 + jitter_x, y as f32 + jitter_y, color);
+        taken += 1;
+        let delta = color - mean;
+        mean += delta / taken as f32;
+        m2 += delta * (color - mean);
+
+        if let Some(tolerance) = settings.tolerance {
+            if taken >= ADAPTIVE_MIN_SAMPLES {
+                let variance = m2 / (taken - 1) as f32;
+                let interval = variance.map(|v| 2.0 * 1.96 * (v / taken as f32).sqrt());
+                if interval.reduce_partial_max() < tolerance {
+                    break;
+                }
+            }
+        }
+    }
+    if let Some(DebugView::Bounces) = settings.debug {
+        return (heat(mean.r, BOUNCE_HEAT_HALF), taken);
+    }
+    (mean, taken)
+}
+
+/// How far apart two luminances may be, relative to the brighter one, before
+/// the denoiser stops blending them. Relative so that dim and bright regions
+/// get smoothed alike
+const DENOISE_RANGE_SIGMA: f32 = 0.3;
+
+/// Edge preserving blur of a linear image: a bilateral filter weighting
+/// neighbours within about `2 * sigma` pixels by both their distance and how
+/// close their luminance is to the pixel being filtered
+pub fn denoise(colors: &[Rgb<f32>], size: PhysicalSize<u32>, sigma: f32) -> Vec<Rgb<f32>> {
+    let (width, height) = (size.width as usize, size.height as usize);
+    let radius = (2.0 * sigma).ceil() as usize;
+    (0..width * height)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            let center = luminance(colors[i]);
+            let mut sum = Rgb::broadcast(0.0);
+            let mut total_weight = 0.0;
+            for ny in y.saturating_sub(radius)..(y + radius + 1).min(height) {
+                for nx in x.saturating_sub(radius)..(x + radius + 1).min(width) {
+                    let color = colors[ny * width + nx];
+                    let (dx, dy) = (nx as f32 - x as f32, ny as f32 - y as f32);
+                    let spatial = (dx * dx + dy * dy) / (2.0 * sigma * sigma);
+                    let neighbour = luminance(color);
+                    // Offset keeps black pixels from rejecting everything
+                    let scale = DENOISE_RANGE_SIGMA * (center.max(neighbour) + 1e-2);
+                    let difference = (center - neighbour) / scale;
+                    let weight = (-spatial - difference * difference / 2.0).exp();
+                    sum += color * weight;
+                    total_weight += weight;
+                }
+            }
+            sum / total_weight
+        })
+        .collect()
+}
+
+/// Luminance auto exposure maps the median of an image to
+const MIDDLE_GRAY: f32 = 0.18;
+
+/// Bins of [`luminance_histogram`], each covering an eighth of a stop
+const HISTOGRAM_BINS: usize = 256;
+/// Luminance at the bottom of the first bin of [`luminance_histogram`], as a
+/// power of two. Darker pixels count towards it
+const HISTOGRAM_MIN_LOG2: f32 = -20.0;
+const HISTOGRAM_BINS_PER_STOP: f32 = 8.0;
+
+/// How many pixels of `colors` fall into each bin of log2 luminance, from
+/// 2^-20 up in eighths of a stop. Black and non-finite pixels aren't
+/// counted, so a black background or unconverged noise doesn't drag the
+/// median down
+pub fn luminance_histogram(colors: &[Rgb<f32>]) -> Vec<u64> {
+    colors
+        .par_chunks(4096)
+        .fold(
+            || vec![0; HISTOGRAM_BINS],
+            |mut histogram, chunk| {
+                for &color in chunk.iter().filter(|&&color| is_finite_color(color)) {
+                    let luminance = luminance(color);
+                    if luminance <= 0.0 {
+                        continue;
+                    }
+                    let bin = (luminance.log2() - HISTOGRAM_MIN_LOG2) * HISTOGRAM_BINS_PER_STOP;
+                    // Negative bins saturate to 0
+                    histogram[(bin as usize).min(HISTOGRAM_BINS - 1)] += 1;
+                }
+                histogram
+            },
+        )
+        .reduce(
+            || vec![0; HISTOGRAM_BINS],
+            |mut a, b| {
+                for (a, b) in a.iter_mut().zip(b) {
+                    *a += b;
+                }
+                a
+            },
+        )
+}
+
+/// Luminance at the middle of the bin holding the median of `histogram`,
+/// `None` if it is empty
+pub fn median_luminance(histogram: &[u64]) -> Option<f32> {
+    let total: u64 = histogram.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let mut seen = 0;
+    let bin = histogram.iter().position(|&count| {
+        seen += count;
+        seen * 2 >= total
+    })?;
+    Some((HISTOGRAM_MIN_LOG2 + (bin as f32 + 0.5) / HISTOGRAM_BINS_PER_STOP).exp2())
+}
+
+/// Rec. 709 luminance of a linear color
+pub fn luminance(color: Rgb<f32>) -> f32 {
+    0.2126 * color.r + 0.7152 * color.g + 0.0722 * color.b
+}
+
+/// sRGB encoding of one linear channel in [0,1]
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Tone map and gamma correct a linear color and pack it for display
+pub fn to_display(color: Rgb<f32>, settings: &RenderSettings) -> u32 {
+    // Checked up front because the sRGB path clamps infinities away
+    if !is_finite_color(color) {
+        return NON_FINITE_PIXEL.to_u32();
+    }
+    let color = settings.tone_map.apply(color * settings.exposure);
+    if let ColorSpace::Srgb = settings.color_space {
+        // Clamp first so the curve never sees values it isn't defined for
+        let color = color.map(|f| linear_to_srgb(f.clamp(0.0, 1.0)));
+        return Pixel::from_vek_color(color).to_u32();
+    }
+    let color = match settings.gamma {
+        // Keep the cheaper sqrt for the default so output stays bit identical
+        2.0 => color.map(|f| f.sqrt()),
+        1.0 => color,
+        gamma => color.map(|f| f.powf(1.0 / gamma)),
+    };
+    Pixel::from_vek_color(color).to_u32()
+}
+
+/// Render the whole image with `settings.seed` and return its linear color,
+/// denoised if asked for but not tone mapped or gamma corrected, along with
+/// the total number of samples taken
+pub fn render_to_buffer(
+    draw_size: PhysicalSize<u32>,
+    world: &World,
+    camera: &Camera,
+    settings: &RenderSettings,
+) -> (Vec<Rgb<f32>>, u64) {
+    let seed = settings.seed.unwrap_or_else(|| rand::rngs::OsRng.gen());
+    let (colors, total_samples) = draw_linear(
+        draw_size,
+        world,
+        camera,
+        settings,
+        seed,
+        &AtomicBool::new(false),
+    )
+    .expect("render without a cancel source can't be cancelled");
+    let colors = match settings.denoise {
+        Some(sigma) => denoise(&colors, draw_size, sigma),
+        None => colors,
+    };
+    (colors, total_samples)
+}
+
+/// Fraction of the samples of each pixel whose primary ray hit something,
+/// sampled the same way as `render_to_buffer` with `settings`
+pub fn render_coverage(
+    draw_size: PhysicalSize<u32>,
+    world: &World,
+    camera: &Camera,
+    settings: &RenderSettings,
+) -> Vec<f32> {
+    // Blurring or clamping would spoil the matte
+    let settings = RenderSettings {
+        debug: Some(DebugView::Coverage),
+        denoise: None,
+        clamp: None,
+        ..*settings
+    };
+    let (colors, _) = render_to_buffer(draw_size, world, camera, &settings);
+    colors.into_iter().map(|color| color.r).collect()
+}
+
+/// How the two views of a stereo pair share one image
+#[derive(Debug, Clone, Copy, Default)]
+pub enum StereoLayout {
+    /// Left view on the left and right view on the right, so the image is
+    /// twice as wide
+    #[default]
+    SideBySide,
+    /// Red from the left view and green and blue from the right, for
+    /// red/cyan glasses
+    Anaglyph,
+}
+
+impl FromStr for StereoLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sbs" => Ok(StereoLayout::SideBySide),
+            "anaglyph" => Ok(StereoLayout::Anaglyph),
+            _ => Err("expected one of sbs, anaglyph".to_string()),
+        }
+    }
+}
+
+/// Packed `left` and `right` views of `size` put together as `layout` says,
+/// and the size of the result
+pub fn compose_stereo(
+    left: &[u32],
+    right: &[u32],
+    size: PhysicalSize<u32>,
+    layout: StereoLayout,
+) -> (Vec<u32>, PhysicalSize<u32>) {
+    match layout {
+        StereoLayout::SideBySide => {
+            let width = size.width as usize;
+            let pixels = left
+                .chunks(width)
+                .zip(right.chunks(width))
+                .flat_map(|(left, right)| left.iter().chain(right).copied())
+                .collect();
+            (pixels, PhysicalSize::new(size.width * 2, size.height))
+        }
+        StereoLayout::Anaglyph => {
+            let pixels = left
+                .iter()
+                .zip(right)
+                .map(|(left, right)| left & 0xff0000 | right & 0x00ffff)
+                .collect();
+            (pixels, size)
+        }
+    }
+}
+
+/// World space position of the first hit through the center of each pixel,
+/// or [`NO_HIT_POSITION`] in every channel where there is none
+pub fn render_positions(
+    draw_size: PhysicalSize<u32>,
+    world: &World,
+    camera: &Camera,
+    settings: &RenderSettings,
+) -> Vec<Rgb<f32>> {
+    // Averaging positions, or a sentinel into them, gives points that are
+    // on nothing
+    let settings = RenderSettings {
+        debug: Some(DebugView::Position),
+        denoise: None,
+        clamp: None,
+        ssaa: 1,
+        ..*settings
+    };
+    render_to_buffer(draw_size, world, camera, &settings).0
+}
+
+/// `render_to_buffer` packed for display, auto exposed if the settings ask
+/// for it. Only every `stride`th pixel across and down is rendered, and
+/// copied over the ones it skips after it, for a quick coarse look. A stride
+/// of 1 renders every pixel
+pub fn draw(
+    draw_size: PhysicalSize<u32>,
+    world: &World,
+    camera: &Camera,
+    settings: &RenderSettings,
+    stride: u32,
+) -> (Vec<u32>, u64) {
+    let stride = stride.max(1);
+    let coarse_size = PhysicalSize::new(
+        draw_size.width.div_ceil(stride),
+        draw_size.height.div_ceil(stride),
+    );
+    let (colors, total_samples) = render_to_buffer(coarse_size, world, camera, settings);
+    let (settings, _) = settings.exposed_for(&colors);
+    let packed = pack_for_display(colors, &settings);
+    if stride == 1 {
+        return (packed, total_samples);
+    }
+    let (width, stride) = (draw_size.width as usize, stride as usize);
+    let coarse_width = coarse_size.width as usize;
+    let filled = (0..width * draw_size.height as usize)
+        .map(|i| packed[(i / width / stride) * coarse_width + (i % width) / stride])
+        .collect();
+    (filled, total_samples)
+}
+
+/// Linear colors tone mapped and packed with `to_display`, warning about
+/// pixels that aren't finite
+pub fn pack_for_display(colors: Vec<Rgb<f32>>, settings: &RenderSettings) -> Vec<u32> {
+    let non_finite = colors
+        .iter()
+        .filter(|&&color| !is_finite_color(color))
+        .count();
+    if non_finite > 0 {
+        eprintln!("rendered with {non_finite} NaN or infinite pixels");
+    }
+    colors
+        .into_iter()
+        .map(|color| to_display(color, settings))
+        .collect()
+}
+
+/// How far apart two images of the same size are
+#[derive(Debug, Clone, Copy)]
+pub struct ImageComparison {
+    /// Mean absolute difference per channel, in [0,1]
+    pub mae: f32,
+    /// Peak signal to noise ratio in decibels. Infinite for identical images
+    pub psnr: f32,
+    /// Mean structural similarity of the luminance, 1 for identical images
+    pub ssim: f32,
+}
+
+/// Standard deviation in pixels of the Gaussian window SSIM compares over
+const SSIM_SIGMA: f32 = 1.5;
+
+/// Compare `a` against `b`. Panics if their sizes differ
+pub fn compare_images(a: &RgbImage, b: &RgbImage) -> ImageComparison {
+    assert_eq!(
+        a.dimensions(),
+        b.dimensions(),
+        "images must be the same size"
+    );
+    let channels = |image: &RgbImage| -> Vec<f32> {
+        image.as_raw().iter().map(|&c| c as f32 / 255.0).collect()
+    };
+    let (a_channels, b_channels) = (channels(a), channels(b));
+    let count = a_channels.len() as f32;
+    let (mut absolute, mut squared) = (0.0, 0.0);
+    for (x, y) in a_channels.iter().zip(&b_channels) {
+        absolute += (x - y).abs();
+        squared += (x - y) * (x - y);
+    }
+    let mse: f32 = squared / count;
+    let psnr = if mse > 0.0 {
+        -10.0 * mse.log10()
+    } else {
+        f32::INFINITY
+    };
+    ImageComparison {
+        mae: absolute / count,
+        psnr,
+        ssim: ssim(a, b),
+    }
+}
+
+/// Mean SSIM of the luminance of `a` and `b` over Gaussian windows
+fn ssim(a: &RgbImage, b: &RgbImage) -> f32 {
+    const C1: f32 = 0.01 * 0.01;
+    const C2: f32 = 0.03 * 0.03;
+    let (width, height) = (a.width() as usize, a.height() as usize);
+    let luma = |image: &RgbImage| -> Vec<f32> {
+        image
+            .pixels()
+            .map(|p| luminance(Rgb::new(p[0], p[1], p[2]).map(|c| c as f32 / 255.0)))
+            .collect()
+    };
+    let (x, y) = (luma(a), luma(b));
+    let product =
+        |p: &[f32], q: &[f32]| -> Vec<f32> { p.iter().zip(q).map(|(p, q)| p * q).collect() };
+    let blur = |values: &[f32]| gaussian_blur(values, width, height, SSIM_SIGMA);
+    let (mean_x, mean_y) = (blur(&x), blur(&y));
+    let (xx, yy, xy) = (
+        blur(&product(&x, &x)),
+        blur(&product(&y, &y)),
+        blur(&product(&x, &y)),
+    );
+    let total: f32 = (0..width * height)
+        .map(|i| {
+            let (mx, my) = (mean_x[i], mean_y[i]);
+            let variance_x = xx[i] - mx * mx;
+            let variance_y = yy[i] - my * my;
+            let covariance = xy[i] - mx * my;
+            (2.0 * mx * my + C1) * (2.0 * covariance + C2)
+                / ((mx * mx + my * my + C1) * (variance_x + variance_y + C2))
+        })
+        .sum();
+    total / (width * height) as f32
+}
+
+/// Separable Gaussian blur of a `width` by `height` grid, renormalized where
+/// the kernel hangs off the edges
+fn gaussian_blur(values: &[f32], width: usize, height: usize, sigma: f32) -> Vec<f32> {
+    let radius = (3.0 * sigma).ceil() as isize;
+    let weights: Vec<f32> = (-radius..=radius)
+        .map(|d| (-(d * d) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let pass = |values: &[f32], horizontal: bool| -> Vec<f32> {
+        (0..width * height)
+            .map(|i| {
+                let (x, y) = ((i % width) as isize, (i / width) as isize);
+                let (mut sum, mut weight_sum) = (0.0, 0.0);
+                for (d, weight) in (-radius..=radius).zip(&weights) {
+                    let (nx, ny) = if horizontal { (x + d, y) } else { (x, y + d) };
+                    if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                        continue;
+                    }
+                    sum += weight * values[ny as usize * width + nx as usize];
+                    weight_sum += weight;
+                }
+                sum / weight_sum
+            })
+            .collect()
+    };
+    pass(&pass(values, true), false)
+}
+
+/// Per channel absolute difference between `a` and `b`
+pub fn difference_image(a: &RgbImage, b: &RgbImage) -> RgbImage {
+    RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        let (p, q) = (a.get_pixel(x, y), b.get_pixel(x, y));
+        image::Rgb(std::array::from_fn(|c| p[c].abs_diff(q[c])))
+    })
+}
+
+/// Write linear colors from `render_to_buffer` out as a 32-bit float
+/// OpenEXR image, untouched by tone mapping or gamma
+pub fn save_exr(
+    path: &Path,
+    colors: &[Rgb<f32>],
+    size: PhysicalSize<u32>,
+) -> exr::error::UnitResult {
+    let width = size.width as usize;
+    exr::prelude::write_rgb_file(path, width, size.height as usize, |x, y| {
+        let color = colors[y * width + x];
+        (color.r, color.g, color.b)
+    })
+}
+
+/// `save_exr` with an alpha channel that is 1 on the pixels `settings`
+/// renders and 0 elsewhere, for merging partial renders
+pub fn save_partial_exr(
+    path: &Path,
+    colors: &[Rgb<f32>],
+    size: PhysicalSize<u32>,
+    settings: &RenderSettings,
+) -> exr::error::UnitResult {
+    let width = size.width as usize;
+    exr::prelude::write_rgba_file(path, width, size.height as usize, |x, y| {
+        let color = colors[y * width + x];
+        let alpha = if settings.renders_pixel(x, y, width) {
+            1.0
+        } else {
+            0.0
+        };
+        (color.r, color.g, color.b, alpha)
+    })
+}
+
+/// Pixels and size of the first RGB(A) layer of an OpenEXR image. Images
+/// without alpha read as opaque
+pub fn load_exr(path: &Path) -> exr::error::Result<(Vec<Rgba<f32>>, PhysicalSize<u32>)> {
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        path,
+        |resolution, _| {
+            let size = PhysicalSize::new(resolution.width() as u32, resolution.height() as u32);
+            (vec![Rgba::broadcast(0.0); resolution.area()], size)
+        },
+        |(pixels, size), position, (r, g, b, a): (f32, f32, f32, f32)| {
+            pixels[position.y() * size.width as usize + position.x()] = Rgba::new(r, g, b, a);
+        },
+    )?;
+    Ok(image.layer_data.channel_data.pixels)
+}
+
+/// One line summary of how long a render took
+pub fn render_stats(size: PhysicalSize<u32>, total_samples: u64, elapsed: Duration) -> String {
+    let samples_per_pixel = total_samples as f64 / (size.width as f64 * size.height as f64);
+    let megasamples_per_second = total_samples as f64 / elapsed.as_secs_f64() / 1e6;
+    format!(
+        "rendered {}x{} @ {}spp in {}ms ({:.1} Msamples/s)",
+        size.width,
+        size.height,
+        (samples_per_pixel * 10.0).round() / 10.0,
+        elapsed.as_millis(),
+        megasamples_per_second
+    )
+}
+
+/// One line summary of the memory the triangles of `meshes` take, and how
+/// much indexing their materials saves over a copy per triangle. `None`
+/// without any triangles
+pub fn mesh_stats(meshes: &[Mesh]) -> Option<String> {
+    let (mut triangles, mut indexed, mut copied) = (0, 0, 0);
+    for object in meshes.iter().filter_map(Mesh::mesh_object) {
+        let (object_indexed, object_copied) = object.memory();
+        triangles += object.triangles.len();
+        indexed += object_indexed;
+        copied += object_copied;
+    }
+    let kib = |bytes: usize| (bytes as f64 / 1024.0 * 10.0).round() / 10.0;
+    (triangles > 0).then(|| {
+        format!(
+            "{triangles} mesh triangles in {} KiB, {} KiB saved by indexing materials instead of copying them per triangle",
+            kib(indexed),
+            kib(copied.saturating_sub(indexed))
+        )
+    })
+}
+
+/// Running mean of render passes for progressive refinement. Keeping the
+/// mean instead of a sum means later passes still move it after hours of
+/// accumulating, where they would vanish into the rounding of a huge sum
+#[derive(Default)]
+pub struct Accumulator {
+    mean: Vec<Rgb<f32>>,
+    pub passes: u64,
+    /// Samples taken over all passes
+    pub samples: u64,
+}
+
+/// Most passes an `Accumulator` takes before it counts as converged
+pub const MAX_PASSES: u64 = 1024;
+
+impl Accumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether more passes would be wasted: adaptive sampling already took
+    /// each pixel to `settings.tolerance`, or `MAX_PASSES` passes are in
+    pub fn converged(&self, settings: &RenderSettings) -> bool {
+        self.passes >= MAX_PASSES || (self.passes > 0 && settings.tolerance.is_some())
+    }
+
+    pub fn reset(&mut self) {
+        self.mean.clear();
+        self.passes = 0;
+        self.samples = 0;
+    }
+
+    /// Seed for the next pass. Pass `k` since the last reset always uses
+    /// `base_seed + k`, and `draw_linear` hashes that with the pixel index,
+    /// so every sample depends only on (`base_seed`, pixel index, `k`). The
+    /// accumulated image is then the same no matter how passes were
+    /// scheduled across threads or how many requests were coalesced into a
+    /// reset along the way
+    pub fn next_seed(&self, base_seed: u64) -> u64 {
+        base_seed.wrapping_add(self.passes)
+    }
+
+    pub fn add(&mut self, pass: &[Rgb<f32>], samples: u64) {
+        if self.passes == 0 {
+            self.mean.clear();
+            self.mean.extend_from_slice(pass);
+        } else {
+            // Welford's update, every pass weighs the same
+            let passes = (self.passes + 1) as f32;
+            for (mean, color) in self.mean.iter_mut().zip(pass) {
+                *mean += (*color - *mean) / passes;
+            }
+        }
+        self.passes += 1;
+        self.samples += samples;
+    }
+
+    /// What `tile` of an unfinished pass adds to the mean so far at `width`,
+    /// packed for display row by row. Used to preview passes as they render
+    pub fn preview_tile(&self, tile: &Tile, width: usize, settings: &RenderSettings) -> Vec<u32> {
+        let passes = (self.passes + 1) as f32;
+        tile.pixels
+            .iter()
+            .enumerate()
+            .map(|(i, &color)| {
+                let index = (tile.y0 + i / tile.width) * width + tile.x0 + i % tile.width;
+                let mean = self.mean.get(index).copied().unwrap_or(color);
+                to_display(mean + (color - mean) / passes, settings)
+            })
+            .collect()
+    }
+
+    /// Mean of all passes so far at `size`, denoised if the settings ask
+    /// for it and packed for display
+    pub fn display_buffer(&self, size: PhysicalSize<u32>, settings: &RenderSettings) -> Vec<u32> {
+        let mut buffer = Vec::new();
+        self.display_buffer_into(&mut buffer, size, settings);
+        buffer
+    }
+
+    /// `display_buffer` written over `buffer`, reusing its allocation.
+    /// Returns the stops auto exposure adjusted the image by, if it did
+    pub fn display_buffer_into(
+        &self,
+        buffer: &mut Vec<u32>,
+        size: PhysicalSize<u32>,
+        settings: &RenderSettings,
+    ) -> Option<f32> {
+        buffer.clear();
+        if settings.denoise.is_none() && !settings.auto_exposure {
+            buffer.extend(self.mean.iter().map(|&color| to_display(color, settings)));
+            return None;
+        }
+        let mean = match settings.denoise {
+            Some(sigma) => denoise(&self.mean, size, sigma),
+            None => self.mean.clone(),
+        };
+        let (settings, ev) = settings.exposed_for(&mean);
+        buffer.extend(mean.into_iter().map(|color| to_display(color, &settings)));
+        ev
+    }
+}
+
+/// Camera placement as it appears in scene files. The aspect ratio comes
+/// from the render size so it isn't part of the scene
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraSettings {
+    pub lookfrom: Vec3<f32>,
+    pub lookat: Vec3<f32>,
+    pub vup: Vec3<f32>,
+    /// Vertical field of view in degrees
+    pub vfov: f32,
+    pub aperture: f32,
+    /// Corners of the lens opening. See [`Camera::with_aperture_blades`]
+    pub aperture_blades: u32,
+    pub focus_dist: f32,
+    pub projection: Projection,
+    /// Part of the [0,1] time range the shutter is open for. See
+    /// [`Camera::with_shutter`]
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            lookfrom: Vec3::zero(),
+            lookat: Vec3::new(0.0, 0.0, -1.0),
+            vup: Vec3::unit_y(),
+            vfov: 90.0,
+            aperture: 0.0,
+            aperture_blades: 0,
+            focus_dist: 1.0,
+            projection: Projection::default(),
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+        }
+    }
+}
+
+impl CameraSettings {
+    pub fn build(&self, aspect_ratio: f32) -> Camera {
+        Camera::new(
+            self.lookfrom,
+            self.lookat,
+            self.vup,
+            self.vfov,
+            aspect_ratio,
+            self.aperture,
+            self.focus_dist,
+            self.projection,
+        )
+        .with_shutter(self.shutter_open, self.shutter_close)
+        .with_aperture_blades(self.aperture_blades)
+    }
+
+    /// Unit vectors pointing forward, right and up from the camera
+    pub fn basis(&self) -> (Vec3<f32>, Vec3<f32>, Vec3<f32>) {
+        let forward = (self.lookat - self.lookfrom).normalized();
+        let right = camera_right(-forward, self.vup);
+        let up = right.cross(forward);
+        (forward, right, up)
+    }
+
+    /// One eye of a stereo pair, moved `offset` along the right axis and
+    /// turned toward the point `convergence` straight ahead of the original
+    /// eye. Things at that distance line up in both views
+    pub fn stereo_eye(&self, offset: f32, convergence: f32) -> Self {
+        let (forward, right, _) = self.basis();
+        Self {
+            lookfrom: self.lookfrom + right * offset,
+            lookat: self.lookfrom + forward * convergence,
+            ..*self
+        }
+    }
+
+    /// Move both the eye and the target by `offset`, given in camera space
+    /// as (right, up, forward)
+    pub fn translate(&mut self, offset: Vec3<f32>) {
+        let (forward, right, up) = self.basis();
+        let world_offset = offset.x * right + offset.y * up + offset.z * forward;
+        self.lookfrom += world_offset;
+        self.lookat += world_offset;
+    }
+
+    /// Camera looking at the center of `bbox` from the front and a little
+    /// above, far enough back that the box's bounding sphere fits in the
+    /// view with some margin. Keeps `vup`, the projection and the shutter
+    pub fn auto_frame(&self, bbox: Aabb, aspect_ratio: f32) -> Self {
+        const VFOV: f32 = 40.0;
+        const MARGIN: f32 = 1.1;
+        let center = bbox.centroid();
+        let radius = ((bbox.max - bbox.min).magnitude() / 2.0).max(1e-3) * MARGIN;
+        // Fit the narrower of the two fields of view
+        let half_vfov = (VFOV / 2.0).to_radians();
+        let half_fov = half_vfov.min((aspect_ratio * half_vfov.tan()).atan());
+        let distance = radius / half_fov.sin();
+        let lookfrom = center + Vec3::new(0.0, 0.3, 1.0).normalized() * distance;
+        let projection = match self.projection {
+            Projection::Orthographic { .. } => Projection::Orthographic {
+                scale: 2.0 * radius / aspect_ratio.min(1.0),
+            },
+            projection => projection,
+        };
+        Self {
+            lookfrom,
+            lookat: center,
+            vup: self.vup,
+            vfov: VFOV,
+            aperture: 0.0,
+            focus_dist: distance,
+            projection,
+            ..*self
+        }
+    }
+
+    /// Turn the view direction by `yaw` around `vup` and `pitch` around the
+    /// camera's right axis, both in radians. Pitch stops short of looking
+    /// straight along `vup` where the basis would degenerate
+    pub fn rotate(&mut self, yaw: f32, pitch: f32) {
+        let (forward, right, _) = self.basis();
+        let distance = (self.lookat - self.lookfrom).magnitude();
+        let yawed = Quaternion::rotation_3d(yaw, self.vup.normalized()) * forward;
+        let pitched = Quaternion::rotation_3d(pitch, right) * yawed;
+        let direction = if pitched.dot(self.vup.normalized()).abs() < 0.99 {
+            pitched
+        } else {
+            yawed
+        };
+        self.lookat = self.lookfrom + direction.normalized() * distance;
+    }
+
+    /// The camera moved `angle` radians around the vertical line through
+    /// `target` and turned to face it. `radius` overrides the horizontal
+    /// distance to the line, otherwise the current one is kept
+    pub fn orbit(&self, target: Vec3<f32>, radius: Option<f32>, angle: f32) -> Self {
+        let mut offset = self.lookfrom - target;
+        if let Some(radius) = radius {
+            let horizontal = Vec3::new(offset.x, 0.0, offset.z);
+            let direction = if horizontal.magnitude_squared() > 0.0 {
+                horizontal.normalized()
+            } else {
+                Vec3::unit_z()
+            };
+            offset = direction * radius + Vec3::unit_y() * offset.y;
+        }
+        Self {
+            lookfrom: target + Quaternion::rotation_y(angle) * offset,
+            lookat: target,
+            ..*self
+        }
+    }
+}
+
+/// A group of objects placed in the scene by a rotation about the Y axis
+/// followed by a translation, so the same geometry can be reused
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Instance {
+    #[serde(default)]
+    pub spheres: Vec<Sphere>,
+    #[serde(default)]
+    pub triangles: Vec<Triangle>,
+    #[serde(default)]
+    pub quads: Vec<Quad>,
+    /// Degrees counterclockwise looking down from +Y
+    #[serde(default)]
+    pub rotate_y: f32,
+    #[serde(default)]
+    pub translate: Vec3<f32>,
+}
+
+impl Instance {
+    /// The placed group, or `None` if it is empty
+    pub fn object(&self) -> Option<Box<dyn Hittable + Sync>> {
+        let mut objects: Vec<Box<dyn Hittable + Sync>> = Vec::new();
+        for sphere in &self.spheres {
+            objects.push(Box::new(sphere.clone()));
+        }
+        for triangle in &self.triangles {
+            objects.push(Box::new(triangle.clone()));
+        }
+        for quad in &self.quads {
+            objects.push(Box::new(quad.clone()));
+        }
+        if objects.is_empty() {
+            return None;
+        }
+        let rotated = RotateY::new(Box::new(BvhNode::new(objects)), self.rotate_y);
+        Some(Box::new(Translate {
+            object: Box::new(rotated),
+            offset: self.translate,
+        }))
+    }
+}
+
+/// Volume of fog or smoke as it appears in scene files
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Medium {
+    /// Shape the medium fills. Its material is ignored
+    pub boundary: Sphere,
+    pub density: f32,
+    pub color: Rgb<f32>,
+}
+
+impl Medium {
+    pub fn object(&self) -> ConstantMedium {
+        ConstantMedium {
+            boundary: Box::new(self.boundary.clone()),
+            density: self.density,
+            material: Material {
+                albedo: Texture::Solid(self.color),
+                emitted: black(),
+                mat_type: MaterialType::Isotropic,
+                double_sided: true,
+                roughness: None,
+                ior: None,
+            },
+        }
+    }
+}
+
+/// Triangles read from a Wavefront OBJ file. Scene files refer to it by path
+/// like [`ImageTexture`]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "PathBuf", into = "PathBuf")]
+pub struct ObjMesh {
+    path: PathBuf,
+    faces: Arc<Vec<MeshFace>>,
+    /// Names the file gave to `usemtl`, for material indices from 1 on
+    material_names: Arc<Vec<String>>,
+}
+
+/// One triangle of a mesh, with normals if the file gave them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshFace {
+    pub vertices: [Vec3<f32>; 3],
+    pub normals: Option<[Vec3<f32>; 3]>,
+    /// 0 for faces before any `usemtl`, otherwise 1 plus the position of
+    /// the face's material among the names in order of first use
+    pub material: u16,
+}
+
+impl ObjMesh {
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read mesh {}: {e}", path.display()))?;
+        let (faces, material_names) = parse_obj(&text)
+            .map_err(|e| format!("failed to parse mesh {}: {e}", path.display()))?;
+        Ok(Self {
+            path,
+            faces: Arc::new(faces),
+            material_names: Arc::new(material_names),
+        })
+    }
+}
+
+impl TryFrom<PathBuf> for ObjMesh {
+    type Error = String;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        Self::load(path)
+    }
+}
+
+impl From<ObjMesh> for PathBuf {
+    fn from(mesh: ObjMesh) -> Self {
+        mesh.path
+    }
+}
+
+/// Every face in OBJ source `text`, with polygons fanned into triangles
+/// around their first vertex, and the material names `usemtl` lines gave
+/// them. Only `v`, `vn`, `f` and `usemtl` lines are read. A face gets
+/// normals only if all of its vertices reference one
+pub fn parse_obj(text: &str) -> Result<(Vec<MeshFace>, Vec<String>), String> {
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut faces = Vec::new();
+    let mut material_names: Vec<String> = Vec::new();
+    let mut material = 0;
+    for (line_number, line) in text.lines().enumerate() {
+        let error = |message: String| format!("line {}: {message}", line_number + 1);
+        let mut fields = line.split_whitespace();
+        let keyword = fields.next();
+        match keyword {
+            Some("v") | Some("vn") => {
+                let coordinates = fields
+                    .take(3)
+                    .map(|field| field.parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| error(format!("invalid vertex: {e}")))?;
+                let [x, y, z] = coordinates[..] else {
+                    return Err(error("vertex needs 3 coordinates".to_string()));
+                };
+                if keyword == Some("v") {
+                    positions.push(Vec3::new(x, y, z));
+                } else {
+                    normals.push(Vec3::new(x, y, z).normalized());
+                }
+            }
+            Some("f") => {
+                // Indices start at 1, negative ones count back from the
+                // latest entry
+                let lookup = |list: &[Vec3<f32>], index: &str| {
+                    let index: i64 = index
+                        .parse()
+                        .map_err(|e| error(format!("invalid face index `{index}`: {e}")))?;
+                    let resolved = if index < 0 {
+                        list.len() as i64 + index
+                    } else {
+                        index - 1
+                    };
+                    usize::try_from(resolved)
+                        .ok()
+                        .and_then(|i| list.get(i).copied())
+                        .ok_or_else(|| error(format!("face index {index} out of range")))
+                };
+                // Each vertex is `v`, `v/vt`, `v//vn` or `v/vt/vn`
+                let face = fields
+                    .map(|field| {
+                        let mut indices = field.split('/');
+                        let position = lookup(&positions, indices.next().unwrap_or_default())?;
+                        let normal = match indices.nth(1) {
+                            Some(index) if !index.is_empty() => Some(lookup(&normals, index)?),
+                            _ => None,
+                        };
+                        Ok((position, normal))
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                if face.len() < 3 {
+                    return Err(error("face needs at least 3 vertices".to_string()));
+                }
+                let has_normals = face.iter().all(|(_, normal)| normal.is_some());
+                for pair in face[1..].windows(2) {
+                    let corners = [face[0], pair[0], pair[1]];
+                    faces.push(MeshFace {
+                        vertices: corners.map(|(position, _)| position),
+                        normals: has_normals
+                            .then(|| corners.map(|(_, normal)| normal.unwrap_or_default())),
+                        material,
+                    });
+                }
+            }
+            Some("usemtl") => {
+                let name = fields.collect::<Vec<_>>().join(" ");
+                let position = match material_names.iter().position(|known| *known == name) {
+                    Some(position) => position,
+                    None => {
+                        material_names.push(name);
+                        material_names.len() - 1
+                    }
+                };
+                material = u16::try_from(position + 1)
+                    .map_err(|_| error("more materials than a mesh can index".to_string()))?;
+            }
+            _ => {}
+        }
+    }
+    Ok((faces, material_names))
+}
+
+/// An OBJ mesh as it appears in scene files, scaled, then rotated about the Y
+/// axis and translated like an [`Instance`]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Mesh {
+    pub obj: ObjMesh,
+    /// Used for faces before any `usemtl` and for names missing from
+    /// `materials`
+    pub material: Material,
+    /// Materials for the names the OBJ file's `usemtl` lines give
+    #[serde(default)]
+    pub materials: BTreeMap<String, Material>,
+    #[serde(default = "one")]
+    pub scale: f32,
+    /// Degrees counterclockwise looking down from +Y
+    #[serde(default)]
+    pub rotate_y: f32,
+    #[serde(default)]
+    pub translate: Vec3<f32>,
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+impl Mesh {
+    /// The scaled mesh under its own BVH, before rotating and translating
+    /// it, or `None` if it has no faces
+    pub fn mesh_object(&self) -> Option<MeshObject> {
+        let triangles: Vec<_> = self
+            .obj
+            .faces
+            .iter()
+            .map(|face| MeshTriangle {
+                vertices: face.vertices.map(|vertex| vertex * self.scale),
+                // Uniform scaling leaves normals alone, flipping only for
+                // negative scales
+                normals: face
+                    .normals
+                    .map(|normals| normals.map(|n| n * self.scale.signum())),
+                material: face.material,
+            })
+            .collect();
+        if triangles.is_empty() {
+            return None;
+        }
+        let materials = std::iter::once(&self.material)
+            .chain(
+                self.obj
+                    .material_names
+                    .iter()
+                    .map(|name| self.materials.get(name).unwrap_or(&self.material)),
+            )
+            .cloned()
+            .collect();
+        let mut indices: Vec<u32> = (0..triangles.len() as u32).collect();
+        let bvh = MeshBvh::new(&triangles, &mut indices);
+        Some(MeshObject {
+            triangles,
+            materials,
+            bvh,
+        })
+    }
+
+    /// The placed mesh, or `None` if it has no faces
+    pub fn object(&self) -> Option<Box<dyn Hittable + Sync>> {
+        let rotated = RotateY::new(Box::new(self.mesh_object()?), self.rotate_y);
+        Some(Box::new(Translate {
+            object: Box::new(rotated),
+            offset: self.translate,
+        }))
+    }
+}
+
+/// On-disk scene description, loaded from RON. Scenes built in code can
+/// start from the empty default and chain the `add_*` methods
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct SceneFile {
+    #[serde(default)]
+    pub spheres: Vec<Sphere>,
+    #[serde(default)]
+    pub planes: Vec<Plane>,
+    #[serde(default)]
+    pub triangles: Vec<Triangle>,
+    #[serde(default)]
+    pub quads: Vec<Quad>,
+    #[serde(default)]
+    pub meshes: Vec<Mesh>,
+    #[serde(default)]
+    pub instances: Vec<Instance>,
+    #[serde(default)]
+    pub media: Vec<Medium>,
+    #[serde(default)]
+    pub background: Background,
+    #[serde(default)]
+    pub camera: Option<CameraSettings>,
+    /// Settings to render the scene with. Command line flags still override
+    /// them
+    #[serde(default)]
+    pub settings: Option<RenderSettings>,
+    /// Width and height in pixels to render the scene at, overridden the
+    /// same way
+    #[serde(default)]
+    pub size: Option<(u32, u32)>,
+}
+
+impl SceneFile {
+    pub fn add_sphere(&mut self, origin: Vec3<f32>, radius: f32, material: Material) -> &mut Self {
+        self.spheres.push(Sphere {
+            origin,
+            radius,
+            material,
+            velocity: Vec3::zero(),
+        });
+        self
+    }
+
+    /// Add a plane through `point`. `normal` doesn't need to be unit length
+    pub fn add_plane(
+        &mut self,
+        point: Vec3<f32>,
+        normal: Vec3<f32>,
+        material: Material,
+    ) -> &mut Self {
+        self.planes.push(Plane {
+            point,
+            normal: normal.normalized(),
+            material,
+        });
+        self
+    }
+
+    /// Infinite two tone checkerboard floor at y = 0 with squares of side
+    /// `scale`
+    pub fn add_ground(&mut self, scale: f32) -> &mut Self {
+        let checker = Texture::Checker {
+            even: Rgb::new(0.2, 0.3, 0.1),
+            odd: Rgb::broadcast(0.9),
+            scale,
+        };
+        self.add_plane(Vec3::zero(), Vec3::unit_y(), Material::lambertian(checker))
+    }
+
+    /// Infinite floor at y = 0 with grid lines of color `line` every
+    /// `spacing` units over `background`, thicker every `major_every` lines
+    pub fn add_grid_floor(
+        &mut self,
+        spacing: f32,
+        major_every: u32,
+        line: Rgb<f32>,
+        background: Rgb<f32>,
+    ) -> &mut Self {
+        let grid = Texture::Grid {
+            background,
+            line,
+            spacing,
+            major_every,
+        };
+        self.add_plane(Vec3::zero(), Vec3::unit_y(), Material::lambertian(grid))
+    }
+
+    pub fn add_quad(
+        &mut self,
+        origin: Vec3<f32>,
+        u: Vec3<f32>,
+        v: Vec3<f32>,
+        material: Material,
+    ) -> &mut Self {
+        self.quads.push(Quad {
+            origin,
+            u,
+            v,
+            material,
+        });
+        self
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(ron::from_str(&text)?)
+    }
+
+    /// The scene as RON that `load` reads back. Textures, environment maps
+    /// and meshes are written as the paths they were loaded from
+    pub fn to_ron(&self) -> Result<String, ron::Error> {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+    }
+
+    pub fn world(&self) -> World {
+        let mut objects: Vec<Box<dyn Hittable + Sync>> = Vec::new();
+        for sphere in &self.spheres {
+            objects.push(Box::new(sphere.clone()));
+        }
+        for plane in &self.planes {
+            objects.push(Box::new(plane.clone()));
+        }
+        for triangle in &self.triangles {
+            objects.push(Box::new(triangle.clone()));
+        }
+        for quad in &self.quads {
+            objects.push(Box::new(quad.clone()));
+        }
+        objects.extend(self.meshes.iter().filter_map(Mesh::object));
+        objects.extend(self.instances.iter().filter_map(Instance::object));
+        let lights = self
+            .spheres
+            .iter()
+            .filter(|sphere| matches!(sphere.material.mat_type, MaterialType::Emissive(_)))
+            .cloned()
+            .collect();
+        World::new(objects, lights, self.background.clone())
+            .with_media(self.media.iter().map(Medium::object).collect())
+    }
+
+    /// Whether anything in the scene moves while the shutter is open
+    pub fn has_motion(&self) -> bool {
+        self.spheres
+            .iter()
+            .chain(self.instances.iter().flat_map(|instance| &instance.spheres))
+            .any(|sphere| sphere.velocity != Vec3::zero())
+    }
+
+    pub fn camera_settings(&self) -> CameraSettings {
+        self.camera.unwrap_or_default()
+    }
+}
+
+/// Scene put together in code instead of loaded from a file, checked as a
+/// whole by `build`
+///
+/// ```
+/// use fastcaster::{render_to_buffer, CameraSettings, Material, RenderSettings, SceneBuilder};
+/// use vek::{Rgb, Vec3};
+///
+/// let scene = SceneBuilder::new()
+///     .add_sphere(Vec3::new(0.0, 0.0, -1.0), 0.5, Material::lambertian(Rgb::new(0.7, 0.3, 0.3)))
+///     .add_ground(1.0)
+///     .camera(CameraSettings::default())
+///     .settings(RenderSettings {
+///         seed: Some(1),
+///         ..RenderSettings::default()
+///     })
+///     .size(16, 9)
+///     .build()
+///     .unwrap();
+/// let (colors, _) = render_to_buffer(scene.size, &scene.world, &scene.camera, &scene.settings);
+/// assert_eq!(colors.len(), 16 * 9);
+///
+/// // Nothing to look through
+/// assert!(SceneBuilder::new().size(16, 9).build().is_err());
+/// ```
+#[derive(Default)]
+pub struct SceneBuilder {
+    scene: SceneFile,
+    size: Option<PhysicalSize<u32>>,
+    /// Problems found while adding things, reported by `build`
+    errors: Vec<String>,
+}
+
+/// Everything needed to render a scene from `SceneBuilder`
+pub struct Scene {
+    pub world: World,
+    pub camera: Camera,
+    pub settings: RenderSettings,
+    pub size: PhysicalSize<u32>,
+}
+
+impl SceneBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_sphere(mut self, origin: Vec3<f32>, radius: f32, material: Material) -> Self {
+        self.scene.add_sphere(origin, radius, material);
+        self
+    }
+
+    /// Add a plane through `point`. `normal` doesn't need to be unit length
+    pub fn add_plane(mut self, point: Vec3<f32>, normal: Vec3<f32>, material: Material) -> Self {
+        self.scene.add_plane(point, normal, material);
+        self
+    }
+
+    pub fn add_quad(
+        mut self,
+        origin: Vec3<f32>,
+        u: Vec3<f32>,
+        v: Vec3<f32>,
+        material: Material,
+    ) -> Self {
+        self.scene.add_quad(origin, u, v, material);
+        self
+    }
+
+    /// Checkerboard floor at y = 0, as `SceneFile::add_ground`
+    pub fn add_ground(mut self, scale: f32) -> Self {
+        self.scene.add_ground(scale);
+        self
+    }
+
+    /// Load the OBJ file at `path` and add it with `material` for all of its
+    /// faces. A file that can't be read fails `build`
+    pub fn add_mesh(mut self, path: impl Into<PathBuf>, material: Material) -> Self {
+        match ObjMesh::load(path.into()) {
+            Ok(obj) => self.scene.meshes.push(Mesh {
+                obj,
+                material,
+                materials: BTreeMap::new(),
+                scale: 1.0,
+                rotate_y: 0.0,
+                translate: Vec3::zero(),
+            }),
+            Err(e) => self.errors.push(e),
+        }
+        self
+    }
+
+    pub fn background(mut self, background: Background) -> Self {
+        self.scene.background = background;
+        self
+    }
+
+    pub fn camera(mut self, camera: CameraSettings) -> Self {
+        self.scene.camera = Some(camera);
+        self
+    }
+
+    pub fn settings(mut self, settings: RenderSettings) -> Self {
+        self.scene.settings = Some(settings);
+        self
+    }
+
+    /// Image size in pixels, which also sets the camera's aspect ratio
+    pub fn size(mut self, width: u32, height: u32) -> Self {
+        self.size = Some(PhysicalSize::new(width, height));
+        self
+    }
+
+    /// The scene ready to render, or everything wrong with it: meshes that
+    /// failed to load, a missing camera or size, and what
+    /// `World::validate` warns about
+    pub fn build(self) -> Result<Scene, String> {
+        let mut errors = self.errors;
+        if self.scene.camera.is_none() {
+            errors.push("the scene has no camera".to_string());
+        }
+        match self.size {
+            None => errors.push("the scene has no image size".to_string()),
+            Some(size) if size.width == 0 || size.height == 0 => {
+                errors.push(format!("{}x{} is no image size", size.width, size.height))
+            }
+            Some(_) => {}
+        }
+        let world = self.scene.world();
+        errors.extend(world.validate());
+        let (Some(size), true) = (self.size, errors.is_empty()) else {
+            return Err(errors.join("; "));
+        };
+        let camera = self
+            .scene
+            .camera_settings()
+            .build(size.width as f32 / size.height as f32)
+            .with_motion_blur(self.scene.has_motion());
+        Ok(Scene {
+            world,
+            camera,
+            settings: self.scene.settings.unwrap_or_default(),
+            size,
+        })
+    }
+}
+
+/// The demo scene: a diffuse sphere between two metal ones, standing on a
+/// sphere so big it passes for the ground
+pub fn default_scene() -> SceneFile {
+    let mut scene = SceneFile::default();
+    scene
+        .add_sphere(
+            Vec3::new(0.0, 0.0, -1.0),
+            0.5,
+            Material::lambertian(Rgb::new(0.7, 0.3, 0.3)),
+        )
+        .add_sphere(
+            Vec3::new(0.0, -100.5, -1.0),
+            100.0,
+            Material::lambertian(Rgb::new(0.8, 0.8, 0.3)),
+        )
+        .add_sphere(
+            Vec3::new(-1.0, 0.0, -1.0),
+            0.5,
+            Material::metal(Rgb::broadcast(0.8), 0.3),
+        )
+        .add_sphere(
+            Vec3::new(1.0, 0.0, -1.0),
+            0.5,
+            Material::metal(Rgb::new(0.8, 0.6, 0.2), 1.0),
+        );
+    scene
+}
+
+/// The demo scene with a glass sphere on the left, on a ground plane
+pub fn glass_spheres() -> SceneFile {
+    let mut scene = SceneFile::default();
+    scene
+        .add_sphere(
+            Vec3::new(0.0, 0.0, -1.0),
+            0.5,
+            Material::lambertian(Rgb::new(0.7, 0.3, 0.3)),
+        )
+        .add_sphere(Vec3::new(-1.0, 0.0, -1.0), 0.5, Material::dielectric(1.5))
+        .add_sphere(
+            Vec3::new(1.0, 0.0, -1.0),
+            0.5,
+            Material::metal(Rgb::new(0.8, 0.6, 0.2), 1.0),
+        )
+        .add_plane(
+            Vec3::new(0.0, -0.5, 0.0),
+            Vec3::unit_y(),
+            Material::lambertian(Rgb::new(0.8, 0.8, 0.3)),
+        );
+    scene
+}
+
+/// Builds one of the [`BUILTIN_SCENES`]
+pub type SceneFactory = fn() -> SceneFile;
+
+/// Scenes that can be selected by name instead of a scene file path, and
+/// the functions that build them
+pub const BUILTIN_SCENES: &[(&str, SceneFactory)] = &[
+    ("three-spheres", default_scene),
+    ("glass-spheres", glass_spheres),
+    ("cornell", cornell_box),
+    ("many-spheres", many_spheres),
+    ("dielectric-test", dielectric_test),
+    ("metal-fuzz-ramp", metal_fuzz_ramp),
+    ("roughness-map", roughness_map),
+];
+
+/// Built in scene selected by name instead of a scene file path
+pub fn builtin_scene(name: &str) -> Option<SceneFile> {
+    BUILTIN_SCENES
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .map(|(_, build)| build())
+}
+
+/// A 22 x 22 grid of small spheres with random materials around three big
+/// ones, for stressing the BVH. Always laid out from the same seed
+pub fn many_spheres() -> SceneFile {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut scene = SceneFile {
+        camera: Some(CameraSettings {
+            lookfrom: Vec3::new(13.0, 2.0, 3.0),
+            lookat: Vec3::zero(),
+            vfov: 20.0,
+            aperture: 0.1,
+            focus_dist: 10.0,
+            ..CameraSettings::default()
+        }),
+        ..SceneFile::default()
+    };
+    scene.add_plane(
+        Vec3::zero(),
+        Vec3::unit_y(),
+        Material::lambertian(Rgb::broadcast(0.5)),
+    );
+    for a in -11..11 {
+        for b in -11..11 {
+            let center = Vec3::new(
+                a as f32 + 0.9 * rng.gen::<f32>(),
+                0.2,
+                b as f32 + 0.9 * rng.gen::<f32>(),
+            );
+            // Keep clear of the big metal sphere
+            if (center - Vec3::new(4.0, 0.2, 0.0)).magnitude() <= 0.9 {
+                continue;
+            }
+            let material = match rng.gen::<f32>() {
+                choice if choice < 0.8 => {
+                    let albedo = Rgb::<f32>::new(rng.gen(), rng.gen(), rng.gen())
+                        * Rgb::new(rng.gen(), rng.gen(), rng.gen());
+                    Material::lambertian(albedo)
+                }
+                choice if choice < 0.95 => {
+                    let albedo = Rgb::new(
+                        rng.gen_range(0.5..1.0),
+                        rng.gen_range(0.5..1.0),
+                        rng.gen_range(0.5..1.0),
+                    );
+                    Material::metal(albedo, rng.gen_range(0.0..0.5))
+                }
+                _ => Material::dielectric(1.5),
+            };
+            scene.add_sphere(center, 0.2, material);
+        }
+    }
+    scene
+        .add_sphere(Vec3::new(0.0, 1.0, 0.0), 1.0, Material::dielectric(1.5))
+        .add_sphere(
+            Vec3::new(-4.0, 1.0, 0.0),
+            1.0,
+            Material::lambertian(Rgb::new(0.4, 0.2, 0.1)),
+        )
+        .add_sphere(
+            Vec3::new(4.0, 1.0, 0.0),
+            1.0,
+            Material::metal(Rgb::new(0.7, 0.6, 0.5), 0.0),
+        );
+    scene
+}
+
+/// Camera a little above and in front of a row of unit spheres resting on
+/// the ground along x, for the material test scenes
+fn sphere_row_camera() -> CameraSettings {
+    CameraSettings {
+        lookfrom: Vec3::new(0.0, 1.5, 6.0),
+        lookat: Vec3::new(0.0, 0.5, 0.0),
+        vfov: 40.0,
+        ..CameraSettings::default()
+    }
+}
+
+/// Glass spheres on a checkerboard, from left to right: air, water, glass,
+/// diamond, and a hollow glass bubble
+pub fn dielectric_test() -> SceneFile {
+    let mut scene = SceneFile {
+        camera: Some(sphere_row_camera()),
+        ..SceneFile::default()
+    };
+    scene.add_ground(0.5);
+    for (i, ior) in [1.0, 1.33, 1.5, 2.4].into_iter().enumerate() {
+        let center = Vec3::new(-3.0 + 1.5 * i as f32, 0.5, 0.0);
+        scene.add_sphere(center, 0.5, Material::dielectric(ior));
+    }
+    // A negative radius flips the inner sphere's normals, leaving a shell
+    let bubble = Vec3::new(3.0, 0.5, 0.0);
+    scene
+        .add_sphere(bubble, 0.5, Material::dielectric(1.5))
+        .add_sphere(bubble, -0.45, Material::dielectric(1.5));
+    scene
+}
+
+/// Gold spheres on a checkerboard with fuzz going from 0 on the left to 1 on
+/// the right in even steps
+pub fn metal_fuzz_ramp() -> SceneFile {
+    let mut scene = SceneFile {
+        camera: Some(sphere_row_camera()),
+        ..SceneFile::default()
+    };
+    scene.add_ground(0.5);
+    for i in 0..6 {
+        let center = Vec3::new(-3.75 + 1.5 * i as f32, 0.5, 0.0);
+        let fuzz = i as f32 / 5.0;
+        scene.add_sphere(center, 0.5, Material::metal(Rgb::new(0.8, 0.6, 0.2), fuzz));
+    }
+    scene
+}
+
+/// A metal sphere whose marble roughness map runs from polished to matte,
+/// beside glass whose IOR map alternates between 1.2 and 1.8 in a checker
+pub fn roughness_map() -> SceneFile {
+    let mut scene = SceneFile {
+        camera: Some(sphere_row_camera()),
+        ..SceneFile::default()
+    };
+    scene.add_ground(0.5);
+    let metal = Material {
+        roughness: Some(Texture::Noise { scale: 4.0 }),
+        ..Material::metal(Rgb::new(0.8, 0.6, 0.2), 0.0)
+    };
+    scene.add_sphere(Vec3::new(-1.2, 1.0, 0.0), 1.0, metal);
+    let glass = Material {
+        ior: Some(Texture::Checker {
+            even: Rgb::broadcast(0.2),
+            odd: Rgb::broadcast(0.8),
+            scale: 0.5,
+        }),
+        ..Material::dielectric(1.5)
+    };
+    scene.add_sphere(Vec3::new(1.2, 1.0, 0.0), 1.0, glass);
+    scene
+}
+
+/// The classic Cornell box, 555 units on a side: red and green side walls,
+/// a ceiling light and two white boxes
+pub fn cornell_box() -> SceneFile {
+    let red = Material::lambertian(Rgb::new(0.65, 0.05, 0.05));
+    let white = Material::lambertian(Rgb::broadcast(0.73));
+    let green = Material::lambertian(Rgb::new(0.12, 0.45, 0.15));
+    let block = |height: f32, rotate_y: f32, translate: Vec3<f32>| Instance {
+        spheres: Vec::new(),
+        triangles: Vec::new(),
+        quads: quad_box(Vec3::zero(), Vec3::new(165.0, height, 165.0), &white),
+        rotate_y,
+        translate,
+    };
+    let mut scene = SceneFile {
+        instances: vec![
+            block(330.0, 15.0, Vec3::new(265.0, 0.0, 295.0)),
+            block(165.0, -18.0, Vec3::new(130.0, 0.0, 65.0)),
+        ],
+        background: Background::Solid(black()),
+        camera: Some(CameraSettings {
+            lookfrom: Vec3::new(278.0, 278.0, -800.0),
+            lookat: Vec3::new(278.0, 278.0, 0.0),
+            vfov: 40.0,
+            focus_dist: 10.0,
+            ..CameraSettings::default()
+        }),
+        ..SceneFile::default()
+    };
+    // The camera looks down +Z, so +X is on the left
+    scene
+        .add_quad(
+            Vec3::new(555.0, 0.0, 0.0),
+            Vec3::new(0.0, 555.0, 0.0),
+            Vec3::new(0.0, 0.0, 555.0),
+            red,
+        )
+        .add_quad(
+            Vec3::zero(),
+            Vec3::new(0.0, 555.0, 0.0),
+            Vec3::new(0.0, 0.0, 555.0),
+            green,
+        )
+        .add_quad(
+            Vec3::new(343.0, 554.0, 332.0),
+            Vec3::new(-130.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -105.0),
+            Material::emissive(Rgb::broadcast(15.0)),
+        )
+        .add_quad(
+            Vec3::zero(),
+            Vec3::new(555.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 555.0),
+            white.clone(),
+        )
+        .add_quad(
+            Vec3::broadcast(555.0),
+            Vec3::new(-555.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -555.0),
+            white.clone(),
+        )
+        .add_quad(
+            Vec3::new(0.0, 0.0, 555.0),
+            Vec3::new(555.0, 0.0, 0.0),
+            Vec3::new(0.0, 555.0, 0.0),
+            white,
+        );
+    scene
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_sphere() -> Sphere {
+        Sphere {
+            origin: Vec3::zero(),
+            radius: 1.0,
+            material: Material::metal(Rgb::broadcast(0.5), 0.0),
+            velocity: Vec3::zero(),
+        }
+    }
+
+    fn hit(origin: Vec3<f32>, direction: Vec3<f32>) -> Option<HitRecord> {
+        hit_sphere(
+            Ray::new(origin, direction, 0.0),
+            &unit_sphere(),
+            SHADOW_ACNE_FUDGE_CONSTANT,
+            f32::INFINITY,
+        )
+    }
+
+    #[test]
+    fn through_center_takes_near_root() {
+        let hit_record = hit(Vec3::new(0.0, 0.0, -5.0), Vec3::unit_z()).unwrap();
+        assert!((hit_record.distance - 4.0).abs() < 1e-5);
+        assert!((hit_record.intersection_point - Vec3::new(0.0, 0.0, -1.0)).magnitude() < 1e-5);
+        assert!((hit_record.surface_normal - -Vec3::unit_z()).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn negative_radius_points_normal_inward() {
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::unit_z(), 0.0);
+        let bubble = Sphere {
+            radius: -1.0,
+            ..unit_sphere()
+        };
+        let solid = hit_sphere(
+            ray,
+            &unit_sphere(),
+            SHADOW_ACNE_FUDGE_CONSTANT,
+            f32::INFINITY,
+        );
+        let hollow = hit_sphere(ray, &bubble, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY).unwrap();
+        assert!((hollow.distance - 4.0).abs() < 1e-5);
+        assert!((hollow.surface_normal - Vec3::unit_z()).magnitude() < 1e-5);
+        // Entering a bubble is leaving the glass around it
+        assert!(!hollow.front_face);
+        assert_eq!(hollow.uv, solid.unwrap().uv);
+    }
+
+    #[test]
+    fn tangent_ray_misses() {
+        assert!(hit(Vec3::new(0.0, 1.0, -5.0), Vec3::unit_z()).is_none());
+    }
+
+    #[test]
+    fn complete_miss() {
+        assert!(hit(Vec3::new(0.0, 2.0, -5.0), Vec3::unit_z()).is_none());
+        // Sphere entirely behind the ray
+        assert!(hit(Vec3::new(0.0, 0.0, 5.0), Vec3::unit_z()).is_none());
+    }
+
+    #[test]
+    fn inside_origin_takes_far_root() {
+        let hit_record = hit(Vec3::zero(), Vec3::unit_z()).unwrap();
+        assert!((hit_record.distance - 1.0).abs() < 1e-5);
+        // Normal still points outward, away from the ray origin
+        assert!((hit_record.surface_normal - Vec3::unit_z()).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn hit_under_fudge_distance_is_ignored() {
+        // Starting on the far surface: the only root left is at ~0
+        let origin = Vec3::new(0.0, 0.0, 1.0 - SHADOW_ACNE_FUDGE_CONSTANT / 2.0);
+        assert!(hit(origin, Vec3::unit_z()).is_none());
+    }
+
+    #[test]
+    fn obj_polygons_are_fanned() {
+        let text =
+            "# unit square\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1 -1//1\n";
+        let (faces, _) = parse_obj(text).unwrap();
+        assert_eq!(faces.len(), 2);
+        assert_eq!(
+            faces[0].vertices,
+            [Vec3::zero(), Vec3::unit_x(), Vec3::new(1.0, 1.0, 0.0)]
+        );
+        assert_eq!(
+            faces[1].vertices,
+            [Vec3::zero(), Vec3::new(1.0, 1.0, 0.0), Vec3::unit_y()]
+        );
+        assert_eq!(faces[0].normals, Some([Vec3::unit_z(); 3]));
+    }
+
+    #[test]
+    fn mesh_triangles_index_their_materials() {
+        let text = "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\nf 1 2 3\nusemtl red\nf 1 3 4\nusemtl blue\nf 1 2 4\nusemtl red\nf 2 3 4\n";
+        let (faces, names) = parse_obj(text).unwrap();
+        assert_eq!(names, ["red", "blue"]);
+        let indices: Vec<_> = faces.iter().map(|face| face.material).collect();
+        assert_eq!(indices, [0, 1, 2, 1]);
+
+        let red = Material::lambertian(Rgb::new(1.0, 0.0, 0.0));
+        let mesh = Mesh {
+            obj: ObjMesh {
+                path: PathBuf::new(),
+                faces: Arc::new(faces),
+                material_names: Arc::new(names),
+            },
+            material: Material::lambertian(Rgb::broadcast(0.5)),
+            materials: BTreeMap::from([("red".to_string(), red)]),
+            scale: 1.0,
+            rotate_y: 0.0,
+            translate: Vec3::zero(),
+        };
+        let object = mesh.mesh_object().unwrap();
+        let albedo = |origin: Vec3<f32>, direction: Vec3<f32>| {
+            let (_, material) = object
+                .hit(Ray::new(origin, direction, 0.0), 0.0, f32::INFINITY)
+                .unwrap();
+            material.albedo.value(Vec2::zero(), Vec3::zero())
+        };
+        // Faces in the z = 0 plane, the x = 0 plane and the slanted one
+        assert_eq!(
+            albedo(Vec3::new(0.2, 0.2, -1.0), Vec3::unit_z()),
+            Rgb::broadcast(0.5)
+        );
+        assert_eq!(
+            albedo(Vec3::new(-1.0, 0.2, 0.2), Vec3::unit_x()),
+            Rgb::new(1.0, 0.0, 0.0)
+        );
+        assert_eq!(
+            albedo(Vec3::broadcast(1.0), -Vec3::broadcast(1.0)),
+            Rgb::new(1.0, 0.0, 0.0)
+        );
+        // `blue` has no material in the scene and falls back
+        assert_eq!(object.materials.len(), 3);
+        let (indexed, copied) = object.memory();
+        assert!(indexed < copied, "{indexed} {copied}");
+    }
+
+    #[test]
+    fn obj_index_out_of_range_is_an_error() {
+        assert!(parse_obj("v 0 0 0\nv 1 0 0\nf 1 2 3\n").is_err());
+    }
+
+    /// Add `passes` passes of a tiny demo scene render to `accumulator`
+    fn accumulate(accumulator: &mut Accumulator, settings: &RenderSettings, passes: u32) {
+        let size = PhysicalSize::new(16, 12);
+        let scene = default_scene();
+        let world = scene.world();
+        let camera = scene.camera_settings().build(16.0 / 12.0);
+        for _ in 0..passes {
+            let seed = accumulator.next_seed(7);
+            let (pass, samples) = draw_linear(
+                size,
+                &world,
+                &camera,
+                settings,
+                seed,
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+            accumulator.add(&pass, samples);
+        }
+    }
+
+    #[test]
+    fn accumulated_mean_holds_over_millions_of_passes() {
+        let mut accumulator = Accumulator::new();
+        for pass in 0..2_000_000u64 {
+            let value = if pass % 2 == 0 { 0.1 } else { 0.3 };
+            accumulator.add(&[Rgb::broadcast(value)], 1);
+        }
+        assert_eq!(accumulator.passes, 2_000_000);
+        assert_eq!(accumulator.samples, 2_000_000);
+        let mean = accumulator.mean[0].r;
+        assert!((mean - 0.2).abs() < 1e-4, "{mean}");
+    }
+
+    #[test]
+    fn accumulation_converges_after_an_adaptive_pass_or_the_pass_cap() {
+        let settings = RenderSettings {
+            samples: 4,
+            max_samples: 8,
+            ..RenderSettings::default()
+        };
+        let adaptive = RenderSettings {
+            tolerance: Some(0.1),
+            ..settings
+        };
+        let pass = [Rgb::broadcast(0.5); 4];
+        let mut accumulator = Accumulator::new();
+        assert!(!accumulator.converged(&adaptive));
+        accumulator.add(&pass, 16);
+        // An adaptive pass stops at the tolerance by itself
+        assert!(accumulator.converged(&adaptive));
+        // Without one, passes go on well past `max_samples` per pixel
+        for _ in 1..MAX_PASSES {
+            assert!(!accumulator.converged(&settings));
+            accumulator.add(&pass, 16);
+        }
+        assert!(accumulator.converged(&settings));
+    }
+
+    /// Four accumulated passes rendered on `threads` threads
+    fn four_passes(threads: usize, tile_size: usize) -> Vec<u32> {
+        let settings = RenderSettings {
+            samples: 2,
+            tile_size,
+            ..RenderSettings::default()
+        };
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let mut accumulator = Accumulator::new();
+        pool.install(|| accumulate(&mut accumulator, &settings, 4));
+        accumulator.display_buffer(PhysicalSize::new(16, 12), &settings)
+    }
+
+    #[test]
+    fn degenerate_sizes_render() {
+        let scene = default_scene();
+        let world = scene.world();
+        let camera = scene.camera_settings().build(1.0);
+        let settings = RenderSettings::default();
+        let render = |width, height| {
+            let size = PhysicalSize::new(width, height);
+            draw_linear(size, &world, &camera, &settings, 0, &AtomicBool::new(false)).unwrap()
+        };
+        assert!(render(0, 0).0.is_empty());
+        let (one_pixel, _) = render(1, 1);
+        assert_eq!(one_pixel.len(), 1);
+        assert!(one_pixel[0].iter().all(|c| c.is_finite()));
+    }
+
+    #[test]
+    fn progress_tiles_match_batched_render() {
+        let scene = default_scene();
+        let world = scene.world();
+        let camera = scene.camera_settings().build(1.5);
+        let settings = RenderSettings {
+            samples: 2,
+            ..RenderSettings::default()
+        };
+        let size = PhysicalSize::new(45, 30);
+        let cancel = AtomicBool::new(false);
+        let streamed = std::sync::Mutex::new(vec![Rgb::broadcast(-1.0); 45 * 30]);
+        let (batched, _) =
+            draw_linear_with_progress(size, &world, &camera, &settings, 7, &cancel, &|tile| {
+                let mut streamed = streamed.lock().unwrap();
+                for (row, row_pixels) in tile.pixels.chunks(tile.width).enumerate() {
+                    let start = (tile.y0 + row) * 45 + tile.x0;
+                    streamed[start..start + tile.width].copy_from_slice(row_pixels);
+                }
+            })
+            .unwrap();
+        assert_eq!(streamed.into_inner().unwrap(), batched);
+    }
+
+    #[test]
+    fn srgb_matches_reference_values() {
+        for (linear, encoded) in [
+            (0.0, 0.0),
+            (0.002, 0.02584),
+            (0.18, 0.46135),
+            (0.5, 0.73536),
+            (1.0, 1.0),
+        ] {
+            assert!((linear_to_srgb(linear) - encoded).abs() < 1e-4, "{linear}");
+        }
+    }
+
+    #[test]
+    fn empty_world_renders_background() {
+        let background = Background::Gradient {
+            top: Rgb::new(0.5, 0.7, 1.0),
+            bottom: Rgb::broadcast(1.0),
+        };
+        let world = World::new(Vec::new(), Vec::new(), background.clone());
+        let camera = CameraSettings::default().build(4.0 / 3.0);
+        let settings = RenderSettings::default();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let ray = camera.get_ray(0.5, 0.5, &mut rng);
+        let (color, bounces) = ray_cast(ray, None, &world, &settings, &mut rng);
+        assert_eq!(color, background.color(ray.direction));
+        assert_eq!(bounces, 0);
+
+        let (width, height) = (40, 30);
+        let size = PhysicalSize::new(width as u32, height as u32);
+        let (pixels, _) =
+            draw_linear(size, &world, &camera, &settings, 0, &AtomicBool::new(false)).unwrap();
+        for (i, pixel) in pixels.into_iter().enumerate() {
+            let u = ((i % width) as f32 + 0.5) / (width - 1) as f32;
+            let v = ((i / width) as f32 + 0.5) / (height - 1) as f32;
+            let expected = background.color(camera.get_ray(u, v, &mut rng).direction);
+            // Samples are jittered over the pixel, which the gradient barely
+            // changes across
+            assert!(
+                (pixel - expected).iter().all(|c| c.abs() < 0.01),
+                "pixel {i}"
+            );
+        }
+    }
+
+    #[test]
+    fn draw_packs_linear_buffer() {
+        let scene = default_scene();
+        let world = scene.world();
+        let camera = scene.camera_settings().build(1.5);
+        let settings = RenderSettings {
+            seed: Some(3),
+            ..RenderSettings::default()
+        };
+        let size = PhysicalSize::new(30, 20);
+        let (linear, _) = render_to_buffer(size, &world, &camera, &settings);
+        let packed: Vec<_> = linear.iter().map(|&c| to_display(c, &settings)).collect();
+        assert_eq!(packed, draw(size, &world, &camera, &settings, 1).0);
+    }
+
+    #[test]
+    fn strided_draw_fills_in_from_the_coarse_image() {
+        let scene = default_scene();
+        let world = scene.world();
+        let camera = scene.camera_settings().build(2.0);
+        let settings = RenderSettings {
+            seed: Some(3),
+            ..RenderSettings::default()
+        };
+        let (coarse, _) = draw(PhysicalSize::new(4, 2), &world, &camera, &settings, 1);
+        let (strided, samples) = draw(PhysicalSize::new(15, 8), &world, &camera, &settings, 4);
+        assert_eq!(samples, 8 * settings.samples as u64);
+        assert_eq!(strided.len(), 15 * 8);
+        for (i, &pixel) in strided.iter().enumerate() {
+            let (x, y) = (i % 15, i / 15);
+            assert_eq!(pixel, coarse[(y / 4) * 4 + x / 4], "({x}, {y})");
+        }
+    }
+
+    #[test]
+    fn exr_round_trips() {
+        let size = PhysicalSize::new(5, 3);
+        let colors: Vec<_> = (0..15)
+            .map(|i| Rgb::new(i as f32 * 0.37, 1.0 / (i as f32 + 1.0), 12.5 - i as f32))
+            .collect();
+        let path = std::env::temp_dir().join(format!("fastcaster-{}.exr", std::process::id()));
+        save_exr(&path, &colors, size).unwrap();
+        let loaded = load_exr(&path);
+        fs::remove_file(&path).unwrap();
+        let (pixels, loaded_size) = loaded.unwrap();
+        assert_eq!(loaded_size, size);
+        let opaque: Vec<_> = colors.iter().map(|&c| Rgba::from_opaque(c)).collect();
+        assert_eq!(pixels, opaque);
+    }
+
+    #[test]
+    fn ggx_visible_normals_face_the_view() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let normal = Vec3::new(0.3, 1.0, -0.2).normalized();
+        let view = Vec3::new(1.0, 0.4, 0.5).normalized();
+        for _ in 0..1000 {
+            let microfacet = rng.rand_ggx_visible_normal(normal, view, 0.8);
+            assert!((microfacet.magnitude() - 1.0).abs() < 1e-4);
+            assert!(microfacet.dot(view) >= -1e-6);
+        }
+        let mirror = rng.rand_ggx_visible_normal(normal, view, 0.0);
+        assert!((mirror - normal).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn analytic_aa_blends_silhouettes() {
+        let mut scene = SceneFile {
+            background: Background::Solid(black()),
+            ..SceneFile::default()
+        };
+        scene.add_sphere(
+            Vec3::new(0.0, 0.0, -1.0),
+            0.5,
+            Material::emissive(Rgb::broadcast(1.0)),
+        );
+        let camera = scene.camera_settings().build(1.0);
+        let partial = |analytic_aa| {
+            let settings = RenderSettings {
+                samples: 1,
+                seed: Some(0),
+                analytic_aa,
+                ..RenderSettings::default()
+            };
+            let (colors, _) = render_to_buffer(
+                PhysicalSize::new(32, 32),
+                &scene.world(),
+                &camera,
+                &settings,
+            );
+            colors.iter().filter(|c| c.r > 0.0 && c.r < 1.0).count()
+        };
+        assert_eq!(partial(false), 0);
+        assert!(partial(true) > 16, "{}", partial(true));
+    }
+
+    #[test]
+    fn validate_flags_duplicate_and_empty_objects() {
+        for (name, build) in BUILTIN_SCENES {
+            assert_eq!(build().world().validate(), Vec::<String>::new(), "{name}");
+        }
+        let mut scene = default_scene();
+        let copy = scene.spheres[0].clone();
+        scene.spheres.push(copy);
+        scene.add_sphere(Vec3::new(0.0, 2.0, 0.0), 0.0, Material::dielectric(1.5));
+        let warnings = scene.world().validate();
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(warnings.iter().any(|w| w.contains("no size")));
+        assert!(warnings.iter().any(|w| w.contains("overlap")));
+    }
+
+    #[test]
+    fn builtin_scenes_render() {
+        for (name, _) in BUILTIN_SCENES {
+            let scene = builtin_scene(name).unwrap();
+            let camera = scene.camera_settings().build(1.0);
+            let settings = RenderSettings {
+                samples: 1,
+                seed: Some(0),
+                ..RenderSettings::default()
+            };
+            let (colors, _) =
+                render_to_buffer(PhysicalSize::new(8, 8), &scene.world(), &camera, &settings);
+            assert!(colors.iter().all(|c| is_finite_color(*c)), "{name}");
+        }
+        assert!(builtin_scene("list").is_none());
+    }
+
+    #[test]
+    fn dumped_scene_renders_the_same() {
+        let mut scene = cornell_box();
+        scene.settings = Some(RenderSettings {
+            samples: 2,
+            seed: Some(9),
+            tone_map: ToneMap::Reinhard,
+            ssaa: 2,
+            ..RenderSettings::default()
+        });
+        scene.size = Some((24, 16));
+        let loaded: SceneFile = ron::from_str(&scene.to_ron().unwrap()).unwrap();
+        assert_eq!(loaded.size, Some((24, 16)));
+        let render = |scene: &SceneFile| {
+            let (width, height) = scene.size.unwrap();
+            let camera = scene.camera_settings().build(width as f32 / height as f32);
+            let settings = scene.settings.unwrap();
+            draw(
+                PhysicalSize::new(width, height),
+                &scene.world(),
+                &camera,
+                &settings,
+                1,
+            )
+            .0
+        };
+        assert_eq!(render(&loaded), render(&scene));
+    }
+
+    #[test]
+    fn tile_ranges_merge_into_full_render() {
+        let scene = default_scene();
+        let world = scene.world();
+        let camera = scene.camera_settings().build(1.5);
+        let settings = RenderSettings {
+            samples: 2,
+            tile_size: 8,
+            filter_radius: 1.5,
+            ..RenderSettings::default()
+        };
+        let size = PhysicalSize::new(30, 20);
+        let render = |tile_range| {
+            let settings = RenderSettings {
+                tile_range,
+                ..settings
+            };
+            draw_linear(size, &world, &camera, &settings, 5, &AtomicBool::new(false))
+                .unwrap()
+                .0
+        };
+        let full = render(None);
+        let first = render(Some(TileRange { start: 0, end: 5 }));
+        let second = render(Some(TileRange { start: 5, end: 12 }));
+        // 4 tiles across, so the first five are the top row and one more
+        let merged: Vec<_> = (0..full.len())
+            .map(|i| {
+                let (x, y) = (i % 30, i / 30);
+                if y / 8 * 4 + x / 8 < 5 {
+                    first[i]
+                } else {
+                    second[i]
+                }
+            })
+            .collect();
+        assert_eq!(merged, full);
+    }
+
+    #[test]
+    fn non_finite_colors_are_flagged() {
+        let settings = RenderSettings {
+            color_space: ColorSpace::Srgb,
+            ..RenderSettings::default()
+        };
+        for bad in [
+            Rgb::new(f32::NAN, 0.5, 0.5),
+            Rgb::new(0.5, f32::INFINITY, 0.5),
+        ] {
+            assert_eq!(to_display(bad, &settings), NON_FINITE_PIXEL.to_u32());
+        }
+        assert_ne!(
+            to_display(Rgb::broadcast(1.0), &settings),
+            NON_FINITE_PIXEL.to_u32()
+        );
+    }
+
+    #[test]
+    fn blue_noise_spreads_neighbours_apart() {
+        const N: usize = BLUE_NOISE_SIZE;
+        let texture = blue_noise();
+        // Every rank is used exactly once
+        let mut ranks: Vec<_> = texture
+            .iter()
+            .map(|v| (v.x * (N * N) as f32) as usize)
+            .collect();
+        ranks.sort_unstable();
+        assert!(ranks.iter().enumerate().all(|(i, &r)| i == r));
+        // White noise neighbours differ by 1/3 on average
+        let difference = (0..N * N)
+            .map(|i| (texture[i].x - texture[i / N * N + (i + 1) % N].x).abs())
+            .sum::<f32>()
+            / (N * N) as f32;
+        assert!(difference > 0.38, "{difference}");
+    }
+
+    #[test]
+    fn exposure_scales_linearly() {
+        let settings = RenderSettings {
+            gamma: 1.0,
+            exposure: 2.0,
+            ..RenderSettings::default()
+        };
+        let dim = Rgb::new(0.1, 0.2, 0.3);
+        let unexposed = RenderSettings {
+            exposure: 1.0,
+            ..settings
+        };
+        assert_eq!(
+            to_display(dim, &settings),
+            to_display(dim * 2.0, &unexposed)
+        );
+    }
+
+    #[test]
+    fn wide_footprints_filter_textures_to_their_average() {
+        let checker = Texture::Checker {
+            even: Rgb::broadcast(0.0),
+            odd: Rgb::broadcast(1.0),
+            scale: 1.0,
+        };
+        let p = Vec3::new(0.3, 0.0, 0.3);
+        assert_eq!(checker.value(Vec2::zero(), p), Rgb::broadcast(0.0));
+        let distant = Footprint {
+            dpdx: Vec3::new(40.0, 0.0, 0.0),
+            dpdy: Vec3::new(0.0, 0.0, 40.0),
+            ..Footprint::default()
+        };
+        let gray = checker.filtered_value(Vec2::zero(), p, distant);
+        assert!((gray.r - 0.5).abs() < 0.05, "{gray:?}");
+
+        let image = RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([if (x + y) % 2 == 0 { 0 } else { 255 }; 3])
+        });
+        let texture = ImageTexture::from_image(PathBuf::new(), &image);
+        assert_eq!(texture.levels.len(), 5);
+        let minified = Footprint {
+            duvdx: Vec2::new(1.0, 0.0),
+            duvdy: Vec2::new(0.0, 1.0),
+            ..Footprint::default()
+        };
+        let average = texture.filtered_value(Vec2::broadcast(0.5), minified);
+        assert_eq!(average, Rgb::broadcast(0.5));
+    }
+
+    #[test]
+    fn grid_lines_fall_on_multiples_of_the_spacing() {
+        let grid = Texture::Grid {
+            background: Rgb::broadcast(0.0),
+            line: Rgb::broadcast(1.0),
+            spacing: 2.0,
+            major_every: 5,
+        };
+        let at = |x: f32, z: f32| grid.value(Vec2::zero(), Vec3::new(x, 0.0, z)).r;
+        assert_eq!(at(4.0, 1.0), 1.0);
+        assert_eq!(at(1.0, -6.0), 1.0);
+        assert_eq!(at(1.0, 1.0), 0.0);
+        // Major lines are wider than minor ones
+        assert_eq!(at(10.05, 1.0), 1.0);
+        assert_eq!(at(4.05, 1.0), 0.0);
+        let distant = Footprint {
+            dpdx: Vec3::new(40.0, 0.0, 0.0),
+            dpdy: Vec3::new(0.0, 0.0, 40.0),
+            ..Footprint::default()
+        };
+        let faded = grid.filtered_value(Vec2::zero(), Vec3::new(1.0, 0.0, 1.0), distant);
+        assert!(faded.r > 0.0 && faded.r < 0.2, "{faded:?}");
+    }
+
+    #[test]
+    fn direct_and_indirect_light_add_up_to_the_render() {
+        let scene = default_scene();
+        let world = scene.world();
+        let camera = scene.camera_settings().build(1.0);
+        let render = |lighting| {
+            let settings = RenderSettings {
+                samples: 2,
+                seed: Some(4),
+                lighting,
+                ..RenderSettings::default()
+            };
+            render_to_buffer(PhysicalSize::new(12, 12), &world, &camera, &settings).0
+        };
+        let full = render(Lighting::All);
+        let direct = render(Lighting::Direct);
+        let indirect = render(Lighting::Indirect);
+        assert!(indirect.iter().any(|c| c.r > 0.0));
+        assert_ne!(direct, full);
+        for ((full, direct), indirect) in full.iter().zip(&direct).zip(&indirect) {
+            let difference = (*full - (*direct + *indirect)).map(f32::abs);
+            assert!(
+                difference.reduce_partial_max() <= 1e-4 * full.reduce_partial_max().max(1.0),
+                "{full:?} {direct:?} {indirect:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn auto_exposure_brings_the_median_to_middle_gray() {
+        // Mostly dim pixels with a few bright ones the median should ignore
+        let mut colors = vec![Rgb::broadcast(0.02); 90];
+        colors.extend([Rgb::broadcast(50.0); 10]);
+        colors.push(Rgb::new(f32::NAN, 0.0, 0.0));
+        colors.extend([Rgb::broadcast(0.0); 200]);
+        let histogram = luminance_histogram(&colors);
+        assert_eq!(histogram.iter().sum::<u64>(), 100);
+        let median = median_luminance(&histogram).unwrap();
+        assert!((median / 0.02 - 1.0).abs() < 0.05, "{median}");
+
+        let settings = RenderSettings {
+            auto_exposure: true,
+            ..RenderSettings::default()
+        };
+        let (exposed, ev) = settings.exposed_for(&colors);
+        assert!((0.02 * exposed.exposure / MIDDLE_GRAY - 1.0).abs() < 0.05);
+        assert!((ev.unwrap() - (MIDDLE_GRAY / 0.02).log2()).abs() < 0.1);
+        assert_eq!(settings.exposed_for(&[Rgb::broadcast(0.0); 4]).1, None);
+        let manual = RenderSettings::default();
+        assert_eq!(manual.exposed_for(&colors).0.exposure, 1.0);
+    }
+
+    #[test]
+    fn equirectangular_renders_wrap_at_the_side_edges() {
+        let mut scene = default_scene();
+        scene.camera = Some(CameraSettings {
+            projection: Projection::Equirectangular,
+            ..scene.camera_settings()
+        });
+        let camera = scene.camera_settings().build(2.0);
+        let settings = RenderSettings {
+            samples: 16,
+            seed: Some(0),
+            ..RenderSettings::default()
+        };
+        let (width, height) = (64, 32);
+        let (colors, _) = render_to_buffer(
+            PhysicalSize::new(width, height),
+            &scene.world(),
+            &camera,
+            &settings,
+        );
+        let width = width as usize;
+        let column_difference = |a: usize, b: usize| {
+            (0..height as usize)
+                .map(|y| {
+                    let difference = colors[y * width + a] - colors[y * width + b];
+                    difference.map(f32::abs).sum()
+                })
+                .sum::<f32>()
+        };
+        let seam = column_difference(0, width - 1);
+        let interior = (1..width - 1)
+            .map(|x| column_difference(x - 1, x))
+            .sum::<f32>()
+            / (width - 2) as f32;
+        assert!(seam <= 2.0 * interior, "{seam} vs {interior}");
+
+        // Straight ahead is the middle of the image, straight behind the
+        // edges
+        let mut rng = StdRng::seed_from_u64(0);
+        let forward = (camera.get_ray(0.5, 0.5, &mut rng).direction - camera.forward).magnitude();
+        let behind = (camera.get_ray(0.0, 0.5, &mut rng).direction + camera.forward).magnitude();
+        assert!(forward < 1e-6 && behind < 1e-6);
+    }
+
+    #[test]
+    fn fisheye_edges_are_half_the_fov_off_axis() {
+        let camera = CameraSettings {
+            projection: Projection::Fisheye { fov: 180.0 },
+            ..CameraSettings::default()
+        }
+        .build(1.0);
+        let mut rng = StdRng::seed_from_u64(0);
+        let top = camera.get_ray(0.5, 0.0, &mut rng).direction;
+        assert!(top.dot(camera.forward).abs() < 1e-6);
+        assert!(top.y > 0.99);
+    }
+
+    #[test]
+    fn stereo_eyes_converge_and_compose() {
+        let camera = CameraSettings {
+            lookfrom: Vec3::new(0.0, 1.0, 3.0),
+            lookat: Vec3::new(0.0, 1.0, 0.0),
+            ..CameraSettings::default()
+        };
+        let (left, right) = (camera.stereo_eye(-0.03, 3.0), camera.stereo_eye(0.03, 3.0));
+        assert_eq!(left.lookat, camera.lookat);
+        assert_eq!(right.lookat, camera.lookat);
+        assert!((left.lookfrom - Vec3::new(-0.03, 1.0, 3.0)).magnitude() < 1e-6);
+        assert!((right.lookfrom - Vec3::new(0.03, 1.0, 3.0)).magnitude() < 1e-6);
+
+        let size = PhysicalSize::new(2, 1);
+        let (left, right) = ([0x112233, 0x445566], [0x778899, 0xaabbcc]);
+        let (sbs, sbs_size) = compose_stereo(&left, &right, size, StereoLayout::SideBySide);
+        assert_eq!(sbs_size, PhysicalSize::new(4, 1));
+        assert_eq!(sbs, [0x112233, 0x445566, 0x778899, 0xaabbcc]);
+        let (anaglyph, _) = compose_stereo(&left, &right, size, StereoLayout::Anaglyph);
+        assert_eq!(anaglyph, [0x118899, 0x44bbcc]);
+    }
+
+    #[test]
+    fn positions_are_first_hits_with_a_sentinel_on_background() {
+        let mut scene = SceneFile::default();
+        scene.add_sphere(
+            Vec3::new(0.0, 0.0, -1.0),
+            0.5,
+            Material::lambertian(Rgb::broadcast(0.5)),
+        );
+        let camera = scene.camera_settings().build(1.0);
+        let size = PhysicalSize::new(33, 33);
+        let positions = render_positions(size, &scene.world(), &camera, &RenderSettings::default());
+        assert_eq!(positions[0], Rgb::broadcast(NO_HIT_POSITION));
+        let center = Vec3::from(positions[16 * 33 + 16]);
+        assert!((center.distance(Vec3::new(0.0, 0.0, -1.0)) - 0.5).abs() < 1e-3);
+        assert!(center.z > -1.0);
+    }
+
+    #[test]
+    fn truncation_estimate_lights_a_closed_mirror_box() {
+        // No light gets into the box, so only the estimate can brighten it
+        let mut scene = SceneFile {
+            quads: quad_box(
+                Vec3::broadcast(-1.0),
+                Vec3::broadcast(1.0),
+                &Material::metal(Rgb::broadcast(0.9), 0.0),
+            ),
+            ..SceneFile::default()
+        };
+        scene.add_sphere(Vec3::zero(), 0.1, Material::lambertian(Rgb::broadcast(0.5)));
+        let world = scene.world();
+        let ray = Ray::new(Vec3::new(0.3, 0.2, 0.0), Vec3::new(1.0, 0.3, 0.2), 0.0);
+        let render = |truncation_estimate: bool| {
+            let settings = RenderSettings {
+                max_depth: 8,
+                rr_min_bounces: 9,
+                truncation_estimate,
+                ..RenderSettings::default()
+            };
+            ray_cast(ray, None, &world, &settings, &mut StdRng::seed_from_u64(0))
+        };
+        let (off, bounces) = render(false);
+        assert_eq!((off, bounces), (Rgb::broadcast(0.0), 8));
+        let (on, _) = render(true);
+        assert!(on.r > 0.0 && on.b > on.r, "{on:?}");
+    }
+
+    #[test]
+    fn rough_white_metal_passes_the_furnace_test() {
+        // Under a uniform white sky a white metal that loses no energy
+        // vanishes, whatever its roughness
+        let furnace = |fuzz: f32, single_scatter_metal: bool| {
+            let mut scene = SceneFile {
+                background: Background::Solid(Rgb::broadcast(1.0)),
+                ..SceneFile::default()
+            };
+            scene.add_sphere(
+                Vec3::zero(),
+                1.0,
+                Material::metal(Rgb::broadcast(1.0), fuzz),
+            );
+            let world = scene.world();
+            let settings = RenderSettings {
+                single_scatter_metal,
+                ..RenderSettings::default()
+            };
+            let mut rng = StdRng::seed_from_u64(0);
+            let n = 20_000;
+            let total: f32 = (0..n)
+                .map(|_| {
+                    let offset = rng.rand_in_unit_disk();
+                    let ray = Ray::new(offset + Vec3::unit_z() * 5.0, -Vec3::unit_z(), 0.0);
+                    ray_cast(ray, None, &world, &settings, &mut rng).0.g
+                })
+                .sum();
+            total / n as f32
+        };
+        for fuzz in [0.0, 0.3, 0.6, 1.0] {
+            let mean = furnace(fuzz, false);
+            assert!((mean - 1.0).abs() < 0.02, "fuzz {fuzz}: {mean}");
+        }
+        let single = furnace(1.0, true);
+        assert!(single < 0.9, "{single}");
+    }
+
+    #[test]
+    fn env_lighting_only_hides_the_background_behind_what_it_lights() {
+        let mut scene = SceneFile {
+            background: Background::Solid(Rgb::broadcast(1.0)),
+            ..SceneFile::default()
+        };
+        scene.add_sphere(Vec3::zero(), 1.0, Material::metal(Rgb::broadcast(1.0), 0.0));
+        let world = scene.world();
+        let settings = RenderSettings {
+            env_lighting_only: true,
+            ..RenderSettings::default()
+        };
+        let cast = |origin: Vec3<f32>| {
+            let ray = Ray::new(origin, -Vec3::unit_z(), 0.0);
+            ray_cast(ray, None, &world, &settings, &mut StdRng::seed_from_u64(0)).0
+        };
+        assert_eq!(cast(Vec3::new(2.0, 0.0, 5.0)), Rgb::broadcast(0.0));
+        assert_eq!(cast(Vec3::new(0.0, 0.5, 5.0)), Rgb::broadcast(1.0));
+    }
+
+    #[test]
+    fn scene_builder_reports_everything_wrong_with_the_scene() {
+        let material = || Material::lambertian(Rgb::broadcast(0.5));
+        let scene = SceneBuilder::new()
+            .add_sphere(Vec3::zero(), 0.5, material())
+            .camera(CameraSettings::default())
+            .size(32, 16)
+            .build()
+            .unwrap();
+        assert_eq!(scene.size, PhysicalSize::new(32, 16));
+        assert!(scene.world.bounding_box().is_some());
+        let error = SceneBuilder::new()
+            .add_sphere(Vec3::zero(), 0.0, material())
+            .add_mesh("missing.obj", material())
+            .size(32, 0)
+            .build()
+            .err()
+            .unwrap();
+        for problem in ["missing.obj", "no camera", "32x0", "no size"] {
+            assert!(error.contains(problem), "{problem} not in {error}");
+        }
+    }
+
+    #[test]
+    fn rays_scatter_in_smoke_as_often_as_its_thickness_says() {
+        let mut scene = SceneFile::default();
+        scene.media.push(Medium {
+            boundary: Sphere {
+                origin: Vec3::zero(),
+                radius: 1.0,
+                material: Material::lambertian(Rgb::broadcast(0.5)),
+                velocity: Vec3::zero(),
+            },
+            density: 0.5,
+            color: Rgb::broadcast(0.5),
+        });
+        let world = scene.world();
+        // Through the middle, 2 units of smoke
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), -Vec3::unit_z(), 0.0);
+        let mut rng = StdRng::seed_from_u64(0);
+        let n = 20_000;
+        let scattered = (0..n)
+            .filter(|_| {
+                world
+                    .hit_with_media(ray, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY, &mut rng)
+                    .is_some_and(|(hit, _)| (4.0..=6.0).contains(&hit.distance))
+            })
+            .count();
+        let expected = 1.0 - (-1.0f32).exp();
+        let fraction = scattered as f32 / n as f32;
+        assert!(
+            (fraction - expected).abs() < 0.01,
+            "{fraction} vs {expected}"
+        );
+        assert!(world
+            .hit(ray, SHADOW_ACNE_FUDGE_CONSTANT, f32::INFINITY)
+            .is_none());
+    }
+
+    #[test]
+    fn roughness_and_ior_maps_replace_the_constants() {
+        let render = |material: Material| {
+            let mut scene = SceneFile::default();
+            scene.add_sphere(Vec3::new(0.0, 0.0, -2.0), 0.5, material);
+            let world = scene.world();
+            let ray = Ray::new(Vec3::new(0.0, 0.3, 0.0), -Vec3::unit_z(), 0.0);
+            (0..16)
+                .map(|seed| {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    ray_cast(ray, None, &world, &RenderSettings::default(), &mut rng).0
+                })
+                .collect::<Vec<_>>()
+        };
+        let all_same = |colors: &[Rgb<f32>]| colors.iter().all(|&color| color == colors[0]);
+        // A mirror reflects the same way every time, a matte map scatters it
+        let polished = Material {
+            roughness: Some(Texture::Solid(Rgb::broadcast(0.0))),
+            ..Material::metal(Rgb::broadcast(0.8), 1.0)
+        };
+        assert!(all_same(&render(polished)));
+        let matte = Material {
+            roughness: Some(Texture::Solid(Rgb::broadcast(1.0))),
+            ..Material::metal(Rgb::broadcast(0.8), 0.0)
+        };
+        assert!(!all_same(&render(matte)));
+
+        // Glass mapped to an IOR of 1 doesn't bend or reflect light at all
+        let background = Background::default().color(-Vec3::unit_z());
+        let air = Material {
+            ior: Some(Texture::Solid(Rgb::broadcast(0.0))),
+            ..Material::dielectric(1.5)
+        };
+        for color in render(air) {
+            assert!(
+                (color - background).map(f32::abs).reduce_partial_max() < 1e-4,
+                "{color:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn comparing_an_image_with_itself_and_with_noise() {
+        let image = RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 8) as u8, 128])
+        });
+        let same = compare_images(&image, &image);
+        assert_eq!(same.mae, 0.0);
+        assert_eq!(same.psnr, f32::INFINITY);
+        assert!((same.ssim - 1.0).abs() < 1e-4);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut noisy = image.clone();
+        for pixel in noisy.pixels_mut() {
+            for c in pixel.0.iter_mut() {
+                *c = c.saturating_add_signed(rng.gen_range(-20..=20));
+            }
+        }
+        let different = compare_images(&image, &noisy);
+        assert!(different.mae > 0.0 && different.psnr.is_finite());
+        assert!(different.ssim < 0.99 && different.ssim > 0.0);
+        assert_eq!(
+            difference_image(&image, &image).as_raw().iter().max(),
+            Some(&0)
+        );
+    }
+
+    #[test]
+    fn thin_glass_shell_barely_bends_light() {
+        // Hollow sphere with walls much thinner than the acne epsilon
+        let mut scene = SceneFile::default();
+        let glass = Material::dielectric(1.5);
+        scene.add_sphere(Vec3::new(0.0, 0.0, -2.0), 0.5, glass.clone());
+        scene.add_sphere(Vec3::new(0.0, 0.0, -2.0), -0.4999, glass);
+        let world = scene.world();
+        let settings = RenderSettings::default();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut through = 0;
+        for _ in 0..2000 {
+            let ray = Ray::new(Vec3::new(0.0, 0.3, 0.0), -Vec3::unit_z(), 0.0);
+            let (color, bounces) = ray_cast(ray, None, &world, &settings, &mut rng);
+            // Passing straight through takes all four walls, and the
+            // gradient background shows whether it came out bent
+            if bounces == 4 {
+                through += 1;
+                let expected = world.background.color(-Vec3::unit_z());
+                assert!(
+                    (color - expected).map(f32::abs).reduce_partial_max() < 1e-3,
+                    "{color:?}"
+                );
+            }
+        }
+        assert!(through > 1000, "{through}");
+    }
+
+    #[test]
+    fn sampling_a_sunny_environment_cuts_noise() {
+        // Dim sky with one bright texel up in the northern sky
+        let image = Rgb32FImage::from_fn(32, 16, |x, y| {
+            if (x, y) == (8, 4) {
+                image::Rgb([2000.0; 3])
+            } else {
+                image::Rgb([0.05; 3])
+            }
+        });
+        let map = EnvironmentMap::from_image(PathBuf::new(), image);
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let direction = map.sample(&mut rng).unwrap();
+            assert!(map.pdf(direction) > 0.0);
+        }
+
+        let mut scene = SceneFile {
+            background: Background::EnvMap(map),
+            ..SceneFile::default()
+        };
+        scene.add_sphere(
+            Vec3::new(0.0, -100.0, 0.0),
+            100.0,
+            Material::lambertian(Rgb::broadcast(0.5)),
+        );
+        let world = scene.world();
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), -Vec3::unit_y(), 0.0);
+        let estimate = |nee: bool| {
+            let settings = RenderSettings {
+                max_depth: 2,
+                nee,
+                ..RenderSettings::default()
+            };
+            let mut rng = StdRng::seed_from_u64(1);
+            let samples: Vec<f32> = (0..20000)
+                .map(|_| ray_cast(ray, None, &world, &settings, &mut rng).0.g)
+                .collect();
+            let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+            let variance =
+                samples.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / samples.len() as f32;
+            (mean, variance)
+        };
+        let (sampled_mean, sampled_variance) = estimate(true);
+        let (bsdf_mean, bsdf_variance) = estimate(false);
+        assert!(
+            (sampled_mean - bsdf_mean).abs() < 0.1 * bsdf_mean,
+            "{sampled_mean} vs {bsdf_mean}"
+        );
+        assert!(
+            sampled_variance < bsdf_variance / 10.0,
+            "{sampled_variance} vs {bsdf_variance}"
+        );
+    }
+
+    #[test]
+    fn preetham_sky_is_blue_overhead_and_brightest_by_the_sun() {
+        let sky = PreethamSky::new(SkyParams::default());
+        let zenith = sky.sky_radiance(Vec3::unit_y());
+        assert!(zenith.b > zenith.r);
+        let near_sun = sky.sky_radiance(sky.sun_direction + Vec3::new(0.0, 0.05, 0.0));
+        let away = sky.sky_radiance(Vec3::new(0.0, 0.7, 0.7));
+        assert!(luminance(near_sun) > luminance(away));
+        assert!(luminance(sky.sun_radiance(sky.sun_direction)) > 100.0 * luminance(near_sun));
+        assert_eq!(sky.sun_radiance(Vec3::unit_y()), Rgb::broadcast(0.0));
+        // A low sun shines through more air and comes out redder
+        let sunset = PreethamSky::new(SkyParams {
+            sun_elevation: 5.0,
+            ..SkyParams::default()
+        });
+        assert!(sunset.sun.r / sunset.sun.b > sky.sun.r / sky.sun.b);
+    }
+
+    #[test]
+    fn bvh_visits_grow_where_the_ray_meets_objects() {
+        let mut scene = SceneFile::default();
+        for i in 0..16 {
+            scene.add_sphere(
+                Vec3::new(i as f32 - 8.0, 0.0, -5.0),
+                0.4,
+                Material::lambertian(Rgb::broadcast(0.5)),
+            );
+        }
+        let world = scene.world();
+        let away = Ray::new(Vec3::zero(), Vec3::unit_z(), 0.0);
+        assert_eq!(world.bvh_visits(away), 1);
+        let through = Ray::new(Vec3::zero(), -Vec3::unit_z(), 0.0);
+        assert!(world.bvh_visits(through) > 4);
+        let (cold, hot) = (
+            DebugView::Bvh.cast(away, &world),
+            DebugView::Bvh.cast(through, &world),
+        );
+        assert!(hot.b < cold.b && hot.g > cold.g);
+    }
+
+    #[test]
+    fn coverage_is_zero_on_background_and_one_inside() {
+        let mut scene = SceneFile::default();
+        scene.add_sphere(
+            Vec3::new(0.0, 0.0, -1.0),
+            0.5,
+            Material::lambertian(Rgb::broadcast(0.5)),
+        );
+        let camera = scene.camera_settings().build(1.0);
+        let settings = RenderSettings {
+            samples: 16,
+            seed: Some(0),
+            ..RenderSettings::default()
+        };
+        let size = PhysicalSize::new(32, 32);
+        let coverage = render_coverage(size, &scene.world(), &camera, &settings);
+        assert_eq!(coverage[0], 0.0);
+        assert_eq!(coverage[31 * 32 + 31], 0.0);
+        assert_eq!(coverage[16 * 32 + 16], 1.0);
+        // The silhouette is antialiased
+        assert!(coverage.iter().any(|&c| c > 0.0 && c < 1.0));
+    }
+
+    #[test]
+    fn polygon_lens_samples_stay_inside() {
+        let mut rng = StdRng::seed_from_u64(0);
+        // Distance from the center to the middle of a hexagon's sides
+        let apothem = (std::f32::consts::PI / 6.0).cos();
+        for _ in 0..1000 {
+            let p = rng.rand_in_regular_polygon(6);
+            let angle = p.y.atan2(p.x) - std::f32::consts::FRAC_PI_2;
+            let to_side =
+                angle.rem_euclid(std::f32::consts::TAU / 6.0) - std::f32::consts::PI / 6.0;
+            assert!(p.magnitude() * to_side.cos() <= apothem + 1e-5);
+        }
+    }
+
+    #[test]
+    fn straight_down_view_falls_back_to_another_up() {
+        let settings = CameraSettings {
+            lookfrom: Vec3::new(0.0, 5.0, 0.0),
+            lookat: Vec3::zero(),
+            ..CameraSettings::default()
+        };
+        let camera = settings.build(1.5);
+        let mut rng = StdRng::seed_from_u64(0);
+        for (s, t) in [(0.5, 0.5), (0.0, 0.0), (1.0, 0.3)] {
+            let ray = camera.get_ray(s, t, &mut rng);
+            assert!(ray.direction.iter().all(|c| c.is_finite()), "{ray:?}");
+            assert!(ray.direction.y < 0.0);
+        }
+        let center = camera.get_ray(0.5, 0.5, &mut rng).direction.normalized();
+        assert!((center - -Vec3::unit_y()).magnitude() < 1e-5);
+        let (_, right, up) = settings.basis();
+        assert!(right.iter().chain(up.iter()).all(|c| c.is_finite()));
+    }
+
+    #[test]
+    fn accumulation_ignores_scheduling() {
+        assert_eq!(four_passes(4, 3), four_passes(1, 32));
+    }
+
+    #[test]
+    fn accumulation_restarts_after_reset() {
+        // A coalesced burst of requests resets the accumulator, after which
+        // the passes must replay from the base seed
+        let settings = RenderSettings {
+            samples: 2,
+            ..RenderSettings::default()
+        };
+        let mut accumulator = Accumulator::new();
+        accumulate(&mut accumulator, &settings, 3);
+        accumulator.reset();
+        accumulate(&mut accumulator, &settings, 4);
+        assert_eq!(
+            accumulator.display_buffer(PhysicalSize::new(16, 12), &settings),
+            four_passes(1, 32)
+        );
+    }
+}
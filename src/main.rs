@@ -1,318 +1,1642 @@
-use std::{sync::mpsc::channel, thread};
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt::{self, Display},
+    fs,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use rand::{rngs::StdRng, Rng, SeedableRng};
-use rayon::prelude::{IntoParallelIterator, ParallelExtend, ParallelIterator};
+use image::{GrayImage, RgbImage, RgbaImage};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use softbuffer::GraphicsContext;
-use vek::{Lerp, Ray, Rgb, Vec3};
+use vek::{Rgb, Rgba, Vec3};
 use winit::{
     dpi::PhysicalSize,
-    event::{Event, StartCause, WindowEvent},
-    event_loop::{ControlFlow, EventLoopBuilder},
+    event::{
+        DeviceEvent, ElementState, Event, KeyboardInput, MouseButton, StartCause, VirtualKeyCode,
+        WindowEvent,
+    },
+    event_loop::{ControlFlow, EventLoopBuilder, EventLoopProxy},
     window::WindowBuilder,
 };
 
+use fastcaster::{
+    builtin_scene, compare_images, compose_stereo, default_scene, difference_image, draw,
+    draw_linear_with_progress, load_exr, mesh_stats, pack_for_display, render_coverage,
+    render_positions, render_stats, render_to_buffer, save_exr, save_partial_exr, Accumulator,
+    Background, Camera, CameraSettings, Crop, DebugView, Material, Mesh, ObjMesh, Pixel,
+    PreethamSky, Projection, RenderSettings, SceneFile, SkyParams, StereoLayout, Tile, TileRange,
+    World, BUILTIN_SCENES,
+};
+
 const WIDTH: usize = 400;
 const HEIGHT: usize = 300;
+/// Pixels apart the quick pass shown before the first full pass are, across
+/// and down
+const COARSE_STRIDE: u32 = 8;
 
-#[derive(Debug, Clone, Copy)]
-struct Pixel {
-    red: u8,
-    green: u8,
-    blue: u8,
+#[derive(Debug)]
+/// A finished image, the size it was rendered at and how it got there
+struct ThreadRedrawCompleteEvent {
+    buffer: Vec<u32>,
+    width: u32,
+    height: u32,
+    /// Samples taken by the latest pass
+    pass_samples: u64,
+    /// Samples taken by every pass that went into `buffer`
+    accumulated_samples: u64,
+    /// How long the latest pass took
+    elapsed: Duration,
+    mode: RenderMode,
+    /// Stops auto exposure adjusted `buffer` by, if it is on
+    exposure_ev: Option<f32>,
 }
 
-#[derive(Clone, Copy)]
-enum MaterialType {
-    Lambertian,
-    Metal(f32),
+/// Packed pixels of one tile of a pass that is still rendering
+#[derive(Debug)]
+struct TileEvent {
+    /// Size of the whole image the tile belongs to
+    size: PhysicalSize<u32>,
+    x0: usize,
+    y0: usize,
+    width: usize,
+    pixels: Vec<u32>,
 }
 
-#[derive(Clone, Copy)]
-struct Material {
-    color: Rgb<f32>,
-    mat_type: MaterialType,
+/// What the render thread and the `--serve` reader send the event loop
+#[derive(Debug)]
+enum RenderEvent {
+    Tile(TileEvent),
+    Complete(ThreadRedrawCompleteEvent),
+    Serve(ServeRequest),
 }
 
-#[derive(Clone, Copy)]
-struct Sphere {
-    origin: vek::Vec3<f32>,
-    radius: f32,
-    material: Material,
+/// One line of JSON read from stdin under `--serve`, like
+/// `{"command": "set_samples", "samples": 16}`. Each gets a [`ServeResponse`]
+/// line on stdout
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum ServeRequest {
+    /// Move the camera. Fields left out keep their current value. Vectors
+    /// are objects, like `{"x": 0, "y": 1, "z": 0}`
+    SetCamera {
+        lookfrom: Option<Vec3<f32>>,
+        lookat: Option<Vec3<f32>>,
+        vup: Option<Vec3<f32>>,
+        vfov: Option<f32>,
+    },
+    /// Samples per pixel of each accumulated pass
+    SetSamples { samples: usize },
+    /// Start accumulating again from scratch
+    Render,
+    /// Write the image on screen to a PNG, like F12
+    Save { path: PathBuf },
 }
-struct World<'a> {
-    spheres: &'a [Sphere],
+
+#[derive(Debug, Serialize)]
+struct ServeResponse {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
 }
 
-impl Pixel {
-    /// Turn pixel into an RGB u32. R high, top padding
-    fn to_u32(self) -> u32 {
-        let r_channel = (self.red as u32) << 16;
-        let g_channel = (self.green as u32) << 8;
-        let b_channel = (self.blue as u32) << 0;
-        r_channel | g_channel | b_channel
+impl ServeResponse {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            error: None,
+        }
     }
 
-    /// Create a pixel from an RGB vec in the [0,1] range
-    fn from_vek_color(v: Rgb<f32>) -> Self {
+    fn error(message: String) -> Self {
         Self {
-            red: (v.r.clamp(0.0, 1.0) * 255.99) as u8,
-            green: (v.g.clamp(0.0, 1.0) * 255.99) as u8,
-            blue: (v.b.clamp(0.0, 1.0) * 255.99) as u8,
+            ok: false,
+            error: Some(message),
         }
     }
 }
 
-#[allow(dead_code)]
-fn visualize_normal(normal: Vec3<f32>) -> Rgb<f32> {
-    (normal / 2.0 + 0.5).into()
+impl fmt::Display for ServeResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&serde_json::to_string(self).map_err(|_| fmt::Error)?)
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
-struct HitRecord {
-    intersection_point: Vec3<f32>,
-    surface_normal: Vec3<f32>,
-    distance: f32,
+/// Forward commands from stdin to the event loop until stdin closes or the
+/// event loop does. Lines that don't parse are answered right away
+fn read_serve_requests(proxy: EventLoopProxy<RenderEvent>) {
+    for line in std::io::stdin().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(request) => {
+                if proxy.send_event(RenderEvent::Serve(request)).is_err() {
+                    break;
+                }
+            }
+            Err(e) => println!("{}", ServeResponse::error(format!("invalid command: {e}"))),
+        }
+    }
 }
 
-const SHADOW_ACNE_FUDGE_CONSTANT: f32 = 0.001;
-
-trait RandVec: Rng {
-    fn rand_vec3(&mut self) -> Vec3<f32> {
-        Vec3::new(
-            self.gen_range(-1.0..1.0),
-            self.gen_range(-1.0..1.0),
-            self.gen_range(-1.0..1.0),
+impl ThreadRedrawCompleteEvent {
+    fn window_title(&self) -> String {
+        let pixels = self.width as f64 * self.height as f64;
+        let spp = |samples: u64| (samples as f64 / pixels * 10.0).round() / 10.0;
+        let exposure = self
+            .exposure_ev
+            .map(|ev| format!(" — {ev:+.1} EV"))
+            .unwrap_or_default();
+        format!(
+            "fastcaster — {} — {}×{} @ {}spp — {}ms — {} spp accumulated{exposure}",
+            self.mode,
+            self.width,
+            self.height,
+            spp(self.pass_samples),
+            self.elapsed.as_millis(),
+            spp(self.accumulated_samples)
         )
     }
+}
 
-    fn rand_vec3_in_unit_sphere(&mut self) -> Vec3<f32> {
-        let mut v;
-        loop {
-            v = self.rand_vec3();
-            if v.magnitude_squared() < 1.0 {
-                break;
+/// What the window shows, cycled through with Tab
+#[derive(Debug, Clone, Copy)]
+enum RenderMode {
+    /// The path traced image
+    Beauty,
+    Debug(DebugView),
+}
+
+impl RenderMode {
+    fn next(self) -> Self {
+        match self {
+            RenderMode::Beauty => RenderMode::Debug(DebugView::Normals),
+            RenderMode::Debug(DebugView::Normals) => RenderMode::Debug(DebugView::Depth),
+            RenderMode::Debug(DebugView::Depth) => RenderMode::Debug(DebugView::Albedo),
+            RenderMode::Debug(DebugView::Albedo) => RenderMode::Debug(DebugView::Bounces),
+            RenderMode::Debug(DebugView::Bounces) => RenderMode::Debug(DebugView::Coverage),
+            RenderMode::Debug(DebugView::Coverage) => RenderMode::Debug(DebugView::Bvh),
+            // Positions only make sense written out, so they aren't in the
+            // cycle, but --debug can still start the window on them
+            RenderMode::Debug(DebugView::Bvh) | RenderMode::Debug(DebugView::Position) => {
+                RenderMode::Beauty
             }
         }
-        v
     }
 
-    fn rand_unit_vec3(&mut self) -> Vec3<f32> {
-        self.rand_vec3_in_unit_sphere().normalized()
+    fn debug(self) -> Option<DebugView> {
+        match self {
+            RenderMode::Beauty => None,
+            RenderMode::Debug(view) => Some(view),
+        }
+    }
+}
+
+impl From<Option<DebugView>> for RenderMode {
+    fn from(debug: Option<DebugView>) -> Self {
+        debug.map_or(RenderMode::Beauty, RenderMode::Debug)
+    }
+}
+
+impl fmt::Display for RenderMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Named like the --debug values
+        f.write_str(match self {
+            RenderMode::Beauty => "beauty",
+            RenderMode::Debug(DebugView::Normals) => "normals",
+            RenderMode::Debug(DebugView::Depth) => "depth",
+            RenderMode::Debug(DebugView::Albedo) => "albedo",
+            RenderMode::Debug(DebugView::Bounces) => "bounces",
+            RenderMode::Debug(DebugView::Coverage) => "coverage",
+            RenderMode::Debug(DebugView::Bvh) => "bvh",
+            RenderMode::Debug(DebugView::Position) => "position",
+        })
+    }
+}
+
+/// Messages from the event loop to the render thread
+#[derive(Debug, Clone, Copy)]
+// Sent at most a few times a frame, so the size doesn't matter
+#[allow(clippy::large_enum_variant)]
+enum RenderCommand {
+    /// Start drawing this view. Restarts accumulation, unless it goes back
+    /// to the beauty pass a debug view interrupted without changing it, and
+    /// only the latest of a burst of queued renders is drawn
+    Render {
+        size: PhysicalSize<u32>,
+        camera: CameraSettings,
+        mode: RenderMode,
+        settings: RenderSettings,
+    },
+    /// Stop rendering and exit the thread
+    Shutdown,
+}
+
+/// Settings the number keys switch between while navigating
+struct QualityTier {
+    name: &'static str,
+    samples: usize,
+    ssaa: u32,
+    max_depth: usize,
+}
+
+impl QualityTier {
+    fn apply(&self, settings: RenderSettings) -> RenderSettings {
+        RenderSettings {
+            samples: self.samples,
+            ssaa: self.ssaa,
+            max_depth: self.max_depth,
+            ..settings
+        }
+    }
+}
+
+impl fmt::Display for QualityTier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}spp per pass, {}x supersampling, up to {} bounces)",
+            self.name, self.samples, self.ssaa, self.max_depth
+        )
     }
+}
+
+/// Tiers for keys 1 to 4, from most responsive to best looking
+const QUALITY_TIERS: [QualityTier; 4] = [
+    QualityTier {
+        name: "preview",
+        samples: 1,
+        ssaa: 1,
+        max_depth: 4,
+    },
+    QualityTier {
+        name: "medium",
+        samples: 4,
+        ssaa: 1,
+        max_depth: 16,
+    },
+    QualityTier {
+        name: "high",
+        samples: 16,
+        ssaa: 1,
+        max_depth: 50,
+    },
+    QualityTier {
+        name: "final",
+        samples: 16,
+        ssaa: 2,
+        max_depth: 100,
+    },
+];
+
+/// Camera fly speed in world units per second
+const CAMERA_SPEED: f32 = 1.5;
+/// Camera rotation in radians per pixel of mouse movement
+const MOUSE_SENSITIVITY: f32 = 0.005;
 
-    fn rand_in_hemisphere(&mut self, normal: Vec3<f32>) -> Vec3<f32> {
-        let in_unit_sphere = self.rand_vec3_in_unit_sphere();
-        if in_unit_sphere.dot(normal) > 0.0 {
-            in_unit_sphere
-        } else {
-            -in_unit_sphere
+struct Options {
+    /// Render headless to this PNG, PPM or EXR instead of opening a window. With
+    /// `frames` this is the directory the frames are written to
+    output: Option<PathBuf>,
+    /// Render a turntable animation of this many frames headless
+    frames: Option<u32>,
+    /// Point the turntable camera circles and looks at. Defaults to the
+    /// scene camera's `lookat`
+    orbit_target: Option<Vec3<f32>>,
+    /// Horizontal distance of the turntable camera from `orbit_target`.
+    /// Defaults to where the scene camera already is
+    orbit_radius: Option<f32>,
+    /// Scene file to load instead of the built in demo scene, or the name of
+    /// another built in scene. `list` prints the names
+    scene: Option<PathBuf>,
+    /// OBJ file to add to the scene, and how to place it
+    mesh: Option<PathBuf>,
+    mesh_scale: f32,
+    mesh_rotate_y: f32,
+    mesh_translate: Vec3<f32>,
+    width: u32,
+    height: u32,
+    /// Overrides the scene camera's projection
+    projection: Option<Projection>,
+    /// Overrides the scene camera's up direction, for tilted shots
+    vup: Option<Vec3<f32>>,
+    /// Overrides the scene camera's shutter interval
+    shutter: Option<(f32, f32)>,
+    /// Overrides the number of corners of the scene camera's lens
+    aperture_blades: Option<u32>,
+    /// Worker threads to render with. `None` uses one per core, same as
+    /// rayon's global pool
+    threads: Option<usize>,
+    /// Replace the scene camera with one framing everything in the scene
+    auto_camera: bool,
+    /// Add a checkerboard floor at y = 0 with squares of side `ground_scale`
+    ground: bool,
+    ground_scale: f32,
+    /// Add a floor at y = 0 with grid lines every `grid_spacing` units,
+    /// thicker every `grid_major_every` lines
+    grid_floor: bool,
+    grid_spacing: f32,
+    grid_major_every: u32,
+    grid_line: Rgb<f32>,
+    grid_background: Rgb<f32>,
+    /// Replace the background with a Preetham daylight sky lit by the sun
+    /// described by `sky_params`
+    sky: bool,
+    sky_params: SkyParams,
+    /// Cap on how often the window redraws while the image refines
+    max_fps: f32,
+    /// Print the scene with every flag applied as RON and exit
+    dump_scene: bool,
+    /// Refuse to render scenes `World::validate` warns about
+    strict: bool,
+    /// Also write a grayscale PNG of how much of each pixel geometry covers
+    coverage: Option<PathBuf>,
+    /// Put that coverage in the alpha channel of a PNG `output`
+    alpha: bool,
+    /// Also write the world space position of the first hit of each pixel
+    /// to this EXR
+    position: Option<PathBuf>,
+    /// Render `output` as a stereo pair with the eyes this far apart
+    stereo: Option<f32>,
+    /// Distance at which the two views line up. Defaults to how far the
+    /// camera is from what it looks at
+    convergence: Option<f32>,
+    stereo_layout: StereoLayout,
+    /// Print how much memory the scene's meshes take before rendering
+    stats: bool,
+    /// Take commands as JSON lines on stdin while showing the window, see
+    /// [`ServeRequest`]. Responses are the only thing written to stdout
+    serve: bool,
+    /// Close the window once the first pass has been on screen for
+    /// `render_once_wait`, or right away without it, saving it as a
+    /// screenshot first
+    render_once: bool,
+    render_once_wait: Option<Duration>,
+    /// Partial renders to combine into `output` instead of rendering
+    merge: Vec<PathBuf>,
+    settings: RenderSettings,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            output: None,
+            frames: None,
+            orbit_target: None,
+            orbit_radius: None,
+            scene: None,
+            mesh: None,
+            mesh_scale: 1.0,
+            mesh_rotate_y: 0.0,
+            mesh_translate: Vec3::zero(),
+            width: WIDTH as u32,
+            height: HEIGHT as u32,
+            projection: None,
+            vup: None,
+            shutter: None,
+            aperture_blades: None,
+            threads: None,
+            auto_camera: false,
+            ground: false,
+            ground_scale: 1.0,
+            grid_floor: false,
+            grid_spacing: 1.0,
+            grid_major_every: 10,
+            grid_line: Rgb::broadcast(0.1),
+            grid_background: Rgb::broadcast(0.6),
+            sky: false,
+            sky_params: SkyParams::default(),
+            max_fps: 60.0,
+            dump_scene: false,
+            strict: false,
+            coverage: None,
+            alpha: false,
+            position: None,
+            stereo: None,
+            convergence: None,
+            stereo_layout: StereoLayout::default(),
+            stats: false,
+            serve: false,
+            render_once: false,
+            render_once_wait: None,
+            merge: Vec::new(),
+            settings: RenderSettings::default(),
         }
     }
 }
 
-impl<T: Rng> RandVec for T {}
+fn flag_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{flag} expects a value"))
+}
 
-fn hit_sphere(ray: Ray<f32>, sphere: Sphere) -> Option<HitRecord> {
-    let oc = ray.origin - sphere.origin;
-    let a = ray.direction.dot(ray.direction);
-    let b = 2.0 * oc.dot(ray.direction);
-    let c = oc.dot(oc) - sphere.radius * sphere.radius;
-    let discriminant = b * b - 4.0 * a * c;
-    if discriminant > 0.0 {
-        let neg_distance = (-b - discriminant.sqrt()) / (2.0 * a);
-        let pos_distance = (-b + discriminant.sqrt()) / (2.0 * a);
-        let distance = if neg_distance > SHADOW_ACNE_FUDGE_CONSTANT {
-            neg_distance
-        } else if pos_distance > SHADOW_ACNE_FUDGE_CONSTANT {
-            pos_distance
-        } else {
-            return None;
-        };
-        let intersection_point = ray.origin + ray.direction * distance;
-        let surface_normal = (intersection_point - sphere.origin).normalized();
-        Some(HitRecord {
-            intersection_point,
-            surface_normal,
-            distance,
-        })
+fn parse_flag<T>(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = flag_value(args, flag)?;
+    value
+        .parse()
+        .map_err(|e| format!("invalid value `{value}` for {flag}: {e}"))
+}
+
+/// Parse a flag's value written as `x,y,z`
+fn parse_vec3_flag(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+) -> Result<Vec3<f32>, String> {
+    let value = flag_value(args, flag)?;
+    let components = value
+        .split(',')
+        .map(|component| component.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid value `{value}` for {flag}: {e}"))?;
+    match components[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(format!(
+            "invalid value `{value}` for {flag}: expected x,y,z"
+        )),
+    }
+}
+
+/// Whether `v` is finite and nonzero, so it has a direction
+fn is_direction(v: Vec3<f32>) -> bool {
+    v.iter().all(|c| c.is_finite()) && v != Vec3::zero()
+}
+
+/// What is wrong with `camera` coming in from `--serve`, checked the way
+/// the command line checks its camera flags
+fn camera_error(camera: &CameraSettings) -> Option<String> {
+    if ![camera.lookfrom, camera.lookat]
+        .iter()
+        .all(|v| v.iter().all(|c| c.is_finite()))
+    {
+        Some("lookfrom and lookat must be finite".to_string())
+    } else if camera.lookfrom == camera.lookat {
+        Some("lookfrom and lookat must be different points".to_string())
+    } else if !is_direction(camera.vup) {
+        Some("vup must be a finite, nonzero direction".to_string())
+    } else if !(camera.vfov > 0.0 && camera.vfov < 180.0) {
+        Some("vfov must be between 0 and 180 degrees".to_string())
     } else {
         None
     }
 }
-const MAX_DEPTH: usize = 100;
 
-fn reflected(v: Vec3<f32>, n: Vec3<f32>) -> Vec3<f32> {
-    return v - 2.0 * v.dot(n) * n;
+/// Defaults read from `FASTCASTER_WIDTH`, `FASTCASTER_HEIGHT`,
+/// `FASTCASTER_SAMPLES` and `FASTCASTER_SEED`, for runs where flags are
+/// awkward to pass. They override settings saved with a scene, and flags
+/// override them in turn
+#[derive(Debug, Clone, Copy, Default)]
+struct EnvDefaults {
+    width: Option<u32>,
+    height: Option<u32>,
+    samples: Option<usize>,
+    seed: Option<u64>,
 }
 
-fn ray_cast(mut ray: Ray<f32>, world: &World, rng: &mut impl rand::Rng) -> Rgb<f32> {
-    let t = 1.0 - 0.5 * (ray.direction.y + 1.0);
-    let background_color = Lerp::lerp(Rgb::broadcast(1.0), Rgb::new(0.5, 0.7, 1.0), 1.0 - t);
-    let mut color = Rgb::broadcast(1.0);
-    for _ in 0..MAX_DEPTH {
-        let mut min_hit_record: Option<(HitRecord, Material)> = None;
-        for sphere in world.spheres {
-            if let Some(hit_record) = hit_sphere(ray, *sphere) {
-                min_hit_record = min_hit_record
-                    .map(|(mhr, color)| {
-                        if mhr.distance < hit_record.distance {
-                            (mhr, color)
-                        } else {
-                            (hit_record, sphere.material)
-                        }
-                    })
-                    .or(Some((hit_record, sphere.material)));
+impl EnvDefaults {
+    /// Read the variables. Ones that don't parse are skipped with a warning
+    fn read() -> Self {
+        let positive = |value: &u32| *value > 0;
+        Self {
+            width: env_var("FASTCASTER_WIDTH", "a width of at least 1", positive),
+            height: env_var("FASTCASTER_HEIGHT", "a height of at least 1", positive),
+            samples: env_var("FASTCASTER_SAMPLES", "a sample count of at least 1", |&n| {
+                n > 0
+            }),
+            seed: env_var("FASTCASTER_SEED", "an unsigned integer seed", |_| true),
+        }
+    }
+
+    fn apply(self, options: &mut Options) {
+        options.width = self.width.unwrap_or(options.width);
+        options.height = self.height.unwrap_or(options.height);
+        options.settings.samples = self.samples.unwrap_or(options.settings.samples);
+        options.settings.seed = self.seed.or(options.settings.seed);
+    }
+}
+
+/// Value of the environment variable `name` if it is set and parses to
+/// something `valid`. Otherwise warns that `expected` was expected
+fn env_var<T: FromStr>(name: &str, expected: &str, valid: impl Fn(&T) -> bool) -> Option<T> {
+    let value = std::env::var_os(name)?;
+    let parsed = value
+        .to_str()
+        .and_then(|value| value.parse().ok())
+        .filter(|parsed| valid(parsed));
+    if parsed.is_none() {
+        eprintln!("warning: ignoring {name}={value:?}, expected {expected}");
+    }
+    parsed
+}
+
+/// Parse the command line with `settings` and `size` as the starting point
+/// for the render settings and image size flags adjust. They come from the
+/// scene, `env` overrides them and flags override both
+fn parse_args(
+    args: impl Iterator<Item = String>,
+    settings: RenderSettings,
+    size: Option<(u32, u32)>,
+    env: EnvDefaults,
+) -> Result<Options, String> {
+    let mut args = args.peekable();
+    let mut options = Options {
+        settings,
+        ..Options::default()
+    };
+    if let Some((width, height)) = size {
+        (options.width, options.height) = (width, height);
+    }
+    env.apply(&mut options);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" | "-o" => options.output = Some(flag_value(&mut args, &arg)?.into()),
+            "--scene" => options.scene = Some(flag_value(&mut args, &arg)?.into()),
+            "--mesh" => options.mesh = Some(flag_value(&mut args, &arg)?.into()),
+            "--mesh-scale" => options.mesh_scale = parse_flag(&mut args, &arg)?,
+            "--mesh-rotate-y" => options.mesh_rotate_y = parse_flag(&mut args, &arg)?,
+            "--mesh-translate" => options.mesh_translate = parse_vec3_flag(&mut args, &arg)?,
+            "--frames" => options.frames = Some(parse_flag(&mut args, &arg)?),
+            "--orbit-target" => options.orbit_target = Some(parse_vec3_flag(&mut args, &arg)?),
+            "--orbit-radius" => options.orbit_radius = Some(parse_flag(&mut args, &arg)?),
+            "--width" => options.width = parse_flag(&mut args, &arg)?,
+            "--height" => options.height = parse_flag(&mut args, &arg)?,
+            "--projection" => options.projection = Some(parse_flag(&mut args, &arg)?),
+            "--vup" => options.vup = Some(parse_vec3_flag(&mut args, &arg)?),
+            "--aperture-blades" => options.aperture_blades = Some(parse_flag(&mut args, &arg)?),
+            "--shutter" => {
+                options.shutter = Some((parse_flag(&mut args, &arg)?, parse_flag(&mut args, &arg)?))
+            }
+            "--auto-camera" => options.auto_camera = true,
+            "--ground" => options.ground = true,
+            "--ground-scale" => options.ground_scale = parse_flag(&mut args, &arg)?,
+            "--grid-floor" => options.grid_floor = true,
+            "--grid-spacing" => options.grid_spacing = parse_flag(&mut args, &arg)?,
+            "--grid-major-every" => options.grid_major_every = parse_flag(&mut args, &arg)?,
+            "--grid-color" => options.grid_line = parse_vec3_flag(&mut args, &arg)?.into(),
+            "--grid-background" => {
+                options.grid_background = parse_vec3_flag(&mut args, &arg)?.into()
+            }
+            "--sky" => match flag_value(&mut args, &arg)?.as_str() {
+                "preetham" => options.sky = true,
+                other => return Err(format!("unknown sky `{other}`, expected preetham")),
+            },
+            "--sun-elevation" => options.sky_params.sun_elevation = parse_flag(&mut args, &arg)?,
+            "--sun-azimuth" => options.sky_params.sun_azimuth = parse_flag(&mut args, &arg)?,
+            "--turbidity" => options.sky_params.turbidity = parse_flag(&mut args, &arg)?,
+            "--dump-scene" => options.dump_scene = true,
+            "--strict" => options.strict = true,
+            "--stats" => options.stats = true,
+            "--coverage" => options.coverage = Some(flag_value(&mut args, &arg)?.into()),
+            "--alpha" => options.alpha = true,
+            "--position" => options.position = Some(flag_value(&mut args, &arg)?.into()),
+            "--stereo" => options.stereo = Some(parse_flag(&mut args, &arg)?),
+            "--convergence" => options.convergence = Some(parse_flag(&mut args, &arg)?),
+            "--stereo-layout" => options.stereo_layout = parse_flag(&mut args, &arg)?,
+            "--serve" => options.serve = true,
+            "--render-once" => options.render_once = true,
+            "--wait" => {
+                let seconds: f32 = parse_flag(&mut args, &arg)?;
+                options.render_once_wait = Some(
+                    Duration::try_from_secs_f32(seconds)
+                        .map_err(|_| "--wait expects a number of seconds".to_string())?,
+                );
+            }
+            "--merge" => {
+                while let Some(path) = args.next_if(|arg| !arg.starts_with('-')) {
+                    options.merge.push(path.into());
+                }
+                if options.merge.is_empty() {
+                    return Err("--merge expects the partial renders to combine".to_string());
+                }
+            }
+            "--max-fps" => options.max_fps = parse_flag(&mut args, &arg)?,
+            "--threads" => options.threads = Some(parse_flag(&mut args, &arg)?),
+            "--samples" => options.settings.samples = parse_flag(&mut args, &arg)?,
+            "--max-depth" => options.settings.max_depth = parse_flag(&mut args, &arg)?,
+            "--seed" => options.settings.seed = Some(parse_flag(&mut args, &arg)?),
+            "--tonemap" => options.settings.tone_map = parse_flag(&mut args, &arg)?,
+            "--filter" => options.settings.filter = parse_flag(&mut args, &arg)?,
+            "--sampler" => options.settings.sampler = parse_flag(&mut args, &arg)?,
+            "--filter-radius" => options.settings.filter_radius = parse_flag(&mut args, &arg)?,
+            "--gamma" => options.settings.gamma = parse_flag(&mut args, &arg)?,
+            "--exposure" => options.settings.exposure = parse_flag(&mut args, &arg)?,
+            "--no-gamma" => options.settings.gamma = 1.0,
+            "--color-space" => options.settings.color_space = parse_flag(&mut args, &arg)?,
+            "--max-diffuse-bounces" => {
+                options.settings.max_diffuse_bounces = Some(parse_flag(&mut args, &arg)?)
+            }
+            "--max-specular-bounces" => {
+                options.settings.max_specular_bounces = Some(parse_flag(&mut args, &arg)?)
+            }
+            "--rr-min-bounces" => options.settings.rr_min_bounces = parse_flag(&mut args, &arg)?,
+            "--tile-size" => options.settings.tile_size = parse_flag(&mut args, &arg)?,
+            "--tolerance" => options.settings.tolerance = Some(parse_flag(&mut args, &arg)?),
+            "--max-samples" => options.settings.max_samples = parse_flag(&mut args, &arg)?,
+            "--no-nee" => options.settings.nee = false,
+            "--truncation-estimate" => options.settings.truncation_estimate = true,
+            "--legacy-metal" => options.settings.legacy_metal = true,
+            "--single-scatter-metal" => options.settings.single_scatter_metal = true,
+            "--env-lighting-only" => options.settings.env_lighting_only = true,
+            "--analytic-aa" => options.settings.analytic_aa = true,
+            "--antithetic" => options.settings.antithetic = true,
+            "--debug" => options.settings.debug = Some(parse_flag(&mut args, &arg)?),
+            "--clamp" => options.settings.clamp = Some(parse_flag(&mut args, &arg)?),
+            "--crop" => {
+                options.settings.crop = Some(Crop {
+                    x0: parse_flag(&mut args, &arg)?,
+                    y0: parse_flag(&mut args, &arg)?,
+                    x1: parse_flag(&mut args, &arg)?,
+                    y1: parse_flag(&mut args, &arg)?,
+                })
             }
+            "--tile-range" => {
+                options.settings.tile_range = Some(TileRange {
+                    start: parse_flag(&mut args, &arg)?,
+                    end: parse_flag(&mut args, &arg)?,
+                })
+            }
+            "--denoise-sigma" => options.settings.denoise = Some(parse_flag(&mut args, &arg)?),
+            "--ssaa" => options.settings.ssaa = parse_flag(&mut args, &arg)?,
+            "--lighting" => options.settings.lighting = parse_flag(&mut args, &arg)?,
+            "--auto-exposure" => options.settings.auto_exposure = true,
+            "--reflection-offset" => {
+                options.settings.reflection_offset = parse_flag(&mut args, &arg)?
+            }
+            "--transmission-offset" => {
+                options.settings.transmission_offset = parse_flag(&mut args, &arg)?
+            }
+            _ => return Err(format!("unrecognized argument `{arg}`")),
+        }
+    }
+    if options.width == 0 || options.height == 0 {
+        return Err("--width and --height must be at least 1".to_string());
+    }
+    if options.settings.samples == 0 || options.settings.max_depth == 0 {
+        return Err("--samples and --max-depth must be at least 1".to_string());
+    }
+    if options.frames == Some(0) {
+        return Err("--frames must be at least 1".to_string());
+    }
+    if options.threads == Some(0) {
+        return Err("--threads must be at least 1".to_string());
+    }
+    if options.settings.tile_size == 0 {
+        return Err("--tile-size must be at least 1".to_string());
+    }
+    if options.settings.gamma.is_nan() || options.settings.gamma <= 0.0 {
+        return Err("--gamma must be positive".to_string());
+    }
+    let exposure = options.settings.exposure;
+    if exposure.is_nan() || exposure <= 0.0 || exposure.is_infinite() {
+        return Err("--exposure must be positive and finite".to_string());
+    }
+    if let Some((open, close)) = options.shutter {
+        // Also rejects NaN
+        if !(0.0 <= open && open <= close && close <= 1.0) {
+            return Err("--shutter expects OPEN CLOSE with 0 <= OPEN <= CLOSE <= 1".to_string());
+        }
+    }
+    if let Some(vup) = options.vup {
+        if !is_direction(vup) {
+            return Err("--vup must be a finite, nonzero direction".to_string());
+        }
+    }
+    if matches!(options.aperture_blades, Some(1 | 2)) {
+        return Err("--aperture-blades must be 0 for a round lens or at least 3".to_string());
+    }
+    let sun_elevation = options.sky_params.sun_elevation;
+    if !(sun_elevation > 0.0 && sun_elevation <= 90.0) {
+        return Err("--sun-elevation must be above 0 and at most 90 degrees".to_string());
+    }
+    if !options.sky_params.sun_azimuth.is_finite() {
+        return Err("--sun-azimuth must be finite".to_string());
+    }
+    if !(2.0..=10.0).contains(&options.sky_params.turbidity) {
+        return Err("--turbidity must be between 2 and 10".to_string());
+    }
+    let offsets = [
+        options.settings.reflection_offset,
+        options.settings.transmission_offset,
+    ];
+    if !offsets
+        .iter()
+        .all(|offset| offset.is_finite() && *offset >= 0.0)
+    {
+        return Err("--reflection-offset and --transmission-offset must be at least 0".to_string());
+    }
+    if let Some(tolerance) = options.settings.tolerance {
+        if tolerance.is_nan() || tolerance <= 0.0 {
+            return Err("--tolerance must be positive".to_string());
+        }
+    }
+    if let Some(clamp) = options.settings.clamp {
+        if clamp.is_nan() || clamp <= 0.0 {
+            return Err("--clamp must be positive".to_string());
+        }
+    }
+    let filter_radius = options.settings.filter_radius;
+    if filter_radius.is_nan() || filter_radius < 0.5 || filter_radius.is_infinite() {
+        return Err("--filter-radius must be finite and at least 0.5".to_string());
+    }
+    if let Some(crop) = options.settings.crop {
+        if crop.x0 >= crop.x1
+            || crop.y0 >= crop.y1
+            || crop.x1 > options.width as usize
+            || crop.y1 > options.height as usize
+        {
+            return Err(
+                "--crop expects x0 y0 x1 y1 with x0 < x1 <= width and y0 < y1 <= height"
+                    .to_string(),
+            );
+        }
+    }
+    if let Some(range) = options.settings.tile_range {
+        let tile_size = options.settings.tile_size as u32;
+        let tiles = options.width.div_ceil(tile_size) * options.height.div_ceil(tile_size);
+        if range.start >= range.end || range.end > tiles as usize {
+            return Err(format!(
+                "--tile-range expects START END with START < END <= {tiles}, the number of tiles"
+            ));
+        }
+        // The filter would blur the black outside the range into the edges
+        if options.settings.denoise.is_some() {
+            return Err("--tile-range can't be combined with --denoise-sigma".to_string());
+        }
+    }
+    if options.serve && (options.output.is_some() || options.frames.is_some()) {
+        return Err(
+            "--serve drives the window, it can't be combined with --output or --frames".to_string(),
+        );
+    }
+    if options.render_once_wait.is_some() && !options.render_once {
+        return Err("--wait only applies to --render-once".to_string());
+    }
+    if options.render_once && (options.output.is_some() || options.frames.is_some()) {
+        return Err(
+            "--render-once shows the window, it can't be combined with --output or --frames"
+                .to_string(),
+        );
+    }
+    // Clear where the background was left out, ready to composite
+    let png_output = options
+        .output
+        .as_deref()
+        .is_some_and(|path| matches!(ImageFormat::from_path(path), Ok(ImageFormat::Png)));
+    if options.settings.env_lighting_only
+        && png_output
+        && options.settings.tile_range.is_none()
+        && options.stereo.is_none()
+    {
+        options.alpha = true;
+    }
+    if options.output.is_none()
+        && (options.coverage.is_some() || options.alpha || options.position.is_some())
+    {
+        return Err("--coverage, --alpha and --position need an --output to go with".to_string());
+    }
+    if let Some(ipd) = options.stereo {
+        if !(ipd.is_finite() && ipd > 0.0) {
+            return Err("--stereo must be a positive eye distance".to_string());
+        }
+        if options.output.is_none() || options.frames.is_some() {
+            return Err("--stereo needs a single image --output".to_string());
+        }
+        if options.settings.tile_range.is_some() || options.alpha {
+            return Err("--stereo can't be combined with --tile-range or --alpha".to_string());
+        }
+    }
+    if options
+        .convergence
+        .is_some_and(|c| !(c.is_finite() && c > 0.0))
+    {
+        return Err("--convergence must be a positive distance".to_string());
+    }
+    if let Some(path) = &options.position {
+        if !matches!(ImageFormat::from_path(path), Ok(ImageFormat::Exr)) {
+            return Err("--position needs an .exr path, positions don't fit in [0,1]".to_string());
+        }
+    }
+    if options.alpha && options.settings.tile_range.is_some() {
+        return Err(
+            "--alpha can't be combined with --tile-range, which uses alpha itself".to_string(),
+        );
+    }
+    if !options.merge.is_empty() && options.output.is_none() {
+        return Err("--merge needs an --output to write to".to_string());
+    }
+    if let Some(sigma) = options.settings.denoise {
+        if sigma.is_nan() || sigma <= 0.0 || sigma.is_infinite() {
+            return Err("--denoise-sigma must be positive and finite".to_string());
+        }
+    }
+    let ground_scale = options.ground_scale;
+    if ground_scale.is_nan() || ground_scale <= 0.0 || ground_scale.is_infinite() {
+        return Err("--ground-scale must be positive and finite".to_string());
+    }
+    let grid_spacing = options.grid_spacing;
+    if grid_spacing.is_nan() || grid_spacing <= 0.0 || grid_spacing.is_infinite() {
+        return Err("--grid-spacing must be positive and finite".to_string());
+    }
+    if options.max_fps.is_nan() || options.max_fps <= 0.0 || options.max_fps.is_infinite() {
+        return Err("--max-fps must be positive and finite".to_string());
+    }
+    if options.settings.ssaa == 0 {
+        return Err("--ssaa must be at least 1".to_string());
+    }
+    if options.settings.max_samples == 0 {
+        return Err("--max-samples must be at least 1".to_string());
+    }
+    Ok(options)
+}
+
+/// Image formats headless renders can be written as
+#[derive(Debug, Clone, Copy)]
+enum ImageFormat {
+    Png,
+    /// Binary (P6) portable pixmap
+    Ppm,
+    /// 32-bit float OpenEXR holding the linear render
+    Exr,
+}
+
+impl ImageFormat {
+    /// Pick the format from the extension of `path`
+    fn from_path(path: &Path) -> Result<Self, String> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("png") => Ok(ImageFormat::Png),
+            Some(extension) if extension.eq_ignore_ascii_case("ppm") => Ok(ImageFormat::Ppm),
+            Some(extension) if extension.eq_ignore_ascii_case("exr") => Ok(ImageFormat::Exr),
+            _ => Err(format!(
+                "can't tell what format to write {}, use a .png, .ppm or .exr extension",
+                path.display()
+            )),
         }
+    }
+}
+
+/// Write a buffer of packed pixels from `draw` out as a binary PPM
+fn save_ppm(path: &Path, buffer: &[u32], size: PhysicalSize<u32>) -> std::io::Result<()> {
+    let mut data = format!("P6\n{} {}\n255\n", size.width, size.height).into_bytes();
+    data.reserve(buffer.len() * 3);
+    for &packed in buffer {
+        let pixel = Pixel::from_u32(packed);
+        data.extend_from_slice(&[pixel.red, pixel.green, pixel.blue]);
+    }
+    fs::write(path, data)
+}
+
+/// Write a buffer of packed pixels from `draw` out as an RGB PNG
+fn save_png(path: &Path, buffer: &[u32], size: PhysicalSize<u32>) -> image::ImageResult<()> {
+    let image = RgbImage::from_fn(size.width, size.height, |x, y| {
+        let pixel = Pixel::from_u32(buffer[(y * size.width + x) as usize]);
+        image::Rgb([pixel.red, pixel.green, pixel.blue])
+    });
+    image.save(path)
+}
+
+/// `save_png` with `alpha` in [0,1] per pixel as the alpha channel
+fn save_png_with_alpha(
+    path: &Path,
+    buffer: &[u32],
+    alpha: &[f32],
+    size: PhysicalSize<u32>,
+) -> image::ImageResult<()> {
+    let image = RgbaImage::from_fn(size.width, size.height, |x, y| {
+        let i = (y * size.width + x) as usize;
+        let pixel = Pixel::from_u32(buffer[i]);
+        let alpha = (alpha[i].clamp(0.0, 1.0) * 255.0).round() as u8;
+        image::Rgba([pixel.red, pixel.green, pixel.blue, alpha])
+    });
+    image.save(path)
+}
+
+/// Write coverage from `render_coverage` out as a linear grayscale PNG
+fn save_coverage(path: &Path, coverage: &[f32], size: PhysicalSize<u32>) -> image::ImageResult<()> {
+    let image = GrayImage::from_fn(size.width, size.height, |x, y| {
+        let c = coverage[(y * size.width + x) as usize];
+        image::Luma([(c.clamp(0.0, 1.0) * 255.0).round() as u8])
+    });
+    image.save(path)
+}
 
-        if let Some((hit_record, hit_material)) = min_hit_record {
-            color *= hit_material.color;
-            match hit_material.mat_type {
-                MaterialType::Lambertian => {
-                    let random = rng.rand_unit_vec3();
-                    ray = Ray::new(
-                        hit_record.intersection_point,
-                        (hit_record.surface_normal + random).normalized(),
-                    );
+/// `save_png` with an alpha channel that is opaque on the pixels `settings`
+/// renders and clear elsewhere, for merging partial renders
+fn save_partial_png(
+    path: &Path,
+    buffer: &[u32],
+    size: PhysicalSize<u32>,
+    settings: &RenderSettings,
+) -> image::ImageResult<()> {
+    let image = RgbaImage::from_fn(size.width, size.height, |x, y| {
+        let pixel = Pixel::from_u32(buffer[(y * size.width + x) as usize]);
+        let covered = settings.renders_pixel(x as usize, y as usize, size.width as usize);
+        let alpha = if covered { 255 } else { 0 };
+        image::Rgba([pixel.red, pixel.green, pixel.blue, alpha])
+    });
+    image.save(path)
+}
+
+/// Arguments of `fastcaster compare A B`
+struct CompareOptions {
+    a: PathBuf,
+    b: PathBuf,
+    /// Also write the per channel difference here
+    diff: Option<PathBuf>,
+    /// Images less alike than these count as different
+    min_psnr: Option<f32>,
+    min_ssim: Option<f32>,
+}
+
+fn parse_compare_args(mut args: impl Iterator<Item = String>) -> Result<CompareOptions, String> {
+    let mut paths = Vec::new();
+    let (mut diff, mut min_psnr, mut min_ssim) = (None, None, None);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--diff" => diff = Some(flag_value(&mut args, &arg)?.into()),
+            "--min-psnr" => min_psnr = Some(parse_flag(&mut args, &arg)?),
+            "--min-ssim" => min_ssim = Some(parse_flag(&mut args, &arg)?),
+            _ if arg.starts_with("--") => return Err(format!("unrecognized argument `{arg}`")),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let [a, b]: [PathBuf; 2] = paths
+        .try_into()
+        .map_err(|_| "compare expects two images".to_string())?;
+    Ok(CompareOptions {
+        a,
+        b,
+        diff,
+        min_psnr,
+        min_ssim,
+    })
+}
+
+/// Print how far apart the two images are. Returns whether they are within
+/// the thresholds
+fn compare(options: &CompareOptions) -> Result<bool, Box<dyn Error>> {
+    let open = |path: &Path| {
+        image::open(path)
+            .map(|image| image.into_rgb8())
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))
+    };
+    let (a, b) = (open(&options.a)?, open(&options.b)?);
+    if a.dimensions() != b.dimensions() {
+        return Err(format!(
+            "{} is {}x{} but {} is {}x{}",
+            options.a.display(),
+            a.width(),
+            a.height(),
+            options.b.display(),
+            b.width(),
+            b.height()
+        )
+        .into());
+    }
+    let comparison = compare_images(&a, &b);
+    println!(
+        "MAE {:.5}  PSNR {:.2} dB  SSIM {:.4}",
+        comparison.mae, comparison.psnr, comparison.ssim
+    );
+    if let Some(path) = &options.diff {
+        difference_image(&a, &b)
+            .save(path)
+            .map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+    }
+    let psnr_ok = options.min_psnr.is_none_or(|min| comparison.psnr >= min);
+    let ssim_ok = options.min_ssim.is_none_or(|min| comparison.ssim >= min);
+    Ok(psnr_ok && ssim_ok)
+}
+
+/// Combine partial renders from `--tile-range` into one image at `output`,
+/// taking each pixel from whichever input covers it. Inputs must all be PNG
+/// or all be EXR, matching `output`
+fn merge_partials(inputs: &[PathBuf], output: &Path) -> Result<(), Box<dyn Error>> {
+    let format = ImageFormat::from_path(output)?;
+    let mut size = None;
+    let mut check_size = |path: &Path, input_size: PhysicalSize<u32>| {
+        if *size.get_or_insert(input_size) != input_size {
+            return Err(format!(
+                "{} isn't the same size as the others",
+                path.display()
+            ));
+        }
+        Ok(())
+    };
+    let uncovered = match format {
+        ImageFormat::Png => {
+            let mut merged: Option<RgbaImage> = None;
+            for path in inputs {
+                let image = image::open(path)
+                    .map_err(|e| format!("failed to read {}: {e}", path.display()))?
+                    .into_rgba8();
+                check_size(path, PhysicalSize::new(image.width(), image.height()))?;
+                let merged =
+                    merged.get_or_insert_with(|| RgbaImage::new(image.width(), image.height()));
+                for (to, from) in merged.pixels_mut().zip(image.pixels()) {
+                    if from[3] > 0 {
+                        *to = *from;
+                    }
                 }
-                MaterialType::Metal(fuzz) => {
-                    let reflected = reflected(ray.direction, hit_record.surface_normal)
-                        + fuzz * rng.rand_vec3_in_unit_sphere();
-                    if reflected.dot(hit_record.surface_normal) > 0.0 {
-                        ray = Ray::new(hit_record.intersection_point, reflected.normalized())
-                    } else {
-                        color *= Rgb::broadcast(0.0);
-                        break;
+            }
+            let merged = merged.expect("--merge takes at least one input");
+            let uncovered = merged.pixels().filter(|pixel| pixel[3] == 0).count();
+            image::DynamicImage::ImageRgba8(merged)
+                .into_rgb8()
+                .save(output)
+                .map_err(|e| format!("failed to write {}: {e}", output.display()))?;
+            uncovered
+        }
+        ImageFormat::Exr => {
+            let mut merged: Option<Vec<Rgba<f32>>> = None;
+            for path in inputs {
+                let (pixels, input_size) = load_exr(path)
+                    .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+                check_size(path, input_size)?;
+                let merged = merged.get_or_insert_with(|| vec![Rgba::broadcast(0.0); pixels.len()]);
+                for (to, from) in merged.iter_mut().zip(pixels) {
+                    if from.a > 0.0 {
+                        *to = from;
                     }
                 }
             }
-        } else {
-            color *= background_color;
-            break;
+            let merged = merged.expect("--merge takes at least one input");
+            let uncovered = merged.iter().filter(|pixel| pixel.a == 0.0).count();
+            let colors: Vec<_> = merged.into_iter().map(Rgb::from).collect();
+            let size = size.expect("size is set by the first input");
+            save_exr(output, &colors, size)
+                .map_err(|e| format!("failed to write {}: {e}", output.display()))?;
+            uncovered
         }
+        ImageFormat::Ppm => return Err("--merge writes .png or .exr images".into()),
+    };
+    if uncovered > 0 {
+        eprintln!("{uncovered} pixels weren't covered by any input and were left black");
     }
-    color
+    Ok(())
 }
 
-#[derive(Debug)]
-struct ThreadRedrawCompleteEvent(Vec<u32>);
-
-fn draw(draw_size: PhysicalSize<u32>, world: &World) -> Vec<u32> {
-    let (width, height) = (draw_size.width as usize, draw_size.height as usize);
-    let aspect_ratio = width as f32 / height as f32;
-    let viewport_height = 2.0;
-    let viewport_width = aspect_ratio * viewport_height;
-    let focal_length = 1.0;
-
-    let origin = Vec3::broadcast(0.0);
-    let horizontal = Vec3::new(viewport_width, 0.0, 0.0);
-    let vertical = Vec3::new(0.0, -viewport_height, 0.0);
-
-    let upper_left_corner =
-        origin - horizontal / 2.0 - vertical / 2.0 - Vec3::new(0.0, 0.0, focal_length);
-    let sample_count = 4;
-
-    let mut buffer: Vec<u32> = Vec::with_capacity(width * height);
-    let seed = rand::rngs::OsRng.gen();
-
-    buffer.par_extend((0..width * height).into_par_iter().map(|i| {
-        let x = i % width;
-        let y = i / width;
-        let mut pixel_color = Rgb::broadcast(0.0);
-        let mut rng = StdRng::seed_from_u64(u64::wrapping_add(seed, i as u64));
-        for _ in 0..sample_count {
-            let v = (y as f32 + rng.gen::<f32>()) / (height as f32 - 1.0);
-            let u = (x as f32 + rng.gen::<f32>()) / (width as f32 - 1.0);
-
-            let normalized_direction =
-                (upper_left_corner + u * horizontal + v * vertical - origin).normalized();
-            if !normalized_direction.is_normalized() {
-                eprintln!("non normal vector");
-            }
-            let ray = Ray::new(origin, normalized_direction);
+/// `draw`, also giving the stops auto exposure adjusted the image by
+fn draw_exposed(
+    size: PhysicalSize<u32>,
+    world: &World,
+    camera: &Camera,
+    settings: &RenderSettings,
+) -> (Vec<u32>, u64, Option<f32>) {
+    let (colors, total_samples) = render_to_buffer(size, world, camera, settings);
+    let (settings, ev) = settings.exposed_for(&colors);
+    (pack_for_display(colors, &settings), total_samples, ev)
+}
 
-            pixel_color += ray_cast(ray, world, &mut rng);
-        }
-        let pixel_color = pixel_color / sample_count as f32;
+/// What gets added to the stats line for the stops auto exposure chose
+fn exposure_stats(ev: Option<f32>) -> String {
+    ev.map(|ev| format!(", auto exposure {ev:+.2} EV"))
+        .unwrap_or_default()
+}
 
-        let pixel_color = pixel_color.map(|f| f.sqrt());
+/// Timestamped PNG in the working directory for a screenshot
+fn screenshot_path() -> PathBuf {
+    PathBuf::from(format!(
+        "fastcaster-{}.png",
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    ))
+}
 
-        let pixel_bits = Pixel::from_vek_color(pixel_color);
-        pixel_bits.to_u32()
-    }));
+/// Save the image currently on screen to a timestamped PNG in the working
+/// directory. Encoding happens on its own thread so the window stays responsive
+fn save_screenshot(buffer: Vec<u32>, size: PhysicalSize<u32>) {
+    let path = screenshot_path();
+    thread::spawn(move || match save_png(&path, &buffer, size) {
+        Ok(()) => eprintln!("saved screenshot to {}", path.display()),
+        Err(e) => eprintln!("failed to save screenshot to {}: {e}", path.display()),
+    });
+}
 
-    buffer
+/// Hand the render thread a command, cancelling the pass in flight. A render
+/// thread that has exited is reported rather than treated as fatal so the
+/// window keeps showing the last image
+fn send_command(sender: &Sender<RenderCommand>, cancel: &AtomicBool, command: RenderCommand) {
+    cancel.store(true, Ordering::Relaxed);
+    if sender.send(command).is_err() {
+        eprintln!("render thread has exited, ignoring render request");
+    }
 }
 
 fn main() {
-    let event_loop = EventLoopBuilder::<ThreadRedrawCompleteEvent>::with_user_event().build();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let env = EnvDefaults::read();
+    let parse = |settings, size| match parse_args(args.iter().cloned(), settings, size, env) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            process::exit(2);
+        }
+    };
+    let fail = |e: Box<dyn Error>| -> ! {
+        eprintln!("error: {e}");
+        process::exit(1);
+    };
+    if args.first().map(String::as_str) == Some("compare") {
+        let options = parse_compare_args(args.into_iter().skip(1)).unwrap_or_else(|message| {
+            eprintln!("{message}");
+            process::exit(2);
+        });
+        match compare(&options) {
+            Ok(true) => return,
+            // Distinct from errors so scripts can tell a regression apart
+            Ok(false) => process::exit(3),
+            Err(e) => fail(e),
+        }
+    }
+    let options = parse(RenderSettings::default(), None);
+    if !options.merge.is_empty() {
+        let output = options
+            .output
+            .as_deref()
+            .expect("parse_args checks --merge has an output");
+        if let Err(e) = merge_partials(&options.merge, output) {
+            fail(e);
+        }
+        return;
+    }
+    if options.scene.as_deref() == Some(Path::new("list")) {
+        for (name, _) in BUILTIN_SCENES {
+            println!("{name}");
+        }
+        return;
+    }
+    let scene = load_scene(&options).unwrap_or_else(|e| fail(e));
+    // Settings and size saved with the scene replace the defaults. Parse
+    // again on top of them so flags on the command line still win
+    let options = match (scene.settings, scene.size) {
+        (None, None) => options,
+        (settings, size) => parse(settings.unwrap_or_default(), size),
+    };
+    if let Err(e) = run(options, scene) {
+        fail(e);
+    }
+}
+
+/// The scene `--scene` names, before any other flags touch it
+fn load_scene(options: &Options) -> Result<SceneFile, Box<dyn Error>> {
+    Ok(match &options.scene {
+        Some(path) => match path.to_str().and_then(builtin_scene) {
+            Some(scene) => scene,
+            None => SceneFile::load(path)
+                .map_err(|e| format!("failed to load scene {}: {e}", path.display()))?,
+        },
+        None => default_scene(),
+    })
+}
+
+fn run(options: Options, mut scene: SceneFile) -> Result<(), Box<dyn Error>> {
+    if let Some(projection) = options.projection {
+        scene.camera = Some(CameraSettings {
+            projection,
+            ..scene.camera_settings()
+        });
+    }
+    if let Some(vup) = options.vup {
+        scene.camera = Some(CameraSettings {
+            vup,
+            ..scene.camera_settings()
+        });
+    }
+    if let Some((shutter_open, shutter_close)) = options.shutter {
+        scene.camera = Some(CameraSettings {
+            shutter_open,
+            shutter_close,
+            ..scene.camera_settings()
+        });
+    }
+    if let Some(aperture_blades) = options.aperture_blades {
+        scene.camera = Some(CameraSettings {
+            aperture_blades,
+            ..scene.camera_settings()
+        });
+    }
+    if let Some(path) = &options.mesh {
+        scene.meshes.push(Mesh {
+            obj: ObjMesh::load(path.clone())?,
+            material: Material::lambertian(Rgb::broadcast(0.7)),
+            materials: Default::default(),
+            scale: options.mesh_scale,
+            rotate_y: options.mesh_rotate_y,
+            translate: options.mesh_translate,
+        });
+    }
+    if options.ground {
+        scene.add_ground(options.ground_scale);
+    }
+    if options.grid_floor {
+        scene.add_grid_floor(
+            options.grid_spacing,
+            options.grid_major_every,
+            options.grid_line,
+            options.grid_background,
+        );
+    }
+    if options.sky {
+        scene.background = Background::Preetham(PreethamSky::new(options.sky_params));
+    }
+    if options.auto_camera {
+        match scene.world().bounding_box() {
+            Some(bbox) => {
+                let aspect_ratio = options.width as f32 / options.height as f32;
+                scene.camera = Some(scene.camera_settings().auto_frame(bbox, aspect_ratio));
+            }
+            None => eprintln!("nothing in the scene to frame, keeping its camera"),
+        }
+    }
+    if options.dump_scene {
+        scene.settings = Some(options.settings);
+        scene.size = Some((options.width, options.height));
+        println!("{}", scene.to_ron()?);
+        return Ok(());
+    }
+    let warnings = scene.world().validate();
+    for warning in &warnings {
+        eprintln!("warning: {warning}");
+    }
+    if options.strict && !warnings.is_empty() {
+        return Err("scene has warnings, not rendering it under --strict".into());
+    }
+    if options.stats {
+        match mesh_stats(&scene.meshes) {
+            Some(stats) => eprintln!("{stats}"),
+            None => eprintln!("no mesh triangles in the scene"),
+        }
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.threads.unwrap_or(0))
+        .build()
+        .map_err(|e| format!("failed to start render threads: {e}"))?;
+
+    if let Some(frames) = options.frames {
+        let directory = options.output.as_deref().unwrap_or(Path::new("."));
+        fs::create_dir_all(directory)
+            .map_err(|e| format!("failed to create {}: {e}", directory.display()))?;
+        let size = PhysicalSize::new(options.width, options.height);
+        let camera = scene.camera_settings();
+        let target = options.orbit_target.unwrap_or(camera.lookat);
+        let world = scene.world();
+        // Frame `i` is always rendered with `seed + i`, so any one frame can
+        // be reproduced on its own
+        let seed = options
+            .settings
+            .seed
+            .unwrap_or_else(|| rand::rngs::OsRng.gen());
+        for frame in 0..frames {
+            let angle = std::f32::consts::TAU * frame as f32 / frames as f32;
+            let camera = camera
+                .orbit(target, options.orbit_radius, angle)
+                .build(size.width as f32 / size.height as f32)
+                .with_motion_blur(scene.has_motion());
+            let settings = RenderSettings {
+                seed: Some(seed.wrapping_add(frame as u64)),
+                ..options.settings
+            };
+            let start = Instant::now();
+            let (buffer, total_samples, ev) =
+                pool.install(|| draw_exposed(size, &world, &camera, &settings));
+            let path = directory.join(format!("frame_{frame:04}.png"));
+            eprintln!(
+                "frame {}/{frames}: {}{}",
+                frame + 1,
+                render_stats(size, total_samples, start.elapsed()),
+                exposure_stats(ev)
+            );
+            let saved = match settings.tile_range {
+                Some(_) => save_partial_png(&path, &buffer, size, &settings),
+                None => save_png(&path, &buffer, size),
+            };
+            saved.map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = &options.output {
+        let format = ImageFormat::from_path(path)?;
+        let partial = options.settings.tile_range.is_some();
+        if partial && matches!(format, ImageFormat::Ppm) {
+            return Err("--tile-range needs a .png or .exr output to mark what it covers".into());
+        }
+        if options.alpha && !matches!(format, ImageFormat::Png) {
+            return Err("--alpha needs a .png output".into());
+        }
+        let size = PhysicalSize::new(options.width, options.height);
+        let world = scene.world();
+        if let Some(ipd) = options.stereo {
+            if matches!(format, ImageFormat::Exr) {
+                return Err("--stereo needs a .png or .ppm output".into());
+            }
+            let camera = scene.camera_settings();
+            let convergence = options
+                .convergence
+                .unwrap_or_else(|| camera.lookfrom.distance(camera.lookat));
+            let start = Instant::now();
+            let mut total_samples = 0;
+            let [left, right] = [-0.5, 0.5].map(|side| {
+                let eye = camera
+                    .stereo_eye(side * ipd, convergence)
+                    .build(size.width as f32 / size.height as f32)
+                    .with_motion_blur(scene.has_motion());
+                let (buffer, samples, _) =
+                    pool.install(|| draw_exposed(size, &world, &eye, &options.settings));
+                total_samples += samples;
+                buffer
+            });
+            // Both eyes together
+            let rendered = PhysicalSize::new(size.width * 2, size.height);
+            eprintln!("{}", render_stats(rendered, total_samples, start.elapsed()));
+            let (buffer, size) = compose_stereo(&left, &right, size, options.stereo_layout);
+            let saved = match format {
+                ImageFormat::Ppm => save_ppm(path, &buffer, size).map_err(|e| e.to_string()),
+                _ => save_png(path, &buffer, size).map_err(|e| e.to_string()),
+            };
+            saved.map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+            return Ok(());
+        }
+        let camera = scene
+            .camera_settings()
+            .build(size.width as f32 / size.height as f32)
+            .with_motion_blur(scene.has_motion());
+        let coverage = (options.coverage.is_some() || options.alpha)
+            .then(|| pool.install(|| render_coverage(size, &world, &camera, &options.settings)));
+        if let Some(position_path) = &options.position {
+            let positions =
+                pool.install(|| render_positions(size, &world, &camera, &options.settings));
+            save_exr(position_path, &positions, size)
+                .map_err(|e| format!("failed to write {}: {e}", position_path.display()))?;
+        }
+        if let (Some(coverage_path), Some(coverage)) = (&options.coverage, &coverage) {
+            save_coverage(coverage_path, coverage, size)
+                .map_err(|e| format!("failed to write {}: {e}", coverage_path.display()))?;
+        }
+        let start = Instant::now();
+        if let ImageFormat::Exr = format {
+            let (colors, total_samples) =
+                pool.install(|| render_to_buffer(size, &world, &camera, &options.settings));
+            eprintln!("{}", render_stats(size, total_samples, start.elapsed()));
+            let saved = if partial {
+                save_partial_exr(path, &colors, size, &options.settings)
+            } else {
+                save_exr(path, &colors, size)
+            };
+            saved.map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+            return Ok(());
+        }
+        let (buffer, total_samples, ev) =
+            pool.install(|| draw_exposed(size, &world, &camera, &options.settings));
+        eprintln!(
+            "{}{}",
+            render_stats(size, total_samples, start.elapsed()),
+            exposure_stats(ev)
+        );
+        let saved = match format {
+            ImageFormat::Png if partial => {
+                save_partial_png(path, &buffer, size, &options.settings).map_err(|e| e.to_string())
+            }
+            ImageFormat::Png => match coverage.as_deref().filter(|_| options.alpha) {
+                Some(coverage) => save_png_with_alpha(path, &buffer, coverage, size),
+                None => save_png(path, &buffer, size),
+            }
+            .map_err(|e| e.to_string()),
+            ImageFormat::Ppm => save_ppm(path, &buffer, size).map_err(|e| e.to_string()),
+            ImageFormat::Exr => unreachable!("EXR is written from the linear buffer above"),
+        };
+        saved.map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+        return Ok(());
+    }
+
+    let event_loop = EventLoopBuilder::<RenderEvent>::with_user_event().build();
     let window = WindowBuilder::new()
-        .with_inner_size(PhysicalSize::new(WIDTH as f32, HEIGHT as f32))
+        .with_inner_size(PhysicalSize::new(options.width, options.height))
         .build(&event_loop)
-        .unwrap();
+        .map_err(|e| format!("failed to create window: {e}"))?;
 
-    let mut window_size = PhysicalSize::new(WIDTH as u32, HEIGHT as u32);
+    // On HiDPI displays the physical size can differ from what was asked for
+    let mut window_size = window.inner_size();
 
-    let mut buffer = vec![0; WIDTH * HEIGHT];
+    let mut buffer = vec![0; (window_size.width * window_size.height) as usize];
+    let mut buffer_size = window_size;
 
     let event_loop_proxy = event_loop.create_proxy();
+    if options.serve {
+        let serve_proxy = event_loop.create_proxy();
+        thread::spawn(move || read_serve_requests(serve_proxy));
+    }
+    // Tiles are reported from the worker threads, which share this one
+    let tile_proxy = Mutex::new(event_loop.create_proxy());
 
-    let (sender, receiver) = channel::<PhysicalSize<u32>>();
-
-    let mut graphics_context = unsafe { GraphicsContext::new(&window, &window).unwrap() };
-
-    sender.send(window.inner_size()).unwrap();
-
-    let _thread = thread::spawn(move || loop {
-        let draw_size = receiver.recv().unwrap();
-        let world = World {
-            spheres: &[
-                Sphere {
-                    origin: Vec3::new(0.0, 0.0, -1.0),
-                    radius: 0.5,
-                    material: Material {
-                        color: Rgb {
-                            r: 0.7,
-                            g: 0.3,
-                            b: 0.3,
-                        },
-                        mat_type: MaterialType::Lambertian,
-                    },
-                },
-                Sphere {
-                    origin: Vec3::new(0.0, -100.5, -1.0),
-                    radius: 100.0,
-                    material: Material {
-                        color: Rgb::new(0.8, 0.8, 0.3),
-                        mat_type: MaterialType::Lambertian,
-                    },
-                },
-                Sphere {
-                    origin: Vec3::new(-1.0, 0.0, -1.0),
-                    radius: 0.5,
-                    material: Material {
-                        color: Rgb::new(0.8, 0.8, 0.8),
-                        mat_type: MaterialType::Metal(0.3),
-                    },
-                },
-                Sphere {
-                    origin: Vec3 {
-                        x: 1.0,
-                        y: 0.0,
-                        z: -1.0,
-                    },
-                    radius: 0.5,
-                    material: Material {
-                        color: Rgb::new(0.8, 0.6, 0.2),
-                        mat_type: MaterialType::Metal(1.0),
-                    },
-                },
-            ],
+    let (sender, receiver) = channel::<RenderCommand>();
+
+    let mut graphics_context = unsafe { GraphicsContext::new(&window, &window) }
+        .map_err(|e| format!("failed to create graphics context: {e}"))?;
+
+    let mut camera = scene.camera_settings();
+    let mut held_keys = HashSet::new();
+    let mut last_move = Instant::now();
+    let frame_interval = Duration::from_secs_f32(1.0 / options.max_fps);
+    // Renders arrive far more often than is worth showing them, so they only
+    // mark the window stale and redraws are paced in MainEventsCleared
+    let mut redraw_pending = false;
+    let mut last_redraw = Instant::now();
+    let mut dragging = false;
+    // Under --render-once, when to save and close now that the first pass
+    // is on screen
+    let mut exit_at: Option<Instant> = None;
+
+    let mut settings = options.settings;
+    let mut mode = RenderMode::from(settings.debug);
+    let cancel = Arc::new(AtomicBool::new(false));
+    let render_cancel = Arc::clone(&cancel);
+    // Displayed buffers go back to the render thread once they are replaced,
+    // so the two just trade places instead of a new one every pass
+    let (recycle_sender, recycled) = channel::<Vec<u32>>();
+    sender.send(RenderCommand::Render {
+        size: window_size,
+        camera,
+        mode,
+        settings,
+    })?;
+
+    let mut render_thread = Some(thread::spawn(move || {
+        let world = scene.world();
+        let motion_blur = scene.has_motion();
+        let Ok(RenderCommand::Render {
+            size: mut draw_size,
+            camera: mut camera_settings,
+            mode: mut render_mode,
+            mut settings,
+        }) = receiver.recv()
+        else {
+            return;
         };
-        event_loop_proxy
-            .send_event(ThreadRedrawCompleteEvent(draw(draw_size, &world)))
-            .unwrap();
-    });
+        let mut accumulator = Accumulator::new();
+        // Beauty passes put aside while a debug view is shown, along with the
+        // size, camera and settings they were rendered with
+        let mut beauty = None;
+        loop {
+            // Clear before draining so a resize that lands after this point
+            // still cancels the pass below
+            render_cancel.store(false, Ordering::Relaxed);
+            // Drain everything queued without blocking. Only the latest
+            // render matters and the image keeps refining while nothing
+            // changes, until it converges. Then there is nothing to do until
+            // the next command
+            let mut wait = accumulator.converged(&settings);
+            loop {
+                let command = if wait {
+                    wait = false;
+                    let command = receiver.recv().map_err(|_| TryRecvError::Disconnected);
+                    // Sending it cancelled a pass that wasn't running
+                    render_cancel.store(false, Ordering::Relaxed);
+                    command
+                } else {
+                    receiver.try_recv()
+                };
+                match command {
+                    Ok(RenderCommand::Render {
+                        size: new_size,
+                        camera: new_camera,
+                        mode: new_mode,
+                        settings: new_settings,
+                    }) => {
+                        let leaving_beauty = matches!(render_mode, RenderMode::Beauty)
+                            && !matches!(new_mode, RenderMode::Beauty);
+                        if leaving_beauty {
+                            let view = (draw_size, camera_settings, settings);
+                            beauty = Some((std::mem::take(&mut accumulator), view));
+                        }
+                        draw_size = new_size;
+                        camera_settings = new_camera;
+                        render_mode = new_mode;
+                        settings = RenderSettings {
+                            debug: render_mode.debug(),
+                            ..new_settings
+                        };
+                        accumulator.reset();
+                        // Cycling back to an unchanged view picks up where
+                        // its accumulation left off
+                        if let RenderMode::Beauty = render_mode {
+                            match beauty.take() {
+                                Some((kept, view))
+                                    if view == (draw_size, camera_settings, settings) =>
+                                {
+                                    accumulator = kept;
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    Ok(RenderCommand::Shutdown) | Err(TryRecvError::Disconnected) => return,
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+            settings.debug = render_mode.debug();
+            let camera = camera_settings
+                .build(draw_size.width as f32 / draw_size.height as f32)
+                .with_motion_blur(motion_blur);
+            // Every pass needs its own seed or accumulating would just
+            // average the same image over and over
+            let seed = match settings.seed {
+                Some(seed) => accumulator.next_seed(seed),
+                None => rand::rngs::OsRng.gen(),
+            };
+            // Put something on screen right away, the first full pass of a
+            // slow scene can take a while
+            if accumulator.passes == 0 {
+                let quick = RenderSettings {
+                    samples: 1,
+                    tolerance: None,
+                    seed: Some(seed),
+                    ..settings
+                };
+                let (pixels, _) =
+                    pool.install(|| draw(draw_size, &world, &camera, &quick, COARSE_STRIDE));
+                if !render_cancel.load(Ordering::Relaxed) {
+                    let event = TileEvent {
+                        size: draw_size,
+                        x0: 0,
+                        y0: 0,
+                        width: draw_size.width as usize,
+                        pixels,
+                    };
+                    let proxy = tile_proxy.lock().unwrap_or_else(|e| e.into_inner());
+                    let _ = proxy.send_event(RenderEvent::Tile(event));
+                }
+            }
+            let start = Instant::now();
+            // Show tiles as they finish. Tiles of a cancelled pass would be
+            // partly sampled, so those are dropped
+            let on_tile = |tile: &Tile| {
+                if render_cancel.load(Ordering::Relaxed) {
+                    return;
+                }
+                let event = TileEvent {
+                    size: draw_size,
+                    x0: tile.x0,
+                    y0: tile.y0,
+                    width: tile.width,
+                    pixels: accumulator.preview_tile(tile, draw_size.width as usize, &settings),
+                };
+                let proxy = tile_proxy.lock().unwrap_or_else(|e| e.into_inner());
+                // A closed event loop is noticed when the pass completes
+                let _ = proxy.send_event(RenderEvent::Tile(event));
+            };
+            let Some((pass, total_samples)) = pool.install(|| {
+                draw_linear_with_progress(
+                    draw_size,
+                    &world,
+                    &camera,
+                    &settings,
+                    seed,
+                    &render_cancel,
+                    &on_tile,
+                )
+            }) else {
+                continue;
+            };
+            let elapsed = start.elapsed();
+            accumulator.add(&pass, total_samples);
+            let mut back_buffer = recycled.try_recv().unwrap_or_default();
+            let exposure_ev =
+                accumulator.display_buffer_into(&mut back_buffer, draw_size, &settings);
+            let event = ThreadRedrawCompleteEvent {
+                buffer: back_buffer,
+                width: draw_size.width,
+                height: draw_size.height,
+                pass_samples: total_samples,
+                accumulated_samples: accumulator.samples,
+                elapsed,
+                mode: render_mode,
+                exposure_ev,
+            };
+            if event_loop_proxy
+                .send_event(RenderEvent::Complete(event))
+                .is_err()
+            {
+                // The event loop is gone, nobody is left to display anything
+                return;
+            }
+        }
+    }));
 
     event_loop.run(move |event, _, control_flow| match event {
         Event::WindowEvent {
@@ -322,31 +1646,290 @@ fn main() {
             WindowEvent::CloseRequested => {
                 *control_flow = ControlFlow::Exit;
             }
-            WindowEvent::Resized(new_size) => {
-                if window_size != new_size {
-                    buffer = vec![0; (new_size.width * new_size.height) as usize];
-                    sender.send(new_size).unwrap();
-                    println!("resized from {:?} to {:?}", window_size, new_size);
-                    window_size = new_size;
-                }
+            // Minimizing can report a zero area size. There is nothing to
+            // render then, so keep the last image until a real size arrives
+            WindowEvent::Resized(new_size)
+            | WindowEvent::ScaleFactorChanged {
+                new_inner_size: &mut new_size,
+                ..
+            } if window_size != new_size && new_size.width > 0 && new_size.height > 0 => {
+                buffer.clear();
+                buffer.resize((new_size.width * new_size.height) as usize, 0);
+                buffer_size = new_size;
+                send_command(
+                    &sender,
+                    &cancel,
+                    RenderCommand::Render {
+                        size: new_size,
+                        camera,
+                        mode,
+                        settings,
+                    },
+                );
+                eprintln!("resized from {:?} to {:?}", window_size, new_size);
+                window_size = new_size;
             }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state,
+                        virtual_keycode: Some(key),
+                        ..
+                    },
+                ..
+            } => match state {
+                ElementState::Pressed => {
+                    if held_keys.is_empty() {
+                        last_move = Instant::now();
+                    }
+                    // Key repeat sends more presses, only the first one counts
+                    if held_keys.insert(key) {
+                        match key {
+                            VirtualKeyCode::F12 => save_screenshot(buffer.clone(), buffer_size),
+                            VirtualKeyCode::Key1
+                            | VirtualKeyCode::Key2
+                            | VirtualKeyCode::Key3
+                            | VirtualKeyCode::Key4 => {
+                                // The number keys are consecutive variants
+                                let tier =
+                                    &QUALITY_TIERS[key as usize - VirtualKeyCode::Key1 as usize];
+                                settings = tier.apply(settings);
+                                eprintln!("quality: {tier}");
+                                send_command(
+                                    &sender,
+                                    &cancel,
+                                    RenderCommand::Render {
+                                        size: window_size,
+                                        camera,
+                                        mode,
+                                        settings,
+                                    },
+                                );
+                            }
+                            VirtualKeyCode::Tab => {
+                                mode = mode.next();
+                                send_command(
+                                    &sender,
+                                    &cancel,
+                                    RenderCommand::Render {
+                                        size: window_size,
+                                        camera,
+                                        mode,
+                                        settings,
+                                    },
+                                );
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                ElementState::Released => {
+                    held_keys.remove(&key);
+                }
+            },
+            WindowEvent::MouseInput {
+                state,
+                button: MouseButton::Left,
+                ..
+            } => dragging = state == ElementState::Pressed,
             _ => {}
         },
-        Event::UserEvent(ThreadRedrawCompleteEvent(new_buf)) => {
-            if buffer.len() == new_buf.len() {
-                buffer = new_buf;
-                window.request_redraw();
-                println!("New display!");
+        Event::DeviceEvent {
+            event: DeviceEvent::MouseMotion { delta: (dx, dy) },
+            ..
+        } if dragging => {
+            camera.rotate(
+                -dx as f32 * MOUSE_SENSITIVITY,
+                -dy as f32 * MOUSE_SENSITIVITY,
+            );
+            send_command(
+                &sender,
+                &cancel,
+                RenderCommand::Render {
+                    size: window_size,
+                    camera,
+                    mode,
+                    settings,
+                },
+            );
+        }
+        Event::MainEventsCleared => {
+            let direction = held_keys
+                .iter()
+                .filter_map(|key| match key {
+                    VirtualKeyCode::W => Some(Vec3::unit_z()),
+                    VirtualKeyCode::S => Some(-Vec3::unit_z()),
+                    VirtualKeyCode::D => Some(Vec3::unit_x()),
+                    VirtualKeyCode::A => Some(-Vec3::unit_x()),
+                    VirtualKeyCode::E => Some(Vec3::unit_y()),
+                    VirtualKeyCode::Q => Some(-Vec3::unit_y()),
+                    _ => None,
+                })
+                .fold(Vec3::zero(), |sum, v| sum + v);
+            let now = Instant::now();
+            let mut wake_at = None;
+            if direction != Vec3::zero() {
+                let dt = (now - last_move).as_secs_f32();
+                last_move = now;
+                camera.translate(direction * CAMERA_SPEED * dt);
+                send_command(
+                    &sender,
+                    &cancel,
+                    RenderCommand::Render {
+                        size: window_size,
+                        camera,
+                        mode,
+                        settings,
+                    },
+                );
+                wake_at = Some(now + frame_interval);
             }
+            // Checked before redrawing so the first pass is presented before
+            // the window closes
+            if let Some(exit_at) = exit_at {
+                if now >= exit_at && !redraw_pending {
+                    let path = screenshot_path();
+                    match save_png(&path, &buffer, buffer_size) {
+                        Ok(()) => eprintln!("saved screenshot to {}", path.display()),
+                        Err(e) => eprintln!("failed to save screenshot to {}: {e}", path.display()),
+                    }
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+                wake_at = Some(wake_at.map_or(exit_at, |wake_at| wake_at.min(exit_at)));
+            }
+            if redraw_pending {
+                let next_frame = last_redraw + frame_interval;
+                if now >= next_frame {
+                    window.request_redraw();
+                    redraw_pending = false;
+                    last_redraw = now;
+                } else {
+                    wake_at = Some(wake_at.map_or(next_frame, |wake_at| wake_at.min(next_frame)));
+                }
+            }
+            // Sleep until the next event once nothing is moving and the
+            // newest image is on screen
+            *control_flow = match wake_at {
+                Some(wake_at) => ControlFlow::WaitUntil(wake_at),
+                None => ControlFlow::Wait,
+            };
         }
-        Event::RedrawRequested(_win_id) => {
-            let (width, height) = {
-                let inner_size = window.inner_size();
-                (inner_size.width, inner_size.height)
+        // The first pass of --render-once stays on screen until it is saved
+        Event::UserEvent(RenderEvent::Tile(_)) if exit_at.is_some() => {}
+        Event::UserEvent(RenderEvent::Complete(event)) if exit_at.is_some() => {
+            let _ = recycle_sender.send(event.buffer);
+        }
+        Event::UserEvent(RenderEvent::Tile(tile))
+            if window_size == tile.size && buffer_size == tile.size =>
+        {
+            let width = tile.size.width as usize;
+            for (row, row_pixels) in tile.pixels.chunks(tile.width).enumerate() {
+                let start = (tile.y0 + row) * width + tile.x0;
+                buffer[start..start + tile.width].copy_from_slice(row_pixels);
+            }
+            redraw_pending = true;
+        }
+        Event::UserEvent(RenderEvent::Complete(event))
+            if window_size == PhysicalSize::new(event.width, event.height) =>
+        {
+            window.set_title(&event.window_title());
+            buffer_size = PhysicalSize::new(event.width, event.height);
+            let front_buffer = std::mem::replace(&mut buffer, event.buffer);
+            // The render thread may have exited, then there's nobody to reuse it
+            let _ = recycle_sender.send(front_buffer);
+            redraw_pending = true;
+            if options.render_once {
+                exit_at = Some(Instant::now() + options.render_once_wait.unwrap_or_default());
+                // Nothing rendered from here on would be shown
+                send_command(&sender, &cancel, RenderCommand::Shutdown);
+            }
+        }
+        // Rendered for a size the window has since left
+        Event::UserEvent(RenderEvent::Complete(event)) => {
+            let _ = recycle_sender.send(event.buffer);
+        }
+        Event::UserEvent(RenderEvent::Serve(request)) => {
+            // Everything but saving changes what is rendered
+            let mut rerender = true;
+            let response = match request {
+                ServeRequest::SetCamera {
+                    lookfrom,
+                    lookat,
+                    vup,
+                    vfov,
+                } => {
+                    let moved = CameraSettings {
+                        lookfrom: lookfrom.unwrap_or(camera.lookfrom),
+                        lookat: lookat.unwrap_or(camera.lookat),
+                        vup: vup.unwrap_or(camera.vup),
+                        vfov: vfov.unwrap_or(camera.vfov),
+                        ..camera
+                    };
+                    match camera_error(&moved) {
+                        Some(message) => {
+                            rerender = false;
+                            ServeResponse::error(message)
+                        }
+                        None => {
+                            camera = moved;
+                            ServeResponse::ok()
+                        }
+                    }
+                }
+                ServeRequest::SetSamples { samples: 0 } => {
+                    rerender = false;
+                    ServeResponse::error("samples must be at least 1".to_string())
+                }
+                ServeRequest::SetSamples { samples } => {
+                    settings.samples = samples;
+                    ServeResponse::ok()
+                }
+                ServeRequest::Render => ServeResponse::ok(),
+                ServeRequest::Save { path } => {
+                    rerender = false;
+                    match save_png(&path, &buffer, buffer_size) {
+                        Ok(()) => ServeResponse::ok(),
+                        Err(e) => {
+                            ServeResponse::error(format!("failed to write {}: {e}", path.display()))
+                        }
+                    }
+                }
             };
-            graphics_context.set_buffer(&buffer, width as u16, height as u16);
+            if rerender {
+                send_command(
+                    &sender,
+                    &cancel,
+                    RenderCommand::Render {
+                        size: window_size,
+                        camera,
+                        mode,
+                        settings,
+                    },
+                );
+            }
+            println!("{response}");
+        }
+        Event::RedrawRequested(_win_id) => {
+            // Use the size the buffer was rendered at rather than asking the
+            // window, which may already have moved on
+            graphics_context.set_buffer(
+                &buffer,
+                buffer_size.width as u16,
+                buffer_size.height as u16,
+            );
         }
         Event::NewEvents(StartCause::Init) => *control_flow = ControlFlow::Wait,
+        Event::LoopDestroyed => {
+            // Under --render-once the thread is already shutting down
+            cancel.store(true, Ordering::Relaxed);
+            let _ = sender.send(RenderCommand::Shutdown);
+            if let Some(render_thread) = render_thread.take() {
+                if render_thread.join().is_err() {
+                    eprintln!("render thread panicked");
+                }
+            }
+        }
         _ => {}
     })
 }
@@ -0,0 +1,52 @@
+//! Runs the binary to check that a scene dumped with `--dump-scene` renders
+//! exactly like the flags it was dumped from
+
+use std::{path::Path, process::Command};
+
+fn fastcaster(args: &[&str]) -> Vec<u8> {
+    let output = Command::new(env!("CARGO_BIN_EXE_fastcaster"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "fastcaster {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+#[test]
+fn dumped_scene_reloads_to_the_same_render() {
+    let directory = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let path = |name: &str| directory.join(name).to_str().unwrap().to_string();
+    let flags = [
+        "--scene",
+        "cornell",
+        "--width",
+        "24",
+        "--height",
+        "16",
+        "--ssaa",
+        "2",
+        "--samples",
+        "2",
+        "--seed",
+        "5",
+    ];
+    let dumped = fastcaster(&[&flags[..], &["--dump-scene"]].concat());
+    std::fs::write(path("dumped.ron"), dumped).unwrap();
+
+    let original = path("original.png");
+    fastcaster(&[&flags[..], &["--output", &original]].concat());
+    let reloaded = path("reloaded.png");
+    fastcaster(&["--scene", &path("dumped.ron"), "--output", &reloaded]);
+
+    let original = image::open(original).unwrap().into_rgb8();
+    let reloaded = image::open(reloaded).unwrap().into_rgb8();
+    assert_eq!(reloaded.dimensions(), (24, 16));
+    assert!(
+        original == reloaded,
+        "the reloaded scene renders differently"
+    );
+}
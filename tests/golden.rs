@@ -0,0 +1,73 @@
+//! Renders known scenes and compares them against committed images. Run with
+//! `BLESS=1` to overwrite the goldens after an intentional change to how
+//! scenes render
+
+use std::path::Path;
+
+use fastcaster::{default_scene, draw, Pixel, RenderSettings};
+use image::RgbImage;
+use winit::dpi::PhysicalSize;
+
+/// Largest per-channel difference still accepted, to allow for floating point
+/// differences between platforms
+const TOLERANCE: u8 = 2;
+
+fn check_golden(name: &str, size: PhysicalSize<u32>, buffer: &[u32]) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(format!("{name}.png"));
+    let rendered = RgbImage::from_fn(size.width, size.height, |x, y| {
+        let pixel = Pixel::from_u32(buffer[(y * size.width + x) as usize]);
+        image::Rgb([pixel.red, pixel.green, pixel.blue])
+    });
+    if std::env::var_os("BLESS").is_some() {
+        rendered.save(&path).unwrap();
+        return;
+    }
+    let golden = image::open(&path)
+        .unwrap_or_else(|e| {
+            panic!(
+                "failed to read {}, run with BLESS=1 to create it: {e}",
+                path.display()
+            )
+        })
+        .into_rgb8();
+    assert_eq!(
+        golden.dimensions(),
+        rendered.dimensions(),
+        "{name} changed size"
+    );
+    let mismatched = golden
+        .pixels()
+        .zip(rendered.pixels())
+        .filter(|(golden, rendered)| {
+            golden
+                .0
+                .iter()
+                .zip(rendered.0)
+                .any(|(&g, r)| g.abs_diff(r) > TOLERANCE)
+        })
+        .count();
+    assert_eq!(
+        mismatched,
+        0,
+        "{mismatched} pixels of {name} differ from {}, run with BLESS=1 if that is intended",
+        path.display()
+    );
+}
+
+#[test]
+fn default_scene_matches_golden() {
+    let scene = default_scene();
+    let size = PhysicalSize::new(80, 60);
+    let camera = scene
+        .camera_settings()
+        .build(size.width as f32 / size.height as f32);
+    let settings = RenderSettings {
+        samples: 16,
+        seed: Some(1),
+        ..RenderSettings::default()
+    };
+    let (buffer, _) = draw(size, &scene.world(), &camera, &settings, 1);
+    check_golden("default_scene", size, &buffer);
+}